@@ -0,0 +1,1169 @@
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    Context,
+    Result,
+};
+use base64::Engine;
+use chrono::Utc;
+
+use crate::{
+    fs::{
+        copy_file,
+        create_symlink,
+        set_file_mode,
+        set_file_mtime,
+        write_atomic,
+        write_atomic_with,
+    },
+    hash::{
+        hash,
+        hash_file,
+        HashAlgorithm,
+    },
+    merge::merge3,
+    patch::{
+        render_hunk,
+        LineEnding,
+        LineEndings,
+        Patch,
+        PatchFile,
+        PatchFileHunk,
+    },
+    store::{
+        read_index,
+        read_stored,
+        QopDir,
+    },
+    walk::{
+        collect_files,
+        key_path,
+        path_key,
+    },
+};
+
+/// Flags that control how a patch is applied.
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    /// Directory the paths of the patch are relative to.
+    pub root: PathBuf,
+    /// Overrides the line endings recorded in the patch.
+    pub line_endings: LineEndings,
+    /// Number of context lines of a hunk that may differ from the file.
+    pub fuzz: usize,
+    /// Applies the patch to files that do not match its pre hash.
+    pub force: bool,
+    /// Merges the patch into files that do not match its pre hash, using
+    /// their stored copy as the merge base.
+    pub merge: bool,
+    /// Copies the files touched by the patch to the backup directory first.
+    pub backup: bool,
+    /// Applies the hunks that fit and writes the others to `.rej` files.
+    pub reject: bool,
+}
+
+/// Outcome of `apply_patch`.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyReport {
+    /// Number of files that were written.
+    pub patched: usize,
+    /// Paths of the files that were deleted.
+    pub deleted: Vec<String>,
+    /// Number of files that were moved.
+    pub renamed: usize,
+    /// Directory holding the backups and the number of files backed up.
+    pub backup: Option<(PathBuf, usize)>,
+    /// Sorted descriptions of the files or hunks that could not be applied.
+    pub rejects: Vec<String>,
+    /// Sorted paths of files that were merged with conflict markers.
+    pub conflicts: Vec<String>,
+    /// Paths of applied files that do not match the post hash of the patch.
+    pub post_hash_mismatches: Vec<String>,
+    /// The files of the patch, sorted by path.
+    pub files: Vec<AppliedFile>,
+}
+
+/// State of a file of the patch after `apply_patch`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AppliedFile {
+    pub path: String,
+    pub pre_hash: String,
+    pub post_hash: String,
+    pub added: usize,
+    pub removed: usize,
+    /// Whether the file now matches the post hash. `None` if the patch records
+    /// no post hash.
+    pub verified: Option<bool>,
+}
+
+/// Returns where a path of a patch is below `root`. Absolute paths and paths
+/// that could leave it are rejected.
+pub fn resolve_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let key = path_key(Path::new(path)).with_context(|| format!("invalid path in the patch: {}", path))?;
+    Ok(root.join(key_path(key.strip_prefix("./").unwrap_or(""))))
+}
+
+/// Returns the sorted paths of the files below `root` that do not match the
+/// pre hash of the patch, deleted files included.
+pub fn mismatched_files(patch: &Patch, root: &Path) -> Result<Vec<String>> {
+    let current_hash = |path: &str| -> Result<String> {
+        match hash_file(patch.hash, resolve_path(root, path)?) {
+            | Ok(v) => Ok(v),
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(hash(patch.hash, [])),
+            | Err(e) => Err(e.into()),
+        }
+    };
+    let mut mismatched = Vec::new();
+    for (path, patch_file) in &patch.files {
+        // patches imported from other formats carry no hashes, a renamed file
+        // is still at its old path
+        if !patch_file.pre_hash.is_empty()
+            && current_hash(patch.renamed.get(path).unwrap_or(path))? != patch_file.pre_hash
+        {
+            mismatched.push(path.clone());
+        }
+    }
+    for (path, pre_hash) in patch.deleted_hashes.iter().filter(|x| patch.deleted.contains(x.0)) {
+        if current_hash(path)? != *pre_hash {
+            mismatched.push(path.clone());
+        }
+    }
+    mismatched.sort();
+    Ok(mismatched)
+}
+
+/// Applies a patch to the working copy. Rejected hunks and merge conflicts do
+/// not fail the call, they are part of the report.
+pub fn apply_patch(qop_dir: &QopDir, patch: Patch, options: ApplyOptions) -> Result<ApplyReport> {
+    let ApplyOptions {
+        root,
+        line_endings,
+        fuzz,
+        force,
+        merge,
+        backup,
+        reject,
+    } = options;
+    let at = |path: &str| resolve_path(&root, path);
+    // all paths are checked before anything is changed
+    for path in patch.files.keys().chain(patch.deleted.iter()).chain(patch.renamed.values()) {
+        at(path)?;
+    }
+    let mismatched = if force {
+        Vec::new()
+    } else {
+        mismatched_files(&patch, &root)?
+    };
+    if !mismatched.is_empty() && !merge && !reject {
+        return Err(anyhow::anyhow!(
+            "files do not match the pre hash of the patch (use --force to apply anyway, --merge to merge them or \
+             --reject to apply what still fits):\n{}",
+            mismatched.join("\n")
+        ));
+    }
+    // the merge base of mismatched files is looked up in the store by its hash
+    let index = if mismatched.is_empty() || !merge {
+        None
+    } else {
+        let index = read_index(qop_dir)?;
+        if index.hash != patch.hash {
+            return Err(anyhow::anyhow!(
+                "can not merge, the patch uses {} hashes but the store uses {}",
+                patch.hash.as_str(),
+                index.hash.as_str()
+            ));
+        }
+        Some(index)
+    };
+
+    // renames never replace files that stay
+    for (new, old) in &patch.renamed {
+        let replaced = !patch.deleted.contains(new) && !patch.renamed.values().any(|x| x == new);
+        if replaced && std::fs::symlink_metadata(at(new)?).is_ok() {
+            return Err(anyhow::anyhow!("can not rename {} to {}, the file exists", old, new));
+        }
+    }
+
+    let mut report = ApplyReport::default();
+    if backup {
+        // patches applied in quick succession must not share a backup
+        let name = Utc::now().format("%Y%m%d%H%M%S%3f").to_string();
+        let mut backup_dir = qop_dir.backups().join(&name);
+        let mut n = 1;
+        while backup_dir.exists() {
+            backup_dir = qop_dir.backups().join(format!("{}-{}", name, n));
+            n += 1;
+        }
+        let mut count = 0;
+        for path in patch.files.keys().chain(patch.deleted.iter()).chain(patch.renamed.values()) {
+            // files added by the patch have nothing to back up
+            let path = at(path)?;
+            if std::fs::symlink_metadata(&path).is_err() {
+                continue;
+            }
+            let backup_path = backup_dir.join(path.strip_prefix(&root)?);
+            if let Some(parent) = backup_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            copy_file(&path, &backup_path)?;
+            count += 1;
+        }
+        report.backup = Some((backup_dir, count));
+    }
+
+    for path in &patch.deleted {
+        // files that changed since the patch was built are kept, their changes
+        // would be lost
+        if mismatched.contains(path) {
+            report.rejects.push(format!("{}: does not match the pre hash, not deleted", path));
+            continue;
+        }
+        log::debug!("delete {}", path);
+        std::fs::remove_file(at(path)?)?;
+        report.deleted.push(path.clone());
+    }
+    rename_files(&root, &patch.renamed)?;
+
+    let mut applied = patch
+        .files
+        .iter()
+        .map(|(path, patch_file)| {
+            let (added, removed) = patch_file.line_counts();
+            AppliedFile {
+                path: path.clone(),
+                pre_hash: patch_file.pre_hash.clone(),
+                post_hash: patch_file.post_hash.clone(),
+                added,
+                removed,
+                verified: None,
+            }
+        })
+        .collect::<Vec<_>>();
+    for mut patch_file in patch.files {
+        log::debug!("patch {}", patch_file.0);
+        let dest = at(&patch_file.0)?;
+        let whole_file = patch_file.1.symlink.is_some() || patch_file.1.binary.is_some();
+        if whole_file && mismatched.contains(&patch_file.0) {
+            if merge {
+                return Err(anyhow::anyhow!(
+                    "can not merge binary file or symlink: {}",
+                    patch_file.0
+                ));
+            }
+            // the content is replaced as a whole, there are no hunks that could
+            // still fit
+            report.rejects.push(format!("{}: does not match the pre hash", patch_file.0));
+            continue;
+        }
+        if let Some(symlink) = &patch_file.1.symlink {
+            if let Some(target) = &symlink.post {
+                let path = dest.as_path();
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                if std::fs::symlink_metadata(path).is_ok() {
+                    std::fs::remove_file(path)?;
+                }
+                create_symlink(target, path)?;
+                report.patched += 1;
+                continue;
+            }
+        }
+        if let Some(binary) = &patch_file.1.binary {
+            let content = base64::engine::general_purpose::STANDARD.decode(&binary.post)?;
+            write_atomic(&dest, &content)?;
+            set_file_mode(&dest, patch_file.1.post_mode)?;
+            if !verify_post_hash(patch.hash, &patch_file.1, &content) {
+                report.post_hash_mismatches.push(patch_file.0.clone());
+            }
+            report.patched += 1;
+            continue;
+        }
+        if patch_file.1.hunks.is_empty()
+            && !patch.added.contains(&patch_file.0)
+            && patch_file.1.pre_hash == patch_file.1.post_hash
+        {
+            // only the permissions or the modification time changed, a file
+            // without hunks but another hash only changed its line endings
+            // and is written again below
+            set_file_mtime(&dest, patch_file.1.post_mtime)?;
+            set_file_mode(&dest, patch_file.1.post_mode)?;
+            report.patched += 1;
+            continue;
+        }
+
+        patch_file.1.hunks.sort_by_key(|x| x.old_range.0);
+
+        // a missing file is an empty base, hunks that only insert lines create
+        // it
+        let file_old = match std::fs::read_to_string(&dest) {
+            | Ok(v) if !patch.added.contains(&patch_file.0) => v,
+            | Ok(_) => String::new(),
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            | Err(e) => return Err(e.into()),
+        };
+
+        let line_ending = match line_endings {
+            | LineEndings::Preserve => {
+                patch_file.1.line_ending.or_else(|| LineEnding::detect(&file_old)).unwrap_or(LineEnding::Lf)
+            },
+            | LineEndings::Lf => LineEnding::Lf,
+            | LineEndings::Crlf => LineEnding::Crlf,
+        };
+
+        if let Some(index) = index.as_ref().filter(|_| mismatched.contains(&patch_file.0)) {
+            let base = match read_stored(qop_dir, index.compression, &patch_file.1.pre_hash) {
+                | Ok(v) => String::from_utf8(v)?.replace("\r\n", "\n"),
+                | Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(anyhow::anyhow!("no merge base in the store for {}", patch_file.0));
+                },
+                | Err(e) => return Err(e.into()),
+            };
+            let (theirs, _, rejected) = apply_hunks(&base, &patch_file.1.hunks, fuzz);
+            if !rejected.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "failed to apply the patch to the merge base of {}",
+                    patch_file.0
+                ));
+            }
+            let base = base.lines().map(|x| x.to_owned()).collect::<Vec<_>>();
+            let ours = file_old.lines().map(|x| x.to_owned()).collect::<Vec<_>>();
+            let (merged, conflict) = merge3(&base, &ours, &theirs);
+            if conflict {
+                report.conflicts.push(patch_file.0.clone());
+            }
+            let final_newline = file_old.is_empty() || file_old.ends_with('\n');
+            let content = join_lines(&merged, line_ending, final_newline);
+            write_atomic(&dest, content.as_bytes())?;
+            set_file_mode(&dest, patch_file.1.post_mode)?;
+            report.patched += 1;
+            continue;
+        }
+
+        // the old file stays in memory as one string that the lines borrow
+        // from, hunks that moved may match anywhere in it. Only the new
+        // content is streamed to the file instead of being built in memory.
+        let old_lines = file_old.lines().collect::<Vec<_>>();
+        let placed = place_hunks(&file_old, &old_lines, &patch_file.1.hunks, fuzz);
+        let rejected = placed.rejected;
+        if !rejected.is_empty() {
+            let lines = rejected.iter().map(|x| (x.old_range.0 + 1).to_string()).collect::<Vec<_>>();
+            if !reject {
+                // the file is left as it is
+                report.rejects.push(format!("{}: hunks at lines {}", patch_file.0, lines.join(", ")));
+                continue;
+            }
+            let reject_path = write_rejects(&root, &patch_file.0, rejected.iter().copied())?;
+            report.rejects.push(format!(
+                "{}: hunks at lines {} written to {}",
+                patch_file.0,
+                lines.join(", "),
+                reject_path.display()
+            ));
+        }
+        write_atomic_with(&dest, |out| {
+            Ok(write_lines(
+                out,
+                &old_lines,
+                &placed.hunks,
+                line_ending,
+                placed.final_newline,
+            )?)
+        })?;
+        set_file_mode(&dest, patch_file.1.post_mode)?;
+        // files with only some of their hunks rejected are patched all the
+        // same
+        if rejected.is_empty() || !placed.hunks.is_empty() {
+            report.patched += 1;
+        }
+        if rejected.is_empty()
+            && !patch_file.1.post_hash.is_empty()
+            && hash_file(patch.hash, &dest)? != patch_file.1.post_hash
+        {
+            report.post_hash_mismatches.push(patch_file.0.clone());
+        }
+    }
+
+    // the files are hashed again as they are on disk now, whichever way they
+    // were written
+    for file in applied.iter_mut().filter(|x| !x.post_hash.is_empty()) {
+        let verified = match hash_file(patch.hash, at(&file.path)?) {
+            | Ok(v) => v == file.post_hash,
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            | Err(e) => return Err(e.into()),
+        };
+        file.verified = Some(verified);
+    }
+    report.files = applied;
+    report.renamed = patch.renamed.len();
+    report.rejects.sort();
+    report.conflicts.sort();
+    Ok(report)
+}
+
+/// Moves the files of `renamed` from the old path in the value to the new path
+/// in the key, both below `root`. All files are moved out of the way first, so
+/// that renames may form chains or swap files.
+fn rename_files(root: &Path, renamed: &BTreeMap<String, String>) -> Result<()> {
+    let mut moved = Vec::new();
+    for (new, old) in renamed {
+        let old_path = resolve_path(root, old)?;
+        let tmp = format!("{}.qop-rename-{}", old_path.display(), std::process::id());
+        std::fs::rename(&old_path, &tmp).with_context(|| format!("failed to rename {} to {}", old, new))?;
+        moved.push((tmp, new));
+    }
+    for (tmp, new) in moved {
+        log::debug!("rename to {}", new);
+        let new = resolve_path(root, new)?;
+        if let Some(parent) = new.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&tmp, new)?;
+    }
+    Ok(())
+}
+
+/// Copies of the files that a series of patches touches, kept in the qop
+/// directory while they are applied, so that they can be put back if any of the
+/// patches fails.
+#[derive(Debug)]
+pub struct Transaction {
+    dir: PathBuf,
+    root: PathBuf,
+    /// Whether the file existed before, by path key. The copies of existing
+    /// files are below `dir`.
+    saved: BTreeMap<String, bool>,
+}
+
+impl Transaction {
+    /// Starts a transaction for the files below `root`. Fails if the copies of
+    /// an interrupted one are still there.
+    pub fn begin(qop_dir: &QopDir, root: &Path) -> Result<Self> {
+        let dir = qop_dir.transaction();
+        if dir.exists() {
+            return Err(anyhow::anyhow!(
+                "an interrupted `qop apply --atomic` left the original files in {}, restore what you need and delete \
+                 it",
+                dir.display()
+            ));
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            root: root.to_owned(),
+            saved: BTreeMap::new(),
+        })
+    }
+
+    /// Saves the files that `patch` changes and the reject files that
+    /// applying it may write next to them, unless an earlier patch of the
+    /// transaction already did.
+    pub fn record(&mut self, patch: &Patch) -> Result<()> {
+        for path in patch.files.keys().chain(patch.deleted.iter()).chain(patch.renamed.values()) {
+            self.save(path)?;
+        }
+        for path in patch.files.keys() {
+            self.record_reject(path)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the reject file that `write_rejects` writes for `path`.
+    pub fn record_reject(&mut self, path: &str) -> Result<()> {
+        self.save(&format!("{}.rej", path))
+    }
+
+    fn save(&mut self, path: &str) -> Result<()> {
+        let key = path_key(Path::new(path)).with_context(|| format!("invalid path in the patch: {}", path))?;
+        if self.saved.contains_key(&key) {
+            return Ok(());
+        }
+        let path = resolve_path(&self.root, &key)?;
+        let exists = std::fs::symlink_metadata(&path).is_ok();
+        if exists {
+            let copy = self.dir.join(key_path(key.strip_prefix("./").unwrap_or("")));
+            if let Some(parent) = copy.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            copy_file(&path, &copy)?;
+        }
+        self.saved.insert(key, exists);
+        Ok(())
+    }
+
+    /// Keeps the changes and deletes the copies.
+    pub fn commit(self) -> Result<()> {
+        std::fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    /// Puts back the saved files and removes the ones that did not exist.
+    /// Returns the number of paths that were restored. The copies are only
+    /// deleted once all of them are back.
+    pub fn rollback(self) -> Result<usize> {
+        for (key, existed) in &self.saved {
+            let path = resolve_path(&self.root, key)?;
+            if std::fs::symlink_metadata(&path).is_ok_and(|x| x.is_file() || x.file_type().is_symlink()) {
+                std::fs::remove_file(&path)?;
+            }
+            if *existed {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let copy = self.dir.join(key_path(key.strip_prefix("./").unwrap_or("")));
+                copy_file(&copy, &path).with_context(|| format!("failed to restore {}", key))?;
+            }
+        }
+        std::fs::remove_dir_all(&self.dir)?;
+        Ok(self.saved.len())
+    }
+}
+
+/// Directory that `apply --output-dir` writes the patched files to, leaving
+/// the ones below the root of the patch as they are. The files a patch
+/// touches are copied there first and patched in place of the originals.
+#[derive(Debug)]
+pub struct OutputDir {
+    dir: PathBuf,
+    root: PathBuf,
+    /// Path keys of the files copied so far, later patches see the result of
+    /// the earlier ones.
+    copied: BTreeSet<String>,
+}
+
+impl OutputDir {
+    /// Creates the output directory. With `copy_unchanged`, all files of the
+    /// working copy below `root` are copied, not only the ones of the patches.
+    pub fn create(qop_dir: &QopDir, root: &Path, dir: &Path, copy_unchanged: bool) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let canonical = dir.canonicalize()?;
+        if root.canonicalize()? == canonical {
+            return Err(anyhow::anyhow!(
+                "the output directory {} is the root of the patch",
+                dir.display()
+            ));
+        }
+        let mut output = Self {
+            dir: dir.to_owned(),
+            root: root.to_owned(),
+            copied: BTreeSet::new(),
+        };
+        if copy_unchanged {
+            for path in collect_files(qop_dir, root, &mut Vec::new())? {
+                // an output directory inside the root is not copied into itself
+                if path.canonicalize().is_ok_and(|x| x.starts_with(&canonical)) {
+                    continue;
+                }
+                output.copy(&path_key(path.strip_prefix(root)?)?)?;
+            }
+        }
+        Ok(output)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Copies the files that `patch` reads from the root, unless they are
+    /// there already.
+    pub fn stage(&mut self, patch: &Patch) -> Result<()> {
+        for path in patch.files.keys().chain(patch.deleted.iter()).chain(patch.renamed.values()) {
+            let key = path_key(Path::new(path)).with_context(|| format!("invalid path in the patch: {}", path))?;
+            // files added by the patch have nothing to copy
+            if !self.copied.contains(&key) && std::fs::symlink_metadata(resolve_path(&self.root, &key)?).is_ok() {
+                self.copy(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, key: &str) -> Result<()> {
+        let to = resolve_path(&self.dir, key)?;
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        copy_file(&resolve_path(&self.root, key)?, &to)?;
+        self.copied.insert(key.to_owned());
+        Ok(())
+    }
+}
+
+/// Writes hunks of a file that were not applied next to it, as a unified diff
+/// with the extension `.rej` appended. Returns the path of the reject file.
+pub fn write_rejects<'a>(root: &Path, path: &str, hunks: impl IntoIterator<Item=&'a PatchFileHunk>) -> Result<PathBuf> {
+    let name = path.strip_prefix("./").unwrap_or(path);
+    let mut content = format!("--- a/{}\n+++ b/{}\n", name, name);
+    for hunk in hunks {
+        content.push_str(&render_hunk(hunk));
+    }
+    let mut reject_path = resolve_path(root, path)?.into_os_string();
+    reject_path.push(".rej");
+    let reject_path = PathBuf::from(reject_path);
+    std::fs::write(&reject_path, content)?;
+    Ok(reject_path)
+}
+
+/// Applies sorted hunks to `file_old`. Returns the new lines, whether the
+/// result ends with a newline and the hunks that could not be placed and were
+/// left out. See `place_hunks` for where the hunks go.
+pub fn apply_hunks<'a>(
+    file_old: &str,
+    hunks: &'a [PatchFileHunk],
+    fuzz: usize,
+) -> (Vec<String>, bool, Vec<&'a PatchFileHunk>) {
+    let old_lines = file_old.lines().collect::<Vec<_>>();
+    let placed = place_hunks(file_old, &old_lines, hunks, fuzz);
+    let mut file_new = Vec::new();
+    let Ok(()) = emit_lines(&old_lines, &placed.hunks, |x| -> Result<(), std::convert::Infallible> {
+        file_new.push(x.to_owned());
+        Ok(())
+    });
+    (file_new, placed.final_newline, placed.rejected)
+}
+
+/// Outcome of `place_hunks`.
+struct PlacedHunks<'a> {
+    /// The applied hunks and the line of the old file they start at.
+    hunks: Vec<(usize, &'a PatchFileHunk)>,
+    /// Whether the result ends with a newline.
+    final_newline: bool,
+    rejected: Vec<&'a PatchFileHunk>,
+}
+
+/// Finds where sorted hunks apply to the lines of `file_old`. Hunks are
+/// expected at their old range, shifted by the offset at which the previous
+/// hunk applied. If their context and removed lines do not match there, the
+/// closest position where they do is used instead. Up to `fuzz` context lines
+/// of a hunk may differ from the file, removed lines always have to match.
+fn place_hunks<'a>(file_old: &str, old_lines: &[&str], hunks: &'a [PatchFileHunk], fuzz: usize) -> PlacedHunks<'a> {
+    let matches_at = |hunk: &PatchFileHunk, start: usize| -> bool {
+        let mut pos = start;
+        let mut mismatches = 0;
+        for line in hunk.diff.lines() {
+            let tag = line.chars().next();
+            if !matches!(tag, Some(' ' | '-')) {
+                continue;
+            }
+            if old_lines.get(pos) != Some(&line.get(1..).unwrap_or("")) {
+                if tag == Some('-') || pos >= old_lines.len() {
+                    return false;
+                }
+                mismatches += 1;
+                if mismatches > fuzz {
+                    return false;
+                }
+            }
+            pos += 1;
+        }
+        true
+    };
+
+    let mut placed = PlacedHunks {
+        hunks: Vec::new(),
+        // an empty base has no final line that could lack a newline
+        final_newline: file_old.is_empty() || file_old.ends_with('\n'),
+        rejected: Vec::new(),
+    };
+    let mut cursor = 0_usize;
+    let mut offset = 0_isize;
+    for hunk in hunks {
+        // a hunk without context or removed lines matches anywhere, past the
+        // end of a shorter file it would silently be appended instead
+        let anchored = hunk.diff.lines().any(|x| x.starts_with([' ', '-']));
+        if !anchored && hunk.old_range.0 as isize + offset > old_lines.len() as isize {
+            placed.rejected.push(hunk);
+            continue;
+        }
+        let expected = (hunk.old_range.0 as isize + offset).clamp(cursor as isize, old_lines.len() as isize) as usize;
+        // search outwards from the expected position, the closest match wins
+        let start = (0..=old_lines.len())
+            .flat_map(|x| [expected.checked_sub(x), Some(expected + x)])
+            .flatten()
+            .filter(|x| (cursor..=old_lines.len()).contains(x))
+            .find(|x| matches_at(hunk, *x));
+        let Some(start) = start else {
+            placed.rejected.push(hunk);
+            continue;
+        };
+        if start != hunk.old_range.0 {
+            log::info!(
+                "hunk at line {} applied at line {} (offset {})",
+                hunk.old_range.0 + 1,
+                start + 1,
+                start as isize - hunk.old_range.0 as isize
+            );
+        }
+        offset = start as isize - hunk.old_range.0 as isize;
+
+        if hunk.new_no_newline {
+            placed.final_newline = false;
+        } else if hunk.old_no_newline {
+            placed.final_newline = true;
+        }
+        cursor = start + hunk.diff.lines().filter(|x| x.starts_with([' ', '-'])).count();
+        placed.hunks.push((start, hunk));
+    }
+    placed
+}
+
+/// Passes the lines of the patched file to `emit` in order.
+fn emit_lines<E>(
+    old_lines: &[&str],
+    hunks: &[(usize, &PatchFileHunk)],
+    mut emit: impl FnMut(&str) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut cursor = 0;
+    for (start, hunk) in hunks {
+        for line in &old_lines[cursor..*start] {
+            emit(line)?;
+        }
+        let mut pos = *start;
+        for line in hunk.diff.lines() {
+            match line.chars().next() {
+                | Some('+') => emit(&line[1..])?,
+                // context that differs within the fuzz keeps the content of the
+                // file
+                | Some(' ') => {
+                    emit(old_lines[pos])?;
+                    pos += 1;
+                },
+                | Some('-') => pos += 1,
+                | _ => (),
+            }
+        }
+        cursor = pos;
+    }
+    for line in &old_lines[cursor..] {
+        emit(line)?;
+    }
+    Ok(())
+}
+
+/// Writes lines separated by `line_ending`, the way `join_lines` joins them.
+fn write_lines(
+    out: &mut dyn std::io::Write,
+    old_lines: &[&str],
+    hunks: &[(usize, &PatchFileHunk)],
+    line_ending: LineEnding,
+    final_newline: bool,
+) -> std::io::Result<()> {
+    let mut empty = true;
+    emit_lines(old_lines, hunks, |line| {
+        if !empty {
+            out.write_all(line_ending.as_str().as_bytes())?;
+        }
+        empty = false;
+        out.write_all(line.as_bytes())
+    })?;
+    if final_newline && !empty {
+        out.write_all(line_ending.as_str().as_bytes())?;
+    }
+    Ok(())
+}
+
+fn join_lines(lines: &[String], line_ending: LineEnding, final_newline: bool) -> String {
+    let mut content = lines.join(line_ending.as_str());
+    if final_newline && !lines.is_empty() {
+        content.push_str(line_ending.as_str());
+    }
+    content
+}
+
+/// Tells whether the written content matches the post hash of the patch.
+/// Patches without hashes always match.
+fn verify_post_hash(algorithm: HashAlgorithm, patch_file: &PatchFile, content: &[u8]) -> bool {
+    patch_file.post_hash.is_empty() || hash(algorithm, content) == patch_file.post_hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diff::compute_diff,
+        store::checkpoint,
+        testing::{
+            apply_options,
+            diff_options,
+            file_patch,
+            in_dir,
+            TempDir,
+        },
+    };
+
+    /// Writes `pre` to a file, applies the patch from `pre` to `post` and
+    /// returns the patched content.
+    fn apply_change(pre: &str, post: &str) -> String {
+        let dir = TempDir::new();
+        dir.write("file.txt", pre);
+        let patch = file_patch("./file.txt", pre.as_bytes(), post.as_bytes());
+        let report = apply_patch(&QopDir::new(dir.path().join(".qop")), patch, apply_options(dir.path())).unwrap();
+        assert!(report.rejects.is_empty());
+        assert!(report.post_hash_mismatches.is_empty());
+        String::from_utf8(dir.read("file.txt")).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_final_newline() {
+        assert_eq!(apply_change("a\nb\nc\n", "a\nB\nc\n"), "a\nB\nc\n");
+        assert_eq!(apply_change("a\nb\nc\n", "a\nb\nc\nd\n"), "a\nb\nc\nd\n");
+    }
+
+    #[test]
+    fn keeps_a_missing_final_newline() {
+        let patch = file_patch("./file.txt", b"a\nb\nc", b"a\nB\nc");
+        let hunk = &patch.files["./file.txt"].hunks[0];
+        assert!(hunk.old_no_newline && hunk.new_no_newline);
+        assert_eq!(apply_change("a\nb\nc", "a\nB\nc"), "a\nB\nc");
+        assert_eq!(apply_change("a\nb\nc", "A\nb\nc"), "A\nb\nc");
+    }
+
+    #[test]
+    fn adds_and_removes_the_final_newline() {
+        assert_eq!(apply_change("a\nb", "a\nb\n"), "a\nb\n");
+        assert_eq!(apply_change("a\nb\n", "a\nb"), "a\nb");
+    }
+
+    #[test]
+    fn files_written_before_a_failure_are_complete() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a\n");
+        std::fs::create_dir(dir.path().join("b.txt")).unwrap();
+        let mut patch = file_patch("./a.txt", b"a\n", b"a\nmore\n");
+        let b = file_patch("./b.txt", b"b\n", b"c\n");
+        patch.files.extend(b.files);
+        let options = ApplyOptions {
+            force: true,
+            ..apply_options(dir.path())
+        };
+
+        // the second file is a directory and can not be patched
+        assert!(apply_patch(&QopDir::new(dir.path().join(".qop")), patch, options).is_err());
+        assert_eq!(dir.read("a.txt"), b"a\nmore\n");
+        let mut names = std::fs::read_dir(dir.path()).unwrap().map(|x| x.unwrap().file_name()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn rewrites_files_whose_line_endings_changed() {
+        let patch = file_patch("./file.txt", b"a\nb\n", b"a\r\nb\r\n");
+        assert!(patch.files["./file.txt"].hunks.is_empty());
+        assert_eq!(apply_change("a\nb\n", "a\r\nb\r\n"), "a\r\nb\r\n");
+        assert_eq!(apply_change("a\r\nb", "a\nb"), "a\nb");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn executable_scripts_stay_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let set_mode =
+            |path: &Path, mode| std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+        set_mode(&dir.write("run.sh", "echo a\n"), 0o644);
+        set_mode(&dir.write("mode-only.sh", "echo\n"), 0o644);
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        in_dir(dir.path(), || {
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            set_mode(&dir.write("run.sh", "echo b\n"), 0o755);
+            set_mode(&dir.path().join("mode-only.sh"), 0o755);
+            let patch = compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch;
+            assert_eq!(patch.files["./run.sh"].post_mode, Some(0o755));
+
+            set_mode(&dir.write("run.sh", "echo a\n"), 0o644);
+            set_mode(&dir.path().join("mode-only.sh"), 0o644);
+            let report = apply_patch(&qop_dir, patch, apply_options(dir.path())).unwrap();
+            assert!(report.rejects.is_empty());
+        });
+        for path in ["run.sh", "mode-only.sh"] {
+            let mode = std::fs::metadata(dir.path().join(path)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o755, "{}", path);
+        }
+        assert_eq!(dir.read("run.sh"), b"echo b\n");
+    }
+
+    #[test]
+    fn context_lines_have_to_match() {
+        let patch = file_patch("./file.txt", b"a\nb\nc\nd\ne\n", b"a\nb\nC\nd\ne\n");
+        let hunks = &patch.files["./file.txt"].hunks;
+
+        let (lines, _, rejected) = apply_hunks("a\nb\nc\nd\ne\n", hunks, 0);
+        assert!(rejected.is_empty());
+        assert_eq!(lines, ["a", "b", "C", "d", "e"]);
+        // a changed context line rejects the hunk unless the fuzz allows it
+        let (_, _, rejected) = apply_hunks("a\nB\nc\nd\ne\n", hunks, 0);
+        assert_eq!(rejected.len(), 1);
+        let (lines, _, rejected) = apply_hunks("a\nB\nc\nd\ne\n", hunks, 1);
+        assert!(rejected.is_empty());
+        assert_eq!(lines, ["a", "B", "C", "d", "e"]);
+        // removed lines always have to match
+        let (_, _, rejected) = apply_hunks("a\nb\nx\nd\ne\n", hunks, 2);
+        assert_eq!(rejected.len(), 1);
+    }
+
+    #[test]
+    fn writes_the_hunks_that_still_fit_and_rejects_the_rest() {
+        let dir = TempDir::new();
+        let pre = (1..=20).map(|x| format!("{}\n", x)).collect::<String>();
+        dir.write("file.txt", &pre);
+        dir.write("gone.txt", "gone\n");
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        let report = in_dir(dir.path(), || {
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            dir.write("file.txt", pre.replace("2\n", "two\n").replace("19\n", "nineteen\n"));
+            std::fs::remove_file("gone.txt").unwrap();
+            let patch = compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch;
+            // the second hunk no longer fits and the deleted file changed
+            dir.write("file.txt", pre.replace("18\n", "eighteen\n"));
+            dir.write("gone.txt", "gone\nlocal edit\n");
+
+            let options = ApplyOptions {
+                reject: true,
+                ..apply_options(dir.path())
+            };
+            apply_patch(&qop_dir, patch, options).unwrap()
+        });
+        assert_eq!(report.rejects.len(), 2);
+        assert!(report.rejects[0].ends_with("file.txt.rej"), "{:?}", report.rejects);
+        assert_eq!(
+            report.rejects[1],
+            "./gone.txt: does not match the pre hash, not deleted"
+        );
+        assert_eq!(dir.read("gone.txt"), b"gone\nlocal edit\n");
+        let content = String::from_utf8(dir.read("file.txt")).unwrap();
+        assert!(content.starts_with("1\ntwo\n3\n"), "{}", content);
+        assert!(content.contains("eighteen\n19\n"), "{}", content);
+        let rejects = String::from_utf8(dir.read("file.txt.rej")).unwrap();
+        assert!(
+            rejects.starts_with("--- a/file.txt\n+++ b/file.txt\n@@ "),
+            "{}",
+            rejects
+        );
+        assert!(rejects.contains("+nineteen\n"), "{}", rejects);
+    }
+
+    #[test]
+    fn counts_files_written_with_rejected_hunks() {
+        let dir = TempDir::new();
+        let pre = (1..=20).map(|x| format!("{}\n", x)).collect::<String>();
+        dir.write("partial.txt", &pre);
+        dir.write("rejected.txt", "changed\n");
+        let mut patch = file_patch(
+            "./partial.txt",
+            pre.as_bytes(),
+            pre.replace("2\n", "two\n").replace("19\n", "nineteen\n").as_bytes(),
+        );
+        patch.files.extend(file_patch("./rejected.txt", b"old\n", b"new\n").files);
+        // the second hunk of the first file no longer fits
+        dir.write("partial.txt", pre.replace("18\n", "eighteen\n"));
+
+        let options = ApplyOptions {
+            reject: true,
+            force: true,
+            ..apply_options(dir.path())
+        };
+        let report = apply_patch(&QopDir::new(dir.path().join(".qop")), patch, options).unwrap();
+        assert_eq!(report.rejects.len(), 2);
+        assert_eq!(report.patched, 1);
+        assert!(String::from_utf8(dir.read("partial.txt")).unwrap().starts_with("1\ntwo\n3\n"));
+    }
+
+    #[test]
+    fn keeps_deleted_files_that_changed_since_the_diff() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a\n");
+        dir.write("b.txt", "b\n");
+        in_dir(dir.path(), || {
+            let qop_dir = QopDir::new(dir.path().join(".qop"));
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            std::fs::remove_file("b.txt").unwrap();
+            let patch = compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch;
+            assert_eq!(patch.deleted, ["./b.txt"]);
+            assert_eq!(patch.deleted_hashes["./b.txt"], hash(patch.hash, b"b\n"));
+
+            dir.write("b.txt", "b\nlocal edit\n");
+            let e = apply_patch(&qop_dir, patch.clone(), apply_options(dir.path())).unwrap_err();
+            assert!(e.to_string().contains("./b.txt"), "{}", e);
+            assert_eq!(mismatched_files(&patch, dir.path()).unwrap(), ["./b.txt"]);
+            let options = ApplyOptions {
+                reject: true,
+                ..apply_options(dir.path())
+            };
+            let report = apply_patch(&qop_dir, patch.clone(), options).unwrap();
+            assert!(report.deleted.is_empty());
+            assert_eq!(report.rejects.len(), 1);
+            assert_eq!(dir.read("b.txt"), b"b\nlocal edit\n");
+
+            let options = ApplyOptions {
+                force: true,
+                ..apply_options(dir.path())
+            };
+            let report = apply_patch(&qop_dir, patch.clone(), options).unwrap();
+            assert_eq!(report.deleted, ["./b.txt"]);
+            assert!(!dir.path().join("b.txt").exists());
+
+            // the file as it was when the patch was built is deleted
+            dir.write("b.txt", "b\n");
+            let report = apply_patch(&qop_dir, patch, apply_options(dir.path())).unwrap();
+            assert_eq!(report.deleted, ["./b.txt"]);
+        });
+    }
+
+    #[test]
+    fn missing_files_are_an_empty_base() {
+        let dir = TempDir::new();
+        let patch = file_patch("./new/nested/file.txt", b"", b"a\nb\n");
+        let report = apply_patch(&QopDir::new(dir.path().join(".qop")), patch, apply_options(dir.path())).unwrap();
+        assert!(report.rejects.is_empty());
+        assert_eq!(report.patched, 1);
+        assert_eq!(dir.read("new/nested/file.txt"), b"a\nb\n");
+    }
+
+    #[test]
+    fn paths_resolve_below_the_root() {
+        let root = Path::new("/root/dir");
+        assert_eq!(resolve_path(root, "./a/b.txt").unwrap(), root.join("a").join("b.txt"));
+        assert_eq!(resolve_path(root, "a/./b.txt").unwrap(), root.join("a").join("b.txt"));
+        for path in ["../b.txt", "./a/../../b.txt", "/etc/passwd"] {
+            assert!(resolve_path(root, path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn patches_apply_below_another_root() {
+        let (cwd, root) = (TempDir::new(), TempDir::new());
+        root.write("file.txt", "old\n");
+        let patch = file_patch("./file.txt", b"old\n", b"new\n");
+        apply_patch(&QopDir::new(cwd.path().join(".qop")), patch, apply_options(root.path())).unwrap();
+        assert_eq!(root.read("file.txt"), b"new\n");
+
+        let patch = file_patch("../file.txt", b"", b"escaped\n");
+        assert!(apply_patch(
+            &QopDir::new(cwd.path().join(".qop")),
+            patch,
+            apply_options(&root.path().join("sub"))
+        )
+        .is_err());
+        assert!(!root.path().join("sub").exists());
+        assert_eq!(root.read("file.txt"), b"new\n");
+    }
+
+    #[test]
+    fn patches_large_files() {
+        let pre = (0..200_000).map(|x| format!("line {}\n", x)).collect::<String>();
+        let post = pre
+            .replace("line 3\n", "line three\n")
+            .replace("line 100000\n", "")
+            .replace("line 199998\n", "line 199998\ninserted\n");
+        let patch = file_patch("./file.txt", pre.as_bytes(), post.as_bytes());
+        assert_eq!(patch.files["./file.txt"].hunks.len(), 3);
+
+        let dir = TempDir::new();
+        dir.write("file.txt", &pre);
+        let report = apply_patch(
+            &QopDir::new(dir.path().join(".qop")),
+            patch.clone(),
+            apply_options(dir.path()),
+        )
+        .unwrap();
+        assert!(report.rejects.is_empty() && report.post_hash_mismatches.is_empty());
+        assert_eq!(dir.read("file.txt"), post.as_bytes());
+
+        // hunks are still found after lines were added in front of them
+        dir.write("file.txt", format!("first\nsecond\n{}", &pre[..pre.len() - 1]));
+        let options = ApplyOptions {
+            force: true,
+            ..apply_options(dir.path())
+        };
+        let report = apply_patch(&QopDir::new(dir.path().join(".qop")), patch, options).unwrap();
+        assert!(report.rejects.is_empty());
+        assert_eq!(
+            dir.read("file.txt"),
+            format!("first\nsecond\n{}", &post[..post.len() - 1]).as_bytes()
+        );
+    }
+
+    #[test]
+    fn creates_missing_parent_directories() {
+        let dir = TempDir::new();
+        dir.write("a/existing.txt", "existing\n");
+        let mut patch = file_patch("./a/b/c/new.txt", b"", b"new\n");
+        patch.added.push("./a/b/c/new.txt".to_owned());
+        let report = apply_patch(&QopDir::new(dir.path().join(".qop")), patch, apply_options(dir.path())).unwrap();
+        assert_eq!(report.patched, 1);
+        assert_eq!(dir.read("a/b/c/new.txt"), b"new\n");
+        assert_eq!(dir.read("a/existing.txt"), b"existing\n");
+    }
+
+    #[test]
+    fn rejects_hunks_past_the_end_of_the_file() {
+        let pre = (1..=50).map(|x| format!("{}\n", x)).collect::<String>();
+        // a hunk without context or removed lines only has its line number
+        let mut insert_only = file_patch(
+            "./file.txt",
+            pre.as_bytes(),
+            pre.replace("40\n", "40\nnew\n").as_bytes(),
+        );
+        let hunk = &mut insert_only.files.get_mut("./file.txt").unwrap().hunks[0];
+        (hunk.diff, hunk.old_range, hunk.new_range) = ("+new\n".to_owned(), (40, 40), (40, 41));
+        let anchored = file_patch(
+            "./file.txt",
+            pre.as_bytes(),
+            pre.replace("45\n", "forty-five\n").as_bytes(),
+        );
+
+        for patch in [insert_only, anchored] {
+            let dir = TempDir::new();
+            dir.write("file.txt", "1\n2\n3\n4\n5\n");
+            let options = ApplyOptions {
+                force: true,
+                ..apply_options(dir.path())
+            };
+            let report = apply_patch(&QopDir::new(dir.path().join(".qop")), patch, options).unwrap();
+            assert_eq!(report.rejects.len(), 1);
+            assert_eq!(report.patched, 0);
+            assert_eq!(dir.read("file.txt"), b"1\n2\n3\n4\n5\n");
+        }
+    }
+
+    #[test]
+    fn rollbacks_remove_reject_files() {
+        let dir = TempDir::new();
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        dir.write("file.txt", "changed\n");
+        dir.write("other.txt", "other\n");
+        dir.write("old.txt.rej", "kept\n");
+        let patch = file_patch("./file.txt", b"old\n", b"new\n");
+        let left_out = file_patch("./other.txt", b"other\n", b"new\n");
+
+        let mut transaction = Transaction::begin(&qop_dir, dir.path()).unwrap();
+        transaction.record_reject("./other.txt").unwrap();
+        write_rejects(dir.path(), "./other.txt", &left_out.files["./other.txt"].hunks).unwrap();
+        transaction.record_reject("./old.txt").unwrap();
+        std::fs::write(dir.path().join("old.txt.rej"), "overwritten\n").unwrap();
+        transaction.record(&patch).unwrap();
+        let options = ApplyOptions {
+            force: true,
+            reject: true,
+            ..apply_options(dir.path())
+        };
+        let report = apply_patch(&qop_dir, patch, options).unwrap();
+        assert_eq!(report.rejects.len(), 1);
+        assert!(dir.path().join("file.txt.rej").exists());
+
+        transaction.rollback().unwrap();
+        assert!(!dir.path().join("file.txt.rej").exists());
+        assert!(!dir.path().join("other.txt.rej").exists());
+        assert_eq!(dir.read("old.txt.rej"), b"kept\n");
+        assert_eq!(dir.read("file.txt"), b"changed\n");
+    }
+}
@@ -1,9 +1,34 @@
-use std::str::FromStr;
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+};
 
-use anyhow::Result;
+use anyhow::{
+    Context,
+    Result,
+};
 use clap::{
+    parser::ValueSource,
     Arg,
     ArgAction,
+    ArgMatches,
+};
+use qop::{
+    diff::{
+        DiffAlgorithm,
+        Whitespace,
+    },
+    hash::HashAlgorithm,
+    patch::{
+        ChangeFilter,
+        LineEndings,
+        PatchFormat,
+    },
+    store::Compression,
+    walk::parse_size,
 };
 
 #[derive(Debug, Eq, PartialEq)]
@@ -12,9 +37,77 @@ pub enum Privilege {
     Experimental,
 }
 
+/// Default values for arguments, read from `config.toml` in the qop directory
+/// and from `qop/config.toml` in the user's config directory. Arguments on the
+/// command line take precedence over the project config, which takes
+/// precedence over the global one, which takes precedence over the built-in
+/// defaults.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Hash algorithm of new stores.
+    pub hash: Option<String>,
+    /// Compression of new stores.
+    pub compression: Option<String>,
+    /// Patch format of `diff`, `apply` and `reverse`.
+    pub format: Option<String>,
+    /// Context lines of `diff`.
+    pub context: Option<usize>,
+    /// Whether hidden files of the working copy are collected.
+    pub hidden: Option<bool>,
+    /// Size above which files of the working copy are skipped, like `10M`.
+    pub max_file_size: Option<String>,
+    /// Name of the files with the ignore rules instead of `.qopfile`.
+    pub qopfile_name: Option<String>,
+}
+
+impl Config {
+    pub fn load(qop_dir: &Path) -> Result<Self> {
+        let global = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")))
+            .map(|x| x.join("qop").join("config.toml"));
+        let mut config = Self::default();
+        for path in global.into_iter().chain([qop_dir.join("config.toml")]) {
+            let content = match std::fs::read_to_string(&path) {
+                | Ok(v) => v,
+                | Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                | Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+            };
+            let other =
+                toml::from_str::<Self>(&content).with_context(|| format!("malformed config {}", path.display()))?;
+            config = Self {
+                hash: other.hash.or(config.hash),
+                compression: other.compression.or(config.compression),
+                format: other.format.or(config.format),
+                context: other.context.or(config.context),
+                hidden: other.hidden.or(config.hidden),
+                max_file_size: other.max_file_size.or(config.max_file_size),
+                qopfile_name: other.qopfile_name.or(config.qopfile_name),
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// Returns the value of an argument unless it only has its default value and
+/// the config has one.
+fn configured<'a>(subc: &'a ArgMatches, id: &str, config: Option<&'a String>) -> &'a str {
+    match (subc.value_source(id), config) {
+        | (Some(ValueSource::DefaultValue), Some(v)) => v,
+        | _ => subc.get_one::<String>(id).unwrap(),
+    }
+}
+
 #[derive(Debug)]
 pub struct CallArgs {
     pub privileges: Privilege,
+    /// Absolute path of the qop directory, see `resolve_qop_dir`.
+    pub qop_dir: PathBuf,
+    pub hidden: bool,
+    pub max_file_size: Option<u64>,
+    pub qopfile_name: String,
+    pub log_level: log::LevelFilter,
     pub command: Command,
 }
 
@@ -39,16 +132,161 @@ pub enum ManualFormat {
     Markdown,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Json,
+}
+
+/// Line format of `manifest`.
+#[derive(Debug, Clone, Copy)]
+pub enum ManifestFormat {
+    /// `<hash>  <path>` as read by `sha256sum -c`.
+    Sha256sum,
+    /// The same as `Sha256sum`, read by `b3sum -c`.
+    B3sum,
+    /// `SHA256 (<path>) = <hash>` as written by `sha256sum --tag`.
+    Bsd,
+}
+
+/// Output format of `diff`.
+#[derive(Debug, Clone, Copy)]
+pub enum DiffFormat {
+    Patch(PatchFormat),
+    /// A standalone HTML page for reading the changes in a browser.
+    Html,
+}
+
+/// What `diff` compares.
+#[derive(Debug, Clone)]
+pub enum DiffSource {
+    /// A checkpoint and the working copy.
+    Checkpoint(String),
+    /// Two directories, without a store.
+    Dirs(String, String),
+}
+
 #[derive(Debug)]
 pub enum Command {
-    Manual { path: String, format: ManualFormat },
-    Autocomplete { path: String, shell: clap_complete::Shell },
+    Manual {
+        path: String,
+        format: ManualFormat,
+    },
+    Autocomplete {
+        path: String,
+        shell: clap_complete::Shell,
+    },
 
-    Init,
-    Apply { file: String },
-    Diff { reverse: bool },
-    Reverse { file: String },
-    Checkpoint,
+    Init {
+        hash: HashAlgorithm,
+        compression: Compression,
+        manifest_only: bool,
+        dry_run: bool,
+    },
+    Apply {
+        files: Vec<String>,
+        format: PatchFormat,
+        reverse: bool,
+        interactive: bool,
+        changes: Option<ChangeFilter>,
+        line_endings: LineEndings,
+        fuzz: usize,
+        force: bool,
+        merge: bool,
+        dry_run: bool,
+        backup: bool,
+        reject: bool,
+        atomic: bool,
+        output_dir: Option<String>,
+        copy_unchanged: bool,
+        report: Option<ReportFormat>,
+        root: String,
+        paths: Vec<String>,
+    },
+    Diff {
+        from: DiffSource,
+        reverse: bool,
+        line_endings: LineEndings,
+        format: DiffFormat,
+        pretty: bool,
+        word_diff: bool,
+        /// Prints only the statistics, as JSON if set.
+        stat: Option<bool>,
+        interactive: bool,
+        algorithm: DiffAlgorithm,
+        whitespace: Whitespace,
+        context: usize,
+        quiet: bool,
+        exit_code: bool,
+        index: Option<String>,
+        find_renames: Option<u8>,
+        paths: Vec<String>,
+        output: String,
+        strict: bool,
+        include_metadata: bool,
+        fast_path: bool,
+    },
+    Log {
+        json: bool,
+    },
+    Prune {
+        keep: Option<usize>,
+        before: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    Migrate,
+    Fsck,
+    Doctor,
+    Status {
+        short: bool,
+    },
+    LsFiles {
+        with_hash: bool,
+        json: bool,
+        paths: Vec<String>,
+    },
+    Manifest {
+        format: ManifestFormat,
+    },
+    Du {
+        bytes: bool,
+    },
+    Hash {
+        files: Vec<String>,
+        /// Defaults to the algorithm of the store.
+        algorithm: Option<HashAlgorithm>,
+    },
+    Restore {
+        checkpoint: String,
+        dry_run: bool,
+        force: bool,
+    },
+    Checkout {
+        checkpoint: String,
+        force: bool,
+    },
+    Reverse {
+        file: String,
+        format: PatchFormat,
+        output: String,
+    },
+    Checkpoint {
+        name: Option<String>,
+        message: Option<String>,
+        author: Option<String>,
+        strict: bool,
+        dry_run: bool,
+    },
+    Watch {
+        interval: u64,
+    },
+    Blame {
+        path: String,
+        json: bool,
+    },
+    Validate {
+        file: String,
+        format: PatchFormat,
+        fix: bool,
+    },
 }
 
 pub struct ClapArgumentLoader {}
@@ -61,11 +299,43 @@ impl ClapArgumentLoader {
             .author("replicadse <aw@voidpointergroup.com>")
             .propagate_version(true)
             .subcommand_required(true)
-            .args([Arg::new("experimental")
-                .short('e')
-                .long("experimental")
-                .help("Enables experimental features.")
-                .num_args(0)])
+            .args([
+                Arg::new("experimental")
+                    .short('e')
+                    .long("experimental")
+                    .help("Enables experimental features.")
+                    .num_args(0),
+                Arg::new("qop-dir")
+                    .long("qop-dir")
+                    .help("The directory holding the store and the index.")
+                    .default_value("./.qop"),
+                Arg::new("no-hidden")
+                    .long("no-hidden")
+                    .help("Skips hidden files and directories of the working copy, whose name starts with a dot.")
+                    .global(true)
+                    .action(ArgAction::SetTrue),
+                Arg::new("max-file-size")
+                    .long("max-file-size")
+                    .help("Skips files of the working copy larger than this size, like 512, 100K, 10M or 1G.")
+                    .global(true),
+                Arg::new("qopfile-name")
+                    .long("qopfile-name")
+                    .help("Reads the ignore rules from files with this name instead of `.qopfile`.")
+                    .global(true),
+                Arg::new("quiet")
+                    .short('q')
+                    .long("quiet")
+                    .help("Only prints warnings and errors besides the output of the command.")
+                    .global(true)
+                    .conflicts_with("verbose")
+                    .action(ArgAction::SetTrue),
+                Arg::new("verbose")
+                    .short('v')
+                    .long("verbose")
+                    .help("Logs every processed file. Repeat for more detail.")
+                    .global(true)
+                    .action(ArgAction::Count),
+            ])
             .subcommand(
                 clap::Command::new("man")
                     .about("Renders the manual.")
@@ -90,27 +360,597 @@ impl ClapArgumentLoader {
                             .required(true),
                     ),
             )
-            .subcommand(clap::Command::new("init").about("init"))
-            .subcommand(clap::Command::new("checkpoint").about("checkpoint").alias("cp"))
+            .subcommand(
+                clap::Command::new("init")
+                    .about("init")
+                    .arg(
+                        clap::Arg::new("hash")
+                            .long("hash")
+                            .help("Hash algorithm of the store. It can not be changed later on.")
+                            .value_parser(["sha256", "blake3"])
+                            .default_value("sha256"),
+                    )
+                    .arg(
+                        clap::Arg::new("compression")
+                            .long("compression")
+                            .help("Compression of the stored file contents. It can not be changed later on.")
+                            .value_parser(["none", "zstd"])
+                            .default_value("zstd"),
+                    )
+                    .arg(
+                        clap::Arg::new("manifest-only")
+                            .long("manifest-only")
+                            .help(
+                                "Records only the hashes of the files without copying them into the store. Changes \
+                                 can be listed but not diffed or restored.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("dry-run")
+                            .long("dry-run")
+                            .help("Only prints the files that would be stored, without writing the store or the index.")
+                            .action(ArgAction::SetTrue),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("checkpoint")
+                    .about("checkpoint")
+                    .alias("cp")
+                    .arg(
+                        clap::Arg::new("name")
+                            .help("Name of the checkpoint. Defaults to the current timestamp.")
+                            .required(false),
+                    )
+                    .arg(
+                        clap::Arg::new("message").short('m').long("message").help("Message describing the checkpoint."),
+                    )
+                    .arg(
+                        clap::Arg::new("author")
+                            .long("author")
+                            .help("Author of the checkpoint. Defaults to $QOP_AUTHOR, then to the user name."),
+                    )
+                    .arg(
+                        clap::Arg::new("strict")
+                            .long("strict")
+                            .help("Fails instead of warning when a file keeps changing while it is copied.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("dry-run")
+                            .long("dry-run")
+                            .help("Only prints the files that would be stored, without writing the store or the index.")
+                            .action(ArgAction::SetTrue),
+                    ),
+            )
             .subcommand(
                 clap::Command::new("apply")
                     .about("Apply patch.")
-                    .arg(clap::Arg::new("file").short('f').long("file").required(true)),
+                    .arg(
+                        clap::Arg::new("paths")
+                            .help("Only applies the changes to these files, directories or glob patterns.")
+                            .num_args(1..),
+                    )
+                    .arg(
+                        clap::Arg::new("file")
+                            .short('f')
+                            .long("file")
+                            .help(
+                                "Patches to apply in order, `-` reads one from stdin. A directory applies all patches \
+                                 in it with the extension of the format, sorted by name.",
+                            )
+                            .num_args(1..)
+                            .action(ArgAction::Append)
+                            .required(true),
+                    )
+                    .arg(
+                        clap::Arg::new("format")
+                            .long("format")
+                            .help("Input format of the patch.")
+                            .value_parser(["toml", "json", "unified"])
+                            .default_value("toml"),
+                    )
+                    .arg(
+                        clap::Arg::new("line-endings")
+                            .long("line-endings")
+                            .help("Line endings of the patched files. `preserve` keeps the ones recorded in the patch.")
+                            .value_parser(["preserve", "lf", "crlf"])
+                            .default_value("preserve"),
+                    )
+                    .arg(
+                        clap::Arg::new("reverse")
+                            .short('r')
+                            .long("reverse")
+                            .help("Applies the patch in reverse, undoing its changes.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("interactive")
+                            .short('i')
+                            .long("interactive")
+                            .help(
+                                "Asks for every hunk whether to apply it and writes the others to `.rej` files. \
+                                 Without a terminal all hunks are applied.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("only-additions")
+                            .long("only-additions")
+                            .help(
+                                "Applies only the hunks that insert lines without deleting any, and added files. \
+                                 Other changes are skipped and listed.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("only-deletions")
+                            .long("only-deletions")
+                            .help(
+                                "Applies only the hunks that delete lines without inserting any, and deleted files. \
+                                 Other changes are skipped and listed.",
+                            )
+                            .conflicts_with("only-additions")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("fuzz")
+                            .long("fuzz")
+                            .help("Number of context lines per hunk that may differ from the file.")
+                            .value_parser(clap::value_parser!(usize))
+                            .default_value("0"),
+                    )
+                    .arg(
+                        clap::Arg::new("force")
+                            .long("force")
+                            .help("Applies the patch even if the files do not match its pre hash.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("merge")
+                            .long("merge")
+                            .help(
+                                "Three-way merges files that do not match the pre hash of the patch, using the stored \
+                                 content as base.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("dry-run")
+                            .long("dry-run")
+                            .help("Only prints what would be changed and whether the pre hashes match.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("backup")
+                            .long("backup")
+                            .help("Copies the files to the backups in the qop directory before changing them.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("reject")
+                            .long("reject")
+                            .help(
+                                "Applies the hunks that fit and writes the others to `<path>.rej`, also for files \
+                                 that do not match the pre hash.",
+                            )
+                            .conflicts_with("merge")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("atomic")
+                            .long("atomic")
+                            .help(
+                                "Applies all patches or none of them. The touched files are copied to the qop \
+                                 directory first and put back if a patch fails, has rejected hunks or merge conflicts.",
+                            )
+                            .conflicts_with_all(["reject", "dry-run"])
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("output-dir")
+                            .long("output-dir")
+                            .help(
+                                "Writes the patched files below this directory at their path in the patch and leaves \
+                                 the originals as they are.",
+                            )
+                            .conflicts_with_all(["dry-run", "backup"]),
+                    )
+                    .arg(
+                        clap::Arg::new("copy-unchanged")
+                            .long("copy-unchanged")
+                            .help("Copies the files the patches do not touch to the output directory as well.")
+                            .requires("output-dir")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("report")
+                            .long("report")
+                            .help(
+                                "Prints the hashes, changed lines and verification result of every file once the \
+                                 patches are applied.",
+                            )
+                            .value_parser(["json"])
+                            .conflicts_with("dry-run"),
+                    )
+                    .arg(
+                        clap::Arg::new("root")
+                            .long("root")
+                            .help("Directory the paths of the patch are relative to.")
+                            .default_value("."),
+                    ),
             )
             .subcommand(
                 clap::Command::new("diff")
                     .about("diff")
-                    .arg(clap::Arg::new("reverse").short('r').long("reverse").action(ArgAction::SetTrue)),
+                    .arg(
+                        clap::Arg::new("paths")
+                            .help("Limits the diff to these files, directories or glob patterns.")
+                            .num_args(1..),
+                    )
+                    .arg(
+                        clap::Arg::new("from")
+                            .long("from")
+                            .help("The checkpoint to diff the working copy against, by default the checked out one.")
+                            .default_value("head"),
+                    )
+                    .arg(
+                        clap::Arg::new("from-dir")
+                            .long("from-dir")
+                            .help("Diffs this directory against --to-dir instead of a checkpoint, without a store.")
+                            .requires("to-dir")
+                            .conflicts_with_all(["from", "index", "find-renames"]),
+                    )
+                    .arg(
+                        clap::Arg::new("to-dir")
+                            .long("to-dir")
+                            .help("The directory --from-dir is diffed against.")
+                            .requires("from-dir"),
+                    )
+                    .arg(clap::Arg::new("index").long("index").help(
+                        "Reads the checkpoints from this index file (`-` for stdin) instead of the one of the store, \
+                         e.g. to compare against a teammate's snapshot. The content it refers to still has to be in \
+                         the local store.",
+                    ))
+                    .arg(
+                        clap::Arg::new("find-renames")
+                            .short('M')
+                            .long("find-renames")
+                            .help(
+                                "Records a deleted and an added file as a rename if at least this percentage of their \
+                                 lines is equal (50 if no value is given).",
+                            )
+                            .num_args(0..=1)
+                            .require_equals(true)
+                            .default_missing_value("50")
+                            .value_parser(clap::value_parser!(u8).range(0..=100)),
+                    )
+                    .arg(clap::Arg::new("reverse").short('r').long("reverse").action(ArgAction::SetTrue))
+                    .arg(output_arg())
+                    .arg(
+                        clap::Arg::new("line-endings")
+                            .long("line-endings")
+                            .help(
+                                "Line endings recorded in the patch. `preserve` records the dominant ones of each \
+                                 file.",
+                            )
+                            .value_parser(["preserve", "lf", "crlf"])
+                            .default_value("preserve"),
+                    )
+                    .arg(
+                        clap::Arg::new("format")
+                            .long("format")
+                            .help(
+                                "Output format of the patch. `html` writes a standalone page with the changed words \
+                                 highlighted, for reading only.",
+                            )
+                            .value_parser(["toml", "json", "unified", "html"])
+                            .default_value("toml"),
+                    )
+                    .arg(
+                        clap::Arg::new("pretty")
+                            .long("pretty")
+                            .help(
+                                "Prints a human readable diff with a summary instead of a patch. Colors are only used \
+                                 in terminals.",
+                            )
+                            .conflicts_with("format")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("word-diff")
+                            .long("word-diff")
+                            .help(
+                                "Shows replaced lines once in the pretty diff, with removed words as [-word-] and \
+                                 added words as {+word+}.",
+                            )
+                            .requires("pretty")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("algorithm")
+                            .long("algorithm")
+                            .help(
+                                "Algorithm that finds the changed lines. `patience` often gives more readable hunks \
+                                 for code.",
+                            )
+                            .value_parser(["myers", "patience", "lcs"])
+                            .default_value("myers"),
+                    )
+                    .arg(
+                        clap::Arg::new("ignore-whitespace")
+                            .short('w')
+                            .long("ignore-whitespace")
+                            .help(
+                                "Compares lines without their whitespace. Changes of only whitespace are left out of \
+                                 the patch, like with `git diff -w`.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("ignore-trailing-whitespace")
+                            .long("ignore-trailing-whitespace")
+                            .help("Compares lines without the whitespace at their end.")
+                            .conflicts_with("ignore-whitespace")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("stat-only")
+                            .long("stat-only")
+                            .help("Prints only the number of inserted and deleted lines per file and in total.")
+                            .conflicts_with_all(["format", "pretty"])
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("json")
+                            .long("json")
+                            .help("Prints the statistics of --stat-only as JSON.")
+                            .requires("stat-only")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("interactive")
+                            .short('i')
+                            .long("interactive")
+                            .help(
+                                "Asks for every hunk whether to include it in the patch. Without a terminal all hunks \
+                                 are included.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("context")
+                            .short('U')
+                            .long("context")
+                            .help("Number of unchanged lines around each change that are part of its hunk.")
+                            .value_parser(clap::value_parser!(usize))
+                            .default_value("3"),
+                    )
+                    .arg(
+                        clap::Arg::new("exit-code")
+                            .long("exit-code")
+                            .help("Exits with 1 if there are differences and with 0 otherwise.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("strict")
+                            .long("strict")
+                            .help(
+                                "Fails on the first file that can not be read instead of leaving it out of the patch.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("include-metadata")
+                            .long("include-metadata")
+                            .help(
+                                "Includes files whose content and permissions are unchanged but whose modification \
+                                 time differs.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("no-fast-path")
+                            .long("no-fast-path")
+                            .help(
+                                "Hashes every file. By default, files with the size and modification time recorded by \
+                                 the checkpoint are taken as unchanged.",
+                            )
+                            .action(ArgAction::SetTrue),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("log").about("Lists the checkpoints.").alias("checkpoints").arg(
+                    clap::Arg::new("json")
+                        .long("json")
+                        .help("Prints the checkpoints as JSON.")
+                        .action(ArgAction::SetTrue),
+                ),
+            )
+            .subcommand(
+                clap::Command::new("prune")
+                    .about("Removes old checkpoints and their store data. The latest checkpoint is always kept.")
+                    .arg(
+                        clap::Arg::new("keep")
+                            .long("keep")
+                            .help("Keeps the given number of newest checkpoints.")
+                            .value_parser(clap::value_parser!(usize)),
+                    )
+                    .arg(clap::Arg::new("before").long("before").help(
+                        "Removes checkpoints older than this date (YYYY-MM-DD) or RFC 3339 instant. Together with \
+                         --keep, only checkpoints matched by both are removed.",
+                    ))
+                    .group(clap::ArgGroup::new("criteria").args(["keep", "before"]).multiple(true).required(true)),
+            )
+            .subcommand(
+                clap::Command::new("watch")
+                    .about("Creates a checkpoint whenever files that are not ignored change.")
+                    .arg(
+                        clap::Arg::new("interval")
+                            .long("interval")
+                            .help("Minimum number of seconds between two checkpoints.")
+                            .value_parser(clap::value_parser!(u64))
+                            .default_value("10"),
+                    ),
+            )
+            .subcommand(clap::Command::new("migrate").about(
+                "Converts a store written by an older qop version, which copies every checkpoint, to the shared \
+                 content store.",
+            ))
+            .subcommand(clap::Command::new("fsck").about(
+                "Checks that the stored content of all checkpoints is present and matches its hash. Exits with 1 if \
+                 problems are found.",
+            ))
+            .subcommand(clap::Command::new("doctor").about(
+                "Checks the qop directory for a missing or unreadable index or store and content of the latest \
+                 checkpoint that is not stored, and tells how to fix them. Exits with 1 if problems are found.",
+            ))
+            .subcommand(
+                clap::Command::new("status")
+                    .about("Lists the files that changed since the checked out checkpoint.")
+                    .arg(
+                        clap::Arg::new("short")
+                            .long("short")
+                            .help("Prints one `<status> <path>` line per file.")
+                            .action(ArgAction::SetTrue),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("ls-files")
+                    .about("Lists the files of the latest checkpoint.")
+                    .arg(
+                        clap::Arg::new("with-hash")
+                            .long("with-hash")
+                            .help("Prints the hash of the stored content after every path.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("json")
+                            .long("json")
+                            .help("Prints the files as JSON.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("paths")
+                            .help("Limits the list to these files, directories or glob patterns.")
+                            .num_args(1..),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("manifest")
+                    .about("Prints the hashes of the files of the latest checkpoint for checksum tools.")
+                    .arg(
+                        clap::Arg::new("format")
+                            .long("format")
+                            .help(
+                                "Format of the lines. `sha256sum` and `b3sum` are read by `sha256sum -c` and `b3sum \
+                                 -c` and need a store with their hash algorithm, `bsd` is the tagged format.",
+                            )
+                            .value_parser(["sha256sum", "b3sum", "bsd"])
+                            .default_value("sha256sum"),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("du").about("Prints the disk usage of the store, in total and per checkpoint.").arg(
+                    clap::Arg::new("bytes")
+                        .long("bytes")
+                        .help("Prints exact sizes in bytes.")
+                        .action(ArgAction::SetTrue),
+                ),
+            )
+            .subcommand(
+                clap::Command::new("hash")
+                    .about("Prints the hashes of files the way the index and patches record them.")
+                    .arg(clap::Arg::new("files").help("The files.").required(true).num_args(1..))
+                    .arg(
+                        clap::Arg::new("hash")
+                            .long("hash")
+                            .help("Hash algorithm. Defaults to the one of the store, or sha256 without a store.")
+                            .value_parser(["sha256", "blake3"]),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("restore")
+                    .about("Restores the working copy to a checkpoint.")
+                    .arg(
+                        clap::Arg::new("checkpoint")
+                            .short('c')
+                            .long("checkpoint")
+                            .help("The checkpoint to restore.")
+                            .default_value("latest"),
+                    )
+                    .arg(
+                        clap::Arg::new("dry-run")
+                            .long("dry-run")
+                            .help("Only prints what would be restored and removed.")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        clap::Arg::new("force")
+                            .long("force")
+                            .help("Restores files whose local changes are in no checkpoint and would be lost.")
+                            .action(ArgAction::SetTrue),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("checkout")
+                    .about("Restores the working copy to a checkpoint and diffs against it from then on.")
+                    .arg(clap::Arg::new("checkpoint").help("The checkpoint to check out.").required(true))
+                    .arg(
+                        clap::Arg::new("force")
+                            .long("force")
+                            .help("Restores files whose local changes are in no checkpoint and would be lost.")
+                            .action(ArgAction::SetTrue),
+                    ),
             )
             .subcommand(
                 clap::Command::new("reverse")
                     .about("Reverse a diff.")
-                    .arg(clap::Arg::new("file").short('f').long("file").required(true)),
+                    .arg(clap::Arg::new("file").short('f').long("file").required(true))
+                    .arg(
+                        clap::Arg::new("format")
+                            .long("format")
+                            .help("Format of the patch that is read and written.")
+                            .value_parser(["toml", "json"])
+                            .default_value("toml"),
+                    )
+                    .arg(output_arg()),
+            )
+            .subcommand(
+                clap::Command::new("blame")
+                    .about("Lists for every checkpoint whether a file changed since the checkpoint before.")
+                    .arg(clap::Arg::new("path").help("The file.").required(true))
+                    .arg(
+                        clap::Arg::new("json")
+                            .long("json")
+                            .help("Prints the timeline as JSON.")
+                            .action(ArgAction::SetTrue),
+                    ),
+            )
+            .subcommand(
+                clap::Command::new("validate")
+                    .about("Checks a patch for problems that would make apply fail or mangle files.")
+                    .arg(clap::Arg::new("file").short('f').long("file").required(true))
+                    .arg(
+                        clap::Arg::new("format")
+                            .long("format")
+                            .help("Format of the patch.")
+                            .value_parser(["toml", "json", "unified"])
+                            .default_value("toml"),
+                    )
+                    .arg(
+                        clap::Arg::new("fix")
+                            .long("fix")
+                            .help("Sorts the hunks of every file and writes the patch back to the file.")
+                            .action(ArgAction::SetTrue),
+                    ),
             )
     }
 
     pub fn load() -> Result<CallArgs> {
         let command = Self::root_command().get_matches();
+        let qop_dir = resolve_qop_dir(command.get_one::<String>("qop-dir").unwrap())?;
+        let config = Config::load(&qop_dir)?;
 
         let privileges = if command.get_flag("experimental") {
             Privilege::Experimental
@@ -132,21 +972,172 @@ impl ClapArgumentLoader {
                 path: subc.get_one::<String>("out").unwrap().into(),
                 shell: clap_complete::Shell::from_str(subc.get_one::<String>("shell").unwrap().as_str()).unwrap(),
             }
-        } else if let Some(_) = command.subcommand_matches("init") {
-            Command::Init
-        } else if let Some(_) = command.subcommand_matches("checkpoint") {
-            Command::Checkpoint
+        } else if let Some(subc) = command.subcommand_matches("init") {
+            Command::Init {
+                hash: HashAlgorithm::from_str(configured(subc, "hash", config.hash.as_ref()))?,
+                compression: Compression::from_str(configured(subc, "compression", config.compression.as_ref()))?,
+                manifest_only: subc.get_flag("manifest-only"),
+                dry_run: subc.get_flag("dry-run"),
+            }
+        } else if let Some(subc) = command.subcommand_matches("checkpoint") {
+            Command::Checkpoint {
+                name: subc.get_one::<String>("name").cloned(),
+                message: subc.get_one::<String>("message").cloned(),
+                author: subc
+                    .get_one::<String>("author")
+                    .cloned()
+                    .or_else(|| std::env::var("QOP_AUTHOR").ok())
+                    .or_else(|| std::env::var("USER").ok())
+                    .or_else(|| std::env::var("USERNAME").ok())
+                    .filter(|x| !x.is_empty()),
+                strict: subc.get_flag("strict"),
+                dry_run: subc.get_flag("dry-run"),
+            }
         } else if let Some(subc) = command.subcommand_matches("apply") {
             Command::Apply {
-                file: subc.get_one::<String>("file").unwrap().into(),
+                files: subc.get_many::<String>("file").unwrap().cloned().collect(),
+                format: PatchFormat::from_str(configured(subc, "format", config.format.as_ref()))?,
+                line_endings: LineEndings::from_str(subc.get_one::<String>("line-endings").unwrap())?,
+                reverse: subc.get_flag("reverse"),
+                interactive: subc.get_flag("interactive"),
+                changes: match (subc.get_flag("only-additions"), subc.get_flag("only-deletions")) {
+                    | (true, _) => Some(ChangeFilter::Additions),
+                    | (false, true) => Some(ChangeFilter::Deletions),
+                    | (false, false) => None,
+                },
+                fuzz: *subc.get_one::<usize>("fuzz").unwrap(),
+                force: subc.get_flag("force"),
+                merge: subc.get_flag("merge"),
+                dry_run: subc.get_flag("dry-run"),
+                backup: subc.get_flag("backup"),
+                reject: subc.get_flag("reject"),
+                atomic: subc.get_flag("atomic"),
+                output_dir: subc.get_one::<String>("output-dir").cloned(),
+                copy_unchanged: subc.get_flag("copy-unchanged"),
+                report: match subc.get_one::<String>("report").map(|x| x.as_str()) {
+                    | None => None,
+                    | Some("json") => Some(ReportFormat::Json),
+                    | Some(_) => return Err(anyhow::anyhow!("unknown report format")),
+                },
+                root: subc.get_one::<String>("root").unwrap().into(),
+                paths: subc.get_many::<String>("paths").map(|x| x.cloned().collect()).unwrap_or_default(),
+            }
+        } else if let Some(subc) = command.subcommand_matches("log") {
+            Command::Log {
+                json: subc.get_flag("json"),
+            }
+        } else if let Some(subc) = command.subcommand_matches("prune") {
+            Command::Prune {
+                keep: subc.get_one::<usize>("keep").copied(),
+                before: subc.get_one::<String>("before").map(|x| parse_instant(x)).transpose()?,
+            }
+        } else if let Some(subc) = command.subcommand_matches("watch") {
+            Command::Watch {
+                interval: *subc.get_one::<u64>("interval").unwrap(),
+            }
+        } else if command.subcommand_matches("migrate").is_some() {
+            Command::Migrate
+        } else if command.subcommand_matches("fsck").is_some() {
+            Command::Fsck
+        } else if command.subcommand_matches("doctor").is_some() {
+            Command::Doctor
+        } else if let Some(subc) = command.subcommand_matches("status") {
+            Command::Status {
+                short: subc.get_flag("short"),
+            }
+        } else if let Some(subc) = command.subcommand_matches("ls-files") {
+            Command::LsFiles {
+                with_hash: subc.get_flag("with-hash"),
+                json: subc.get_flag("json"),
+                paths: subc.get_many::<String>("paths").map(|x| x.cloned().collect()).unwrap_or_default(),
+            }
+        } else if let Some(subc) = command.subcommand_matches("manifest") {
+            Command::Manifest {
+                format: match subc.get_one::<String>("format").unwrap().as_str() {
+                    | "sha256sum" => ManifestFormat::Sha256sum,
+                    | "b3sum" => ManifestFormat::B3sum,
+                    | "bsd" => ManifestFormat::Bsd,
+                    | _ => return Err(anyhow::anyhow!("unknown manifest format")),
+                },
+            }
+        } else if let Some(subc) = command.subcommand_matches("du") {
+            Command::Du {
+                bytes: subc.get_flag("bytes"),
+            }
+        } else if let Some(subc) = command.subcommand_matches("hash") {
+            Command::Hash {
+                files: subc.get_many::<String>("files").unwrap().cloned().collect(),
+                algorithm: subc.get_one::<String>("hash").map(|x| HashAlgorithm::from_str(x)).transpose()?,
+            }
+        } else if let Some(subc) = command.subcommand_matches("restore") {
+            Command::Restore {
+                checkpoint: subc.get_one::<String>("checkpoint").unwrap().into(),
+                dry_run: subc.get_flag("dry-run"),
+                force: subc.get_flag("force"),
+            }
+        } else if let Some(subc) = command.subcommand_matches("checkout") {
+            Command::Checkout {
+                checkpoint: subc.get_one::<String>("checkpoint").unwrap().into(),
+                force: subc.get_flag("force"),
             }
         } else if let Some(subc) = command.subcommand_matches("reverse") {
             Command::Reverse {
                 file: subc.get_one::<String>("file").unwrap().into(),
+                format: PatchFormat::from_str(configured(subc, "format", config.format.as_ref()))?,
+                output: subc.get_one::<String>("output").unwrap().into(),
+            }
+        } else if let Some(subc) = command.subcommand_matches("blame") {
+            Command::Blame {
+                path: subc.get_one::<String>("path").unwrap().into(),
+                json: subc.get_flag("json"),
+            }
+        } else if let Some(subc) = command.subcommand_matches("validate") {
+            Command::Validate {
+                file: subc.get_one::<String>("file").unwrap().into(),
+                format: PatchFormat::from_str(configured(subc, "format", config.format.as_ref()))?,
+                fix: subc.get_flag("fix"),
             }
         } else if let Some(subc) = command.subcommand_matches("diff") {
             Command::Diff {
+                from: match (subc.get_one::<String>("from-dir"), subc.get_one::<String>("to-dir")) {
+                    | (Some(from), Some(to)) => DiffSource::Dirs(from.clone(), to.clone()),
+                    | _ => DiffSource::Checkpoint(subc.get_one::<String>("from").unwrap().clone()),
+                },
                 reverse: subc.get_flag("reverse"),
+                line_endings: LineEndings::from_str(subc.get_one::<String>("line-endings").unwrap())?,
+                format: match configured(subc, "format", config.format.as_ref()) {
+                    | "html" => DiffFormat::Html,
+                    | v => DiffFormat::Patch(PatchFormat::from_str(v)?),
+                },
+                pretty: subc.get_flag("pretty"),
+                word_diff: subc.get_flag("word-diff"),
+                stat: match (subc.get_flag("stat-only"), subc.get_flag("json")) {
+                    | (false, _) => None,
+                    | (true, json) => Some(json),
+                },
+                interactive: subc.get_flag("interactive"),
+                algorithm: DiffAlgorithm::from_str(subc.get_one::<String>("algorithm").unwrap())?,
+                whitespace: match (
+                    subc.get_flag("ignore-whitespace"),
+                    subc.get_flag("ignore-trailing-whitespace"),
+                ) {
+                    | (true, _) => Whitespace::All,
+                    | (false, true) => Whitespace::Trailing,
+                    | (false, false) => Whitespace::Exact,
+                },
+                context: match (subc.value_source("context"), config.context) {
+                    | (Some(ValueSource::DefaultValue), Some(v)) => v,
+                    | _ => *subc.get_one::<usize>("context").unwrap(),
+                },
+                quiet: subc.get_flag("quiet"),
+                exit_code: subc.get_flag("exit-code"),
+                index: subc.get_one::<String>("index").cloned(),
+                find_renames: subc.get_one::<u8>("find-renames").copied(),
+                paths: subc.get_many::<String>("paths").map(|x| x.cloned().collect()).unwrap_or_default(),
+                output: subc.get_one::<String>("output").unwrap().into(),
+                strict: subc.get_flag("strict"),
+                include_metadata: subc.get_flag("include-metadata"),
+                fast_path: !subc.get_flag("no-fast-path"),
             }
         } else {
             return Err(anyhow::anyhow!("unknown command"));
@@ -154,6 +1145,29 @@ impl ClapArgumentLoader {
 
         let callargs = CallArgs {
             privileges,
+            qop_dir,
+            hidden: !command.get_flag("no-hidden") && config.hidden.unwrap_or(true),
+            max_file_size: command
+                .get_one::<String>("max-file-size")
+                .or(config.max_file_size.as_ref())
+                .map(|x| parse_size(x))
+                .transpose()?,
+            qopfile_name: match command.get_one::<String>("qopfile-name").or(config.qopfile_name.as_ref()) {
+                | Some(v) if v.is_empty() || v == "." || v == ".." || v.contains(['/', '\\']) => {
+                    return Err(anyhow::anyhow!("invalid qopfile name, expected a file name: {}", v));
+                },
+                | Some(v) => v.clone(),
+                | None => ".qopfile".to_owned(),
+            },
+            log_level: if command.get_flag("quiet") {
+                log::LevelFilter::Warn
+            } else {
+                match command.get_count("verbose") {
+                    | 0 => log::LevelFilter::Info,
+                    | 1 => log::LevelFilter::Debug,
+                    | _ => log::LevelFilter::Trace,
+                }
+            },
             command: cmd,
         };
 
@@ -161,3 +1175,133 @@ impl ClapArgumentLoader {
         Ok(callargs)
     }
 }
+
+/// The file a command writes its result to. Writing to a file instead of
+/// redirecting stdout keeps the encoding intact on shells that re-encode it.
+fn output_arg() -> Arg {
+    Arg::new("output")
+        .short('o')
+        .long("output")
+        .help("Writes the result to this file instead of stdout (`-`).")
+        .default_value("-")
+}
+
+/// Turns the `--qop-dir` argument into an absolute path, so that the store is
+/// found the same way by every command. A leading `~` stands for the home
+/// directory, relative paths are taken from the current directory and symlinks
+/// are resolved as far as the path exists. The working copy, which is the
+/// current directory, must not be inside the qop directory, since it would
+/// record its own store.
+fn resolve_qop_dir(arg: &str) -> Result<PathBuf> {
+    let path = match arg.strip_prefix('~') {
+        | Some(rest) if rest.is_empty() || rest.starts_with(['/', std::path::MAIN_SEPARATOR]) => {
+            let home = std::env::var_os("HOME")
+                .filter(|x| !x.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("can not expand ~ in the qop directory {}, HOME is not set", arg))?;
+            PathBuf::from(home).join(rest.trim_start_matches(['/', std::path::MAIN_SEPARATOR]))
+        },
+        | _ => PathBuf::from(arg),
+    };
+    let cwd = std::env::current_dir().context("failed to read the current directory")?;
+    let mut resolved = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            | std::path::Component::CurDir => (),
+            | std::path::Component::ParentDir => {
+                resolved.pop();
+            },
+            | v => {
+                resolved.push(v);
+                // a symlink on the way must be followed before a later `..`
+                if let Ok(v) = resolved.canonicalize() {
+                    resolved = v;
+                }
+            },
+        }
+    }
+    let cwd = cwd.canonicalize().unwrap_or(cwd);
+    if cwd.starts_with(&resolved) {
+        return Err(anyhow::anyhow!(
+            "the working copy {} is inside the qop directory {}",
+            cwd.display(),
+            resolved.display()
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Parses an RFC 3339 instant or a date, which stands for its start in UTC.
+fn parse_instant(s: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    if let Ok(v) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(v.with_timezone(&chrono::Utc));
+    }
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| anyhow::anyhow!("invalid date, expected YYYY-MM-DD or RFC 3339: {}", s))?;
+    Ok(date.and_time(chrono::NaiveTime::MIN).and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `f` in a new temporary directory with `HOME` set to its `home`
+    /// directory. Both are shared by the tests, so only one of them runs at a
+    /// time.
+    fn in_temp_dir(f: impl FnOnce(&Path)) {
+        static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _guard = ENV.lock().unwrap_or_else(|x| x.into_inner());
+        let dir = std::env::temp_dir().join(format!("qop-args-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("home")).unwrap();
+        std::fs::create_dir_all(dir.join("work/sub")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let (previous_dir, previous_home) = (std::env::current_dir().unwrap(), std::env::var_os("HOME"));
+        std::env::set_current_dir(dir.join("work/sub")).unwrap();
+        std::env::set_var("HOME", dir.join("home"));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&dir)));
+        std::env::set_current_dir(previous_dir).unwrap();
+        match previous_home {
+            | Some(v) => std::env::set_var("HOME", v),
+            | None => std::env::remove_var("HOME"),
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+        result.unwrap_or_else(|x| std::panic::resume_unwind(x));
+    }
+
+    #[test]
+    fn resolves_qop_dirs() {
+        in_temp_dir(|dir| {
+            assert_eq!(resolve_qop_dir("~").unwrap(), dir.join("home"));
+            assert_eq!(resolve_qop_dir("~/store").unwrap(), dir.join("home/store"));
+            // only a leading `~/` is the home directory
+            assert_eq!(resolve_qop_dir("~store").unwrap(), dir.join("work/sub/~store"));
+            assert_eq!(resolve_qop_dir(".qop").unwrap(), dir.join("work/sub/.qop"));
+            assert_eq!(resolve_qop_dir("./a/../.qop").unwrap(), dir.join("work/sub/.qop"));
+            assert_eq!(resolve_qop_dir("../.qop").unwrap(), dir.join("work/.qop"));
+            assert_eq!(
+                resolve_qop_dir(&dir.join("store").to_string_lossy()).unwrap(),
+                dir.join("store")
+            );
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_before_parent_components() {
+        in_temp_dir(|dir| {
+            std::os::unix::fs::symlink(dir.join("home"), dir.join("work/sub/link")).unwrap();
+            assert_eq!(resolve_qop_dir("link/../.qop").unwrap(), dir.join(".qop"));
+        });
+    }
+
+    #[test]
+    fn rejects_qop_dirs_around_the_working_copy() {
+        in_temp_dir(|dir| {
+            for arg in [".", "..", "../..", "./"] {
+                let e = resolve_qop_dir(arg).unwrap_err();
+                assert!(e.to_string().contains("is inside the qop directory"), "{}: {}", arg, e);
+            }
+            assert!(resolve_qop_dir(&dir.join("work").to_string_lossy()).is_err());
+        });
+    }
+}
@@ -0,0 +1,1081 @@
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+};
+
+use anyhow::{
+    Context,
+    Result,
+};
+use base64::Engine;
+use rayon::prelude::*;
+
+use crate::{
+    fs::{
+        file_mode,
+        read_symlink,
+    },
+    hash::{
+        hash,
+        hash_file,
+        hash_symlink,
+        HashAlgorithm,
+    },
+    patch::{
+        LineEnding,
+        LineEndings,
+        Patch,
+        PatchFile,
+        PatchFileBinary,
+        PatchFileHunk,
+        PatchFileSymlink,
+        PATCH_VERSION,
+    },
+    store::{
+        file_stamp,
+        read_index,
+        read_stored,
+        resolve_checkpoint,
+        Index,
+        IndexFile,
+        QopDir,
+    },
+    walk::{
+        collect_files,
+        key_path,
+        path_key,
+        PathFilter,
+    },
+};
+
+/// Flags that control how a patch is built.
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /// Builds the patch from the working copy to the checkpoint instead.
+    pub reverse: bool,
+    pub line_endings: LineEndings,
+    /// Number of unchanged lines around the changes of a hunk.
+    pub context: usize,
+    /// Paths the patch is limited to.
+    pub paths: PathFilter,
+    /// Index to compare against instead of the one of the store. The content
+    /// it refers to has to be in the store.
+    pub index: Option<Index>,
+    /// Records a deleted and an added file as a rename if at least this
+    /// percentage of their lines is equal. Files with equal content are always
+    /// paired first.
+    pub find_renames: Option<u8>,
+    /// Fails on the first file that can not be read instead of leaving it out
+    /// of the patch.
+    pub strict: bool,
+    pub algorithm: DiffAlgorithm,
+    pub whitespace: Whitespace,
+    /// Also emits files whose content and permissions are unchanged but whose
+    /// modification time differs, as patch files without hunks. Permission
+    /// changes are always part of the patch.
+    pub include_metadata: bool,
+
+    /// Takes files that still have the size and modification time recorded
+    /// in the index as unchanged instead of hashing them.
+    pub fast_path: bool,
+}
+
+impl DiffOptions {
+    /// The flags of the options that apply to the lines of a single file.
+    pub fn text(&self) -> TextOptions {
+        TextOptions {
+            line_endings: self.line_endings,
+            context: self.context,
+            algorithm: self.algorithm,
+            whitespace: self.whitespace,
+        }
+    }
+}
+
+/// Flags that control how the lines of two text files are compared.
+#[derive(Debug, Clone, Copy)]
+pub struct TextOptions {
+    pub line_endings: LineEndings,
+    pub context: usize,
+    pub algorithm: DiffAlgorithm,
+    pub whitespace: Whitespace,
+}
+
+/// Whitespace that is ignored when lines are compared.
+///
+/// Lines that only differ in ignored whitespace are equal, so changes that
+/// touch nothing else are dropped from the patch like with `git diff -w`. If a
+/// hunk has such lines as context, they are written the way they are in the
+/// old file and keep that whitespace on apply. The patch then has no post hash
+/// since the applied file is not the new one.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Whitespace {
+    #[default]
+    Exact,
+    /// Ignores whitespace at the end of lines.
+    Trailing,
+    /// Ignores all whitespace, including added or removed whitespace within
+    /// lines.
+    All,
+}
+
+impl Whitespace {
+    /// The content of a line that is compared.
+    fn normalize<'a>(&self, line: &'a str) -> std::borrow::Cow<'a, str> {
+        let (content, newline) = match line.strip_suffix('\n') {
+            | Some(v) => (v, "\n"),
+            | None => (line, ""),
+        };
+        match self {
+            | Self::Exact => std::borrow::Cow::Borrowed(line),
+            | Self::Trailing => std::borrow::Cow::Owned(format!("{}{}", content.trim_end(), newline)),
+            | Self::All => {
+                std::borrow::Cow::Owned(content.chars().filter(|x| !x.is_whitespace()).chain(newline.chars()).collect())
+            },
+        }
+    }
+}
+
+/// Algorithm that finds the changed lines. They differ in speed and in how
+/// readable the hunks are, the patches they produce all apply the same way.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DiffAlgorithm {
+    #[default]
+    Myers,
+    /// Matches unique lines first, which often aligns code with many equal
+    /// lines like braces better.
+    Patience,
+    Lcs,
+}
+
+impl FromStr for DiffAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            | "myers" => Ok(Self::Myers),
+            | "patience" => Ok(Self::Patience),
+            | "lcs" => Ok(Self::Lcs),
+            | _ => Err(anyhow::anyhow!("unknown diff algorithm")),
+        }
+    }
+}
+
+impl From<DiffAlgorithm> for similar::Algorithm {
+    fn from(value: DiffAlgorithm) -> Self {
+        match value {
+            | DiffAlgorithm::Myers => Self::Myers,
+            | DiffAlgorithm::Patience => Self::Patience,
+            | DiffAlgorithm::Lcs => Self::Lcs,
+        }
+    }
+}
+
+/// A patch and the files that were left out of it.
+#[derive(Debug)]
+pub struct DiffReport {
+    pub patch: Patch,
+    /// The files that could not be read, sorted by their path.
+    pub failed: Vec<FailedFile>,
+}
+
+/// A file that was left out of a patch since it could not be read.
+#[derive(Debug)]
+pub struct FailedFile {
+    pub path: String,
+    pub error: anyhow::Error,
+}
+
+/// Splits the results of the single files into the successful ones and the
+/// failures. With `strict`, the first failure is returned instead.
+fn split_failures<T>(results: Vec<(String, Result<Option<T>>)>, strict: bool) -> Result<(Vec<T>, Vec<FailedFile>)> {
+    let (mut ok, mut failed) = (Vec::new(), Vec::new());
+    for (path, result) in results {
+        match result {
+            | Ok(v) => ok.extend(v),
+            | Err(e) if strict => return Err(e.context(format!("failed to diff {}", path))),
+            | Err(e) => failed.push(FailedFile { path, error: e }),
+        }
+    }
+    failed.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((ok, failed))
+}
+
+/// Builds the patch from a checkpoint to the working copy. Files that can not
+/// be read are left out unless `DiffOptions::strict` is set.
+pub fn compute_diff(qop_dir: &QopDir, from: &str, options: &DiffOptions) -> Result<DiffReport> {
+    let DiffOptions {
+        reverse,
+        line_endings: _,
+        context: _,
+        paths,
+        index,
+        find_renames: _,
+        strict,
+        algorithm: _,
+        whitespace: _,
+        include_metadata,
+        fast_path,
+    } = options;
+    let (reverse, text) = (*reverse, options.text());
+    // the stamps of an index from elsewhere describe other files
+    let fast_path = *fast_path && index.is_none();
+    let index = match index {
+        | Some(v) => v.clone(),
+        | None => read_index(qop_dir)?,
+    };
+    index.require_content("diff")?;
+    let files = resolve_checkpoint(&index, from)?;
+    if options.index.is_some() {
+        let missing = files
+            .iter()
+            .filter(|(path, file)| paths.matches(path) && !qop_dir.blob(&file.hash).exists())
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "the content of these files of the index is not in the store {}:\n{}",
+                qop_dir.path().display(),
+                missing.join("\n")
+            ));
+        }
+    }
+    enum Change {
+        Added(PatchFile),
+        /// The file is deleted, it has the hash before the patch.
+        Deleted(String),
+        Modified(PatchFile),
+    }
+    let untracked = collect_files(qop_dir, Path::new("."), &mut Vec::new())?
+        .into_iter()
+        .map(|x| path_key(&x))
+        .filter(|x| !x.as_ref().is_ok_and(|x| files.contains_key(x) || !paths.matches(x)))
+        .collect::<Result<Vec<_>>>()?;
+
+    // reading, hashing and diffing the files is CPU bound and runs on the
+    // thread pool, untracked files have no stored hash
+    let diff_one = |path: &String, file: Option<&IndexFile>| -> Result<Option<(String, Change)>> {
+        let wc_path = key_path(path);
+        let wc = DiffSide::Path(&wc_path);
+        let Some(file) = file else {
+            if reverse {
+                return Ok(Some((path.clone(), Change::Deleted(hash_file(index.hash, &wc_path)?))));
+            }
+            let patch_file = diff_paths(index.hash, DiffSide::Missing, wc, text)?;
+            return Ok(Some((path.clone(), Change::Added(patch_file))));
+        };
+        let stored = || {
+            read_stored(qop_dir, index.compression, &file.hash)
+                .with_context(|| format!("failed to read the stored content of {}", path))
+        };
+        if fast_path
+            && file.stamp.is_some()
+            && file_stamp(&wc_path).ok().flatten() == file.stamp
+            && file_mode(&wc_path)? == file.mode
+        {
+            return Ok(None);
+        }
+        let wc_hash = match hash_file(index.hash, &wc_path) {
+            | Ok(v) => v,
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !reverse {
+                    return Ok(Some((path.clone(), Change::Deleted(file.hash.clone()))));
+                }
+                let content = stored()?;
+                let patch_file = diff_paths(index.hash, DiffSide::Missing, DiffSide::Stored(&content, file), text)?;
+                return Ok(Some((path.clone(), Change::Added(patch_file))));
+            },
+            | Err(e) => return Err(e.into()),
+        };
+
+        let patch_file = if wc_hash == file.hash {
+            let stored = FileMetadata {
+                mode: file.mode,
+                mtime: file.stamp.map(|x| x.mtime).filter(|_| *include_metadata),
+            };
+            let wc = FileMetadata::read(&wc_path, *include_metadata)?;
+            let (pre, post) = if !reverse { (stored, wc) } else { (wc, stored) };
+            let Some(patch_file) = metadata_change(wc_hash, pre, post) else {
+                return Ok(None);
+            };
+            patch_file
+        } else {
+            let content = stored()?;
+            let stored = DiffSide::Stored(&content, file);
+            if !reverse {
+                diff_paths(index.hash, stored, wc, text)?
+            } else {
+                diff_paths(index.hash, wc, stored, text)?
+            }
+        };
+        if is_unchanged(&patch_file) {
+            return Ok(None);
+        }
+        Ok(Some((path.clone(), Change::Modified(patch_file))))
+    };
+    let results = untracked
+        .par_iter()
+        .map(|path| (path, None))
+        .chain(files.par_iter().filter(|(path, _)| paths.matches(path)).map(|(path, file)| (path, Some(file))))
+        .map(|(path, file)| (path.clone(), diff_one(path, file)))
+        .collect::<Vec<_>>();
+    let (changes, failed) = split_failures(results, *strict)?;
+
+    let mut patch = Patch {
+        version: PATCH_VERSION,
+        hash: index.hash,
+        files: BTreeMap::new(),
+        deleted: Vec::new(),
+        deleted_hashes: BTreeMap::new(),
+        added: Vec::new(),
+        renamed: BTreeMap::new(),
+    };
+    for (path, change) in changes {
+        match change {
+            | Change::Added(patch_file) => {
+                patch.files.insert(path.clone(), patch_file);
+                patch.added.push(path);
+            },
+            | Change::Deleted(hash) => {
+                patch.deleted_hashes.insert(path.clone(), hash);
+                patch.deleted.push(path);
+            },
+            | Change::Modified(patch_file) => {
+                patch.files.insert(path, patch_file);
+            },
+        }
+    }
+    // untracked files come in the order of the traversal
+    patch.added.sort();
+    patch.deleted.sort();
+
+    if let Some(threshold) = options.find_renames {
+        find_renames(qop_dir, &index, files, &mut patch, options, threshold)?;
+    }
+    Ok(DiffReport { patch, failed })
+}
+
+/// Turns pairs of a deleted and an added file of the patch into renames.
+/// Files with equal content are paired first, then the text files with the
+/// highest share of equal lines, as long as it is at least `threshold` percent.
+fn find_renames(
+    qop_dir: &QopDir,
+    index: &Index,
+    files: &BTreeMap<String, IndexFile>,
+    patch: &mut Patch,
+    options: &DiffOptions,
+    threshold: u8,
+) -> Result<()> {
+    struct Candidate<'a> {
+        path: String,
+        /// Where the file is in the working copy.
+        wc_path: PathBuf,
+        hash: String,
+        content: Vec<u8>,
+        /// The file of the checkpoint, `None` for the working copy.
+        file: Option<&'a IndexFile>,
+        symlink: bool,
+    }
+    // deleted files are on the stored side unless the patch is built in reverse
+    let load = |path: &String, stored: bool| -> Result<Candidate> {
+        if stored {
+            let file = &files[path];
+            return Ok(Candidate {
+                path: path.clone(),
+                wc_path: PathBuf::new(),
+                hash: file.hash.clone(),
+                content: read_stored(qop_dir, index.compression, &file.hash)
+                    .with_context(|| format!("failed to read the stored content of {}", path))?,
+                file: Some(file),
+                symlink: file.symlink,
+            });
+        }
+        let wc_path = key_path(path);
+        let symlink = read_symlink(&wc_path)?.is_some();
+        Ok(Candidate {
+            path: path.clone(),
+            hash: hash_file(index.hash, &wc_path)?,
+            content: if symlink { Vec::new() } else { std::fs::read(&wc_path)? },
+            wc_path,
+            file: None,
+            symlink,
+        })
+    };
+    // empty files all look the same, pairing them would be a guess
+    let old = patch
+        .deleted
+        .iter()
+        .map(|x| load(x, !options.reverse))
+        .filter(|x| !x.as_ref().is_ok_and(|x| !x.symlink && x.content.is_empty()))
+        .collect::<Result<Vec<_>>>()?;
+    let new = patch
+        .added
+        .iter()
+        .map(|x| load(x, options.reverse))
+        .filter(|x| !x.as_ref().is_ok_and(|x| !x.symlink && x.content.is_empty()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let (mut old_used, mut new_used) = (vec![false; old.len()], vec![false; new.len()]);
+    let mut pairs = Vec::new();
+    for (n, new_file) in new.iter().enumerate() {
+        if let Some(o) = (0..old.len()).find(|o| !old_used[*o] && old[*o].hash == new_file.hash) {
+            old_used[o] = true;
+            new_used[n] = true;
+            pairs.push((o, n));
+        }
+    }
+    let text = |x: &Candidate| !x.symlink && !is_binary(&x.content);
+    let mut scores = Vec::new();
+    for (o, old_file) in old.iter().enumerate().filter(|x| !old_used[x.0] && text(x.1)) {
+        for (n, new_file) in new.iter().enumerate().filter(|x| !new_used[x.0] && text(x.1)) {
+            // both sides were checked by `is_binary`, so they are valid utf-8
+            let diff = similar::TextDiff::from_lines(
+                std::str::from_utf8(&old_file.content)?,
+                std::str::from_utf8(&new_file.content)?,
+            );
+            let score = diff.ratio() * 100.0;
+            if score >= f32::from(threshold) {
+                scores.push((score, o, n));
+            }
+        }
+    }
+    // the most similar pairs win, ties keep the order of the paths
+    scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (_, o, n) in scores {
+        if !old_used[o] && !new_used[n] {
+            old_used[o] = true;
+            new_used[n] = true;
+            pairs.push((o, n));
+        }
+    }
+
+    fn side<'a>(x: &'a Candidate) -> DiffSide<'a> {
+        match x.file {
+            | Some(file) => DiffSide::Stored(&x.content, file),
+            | None => DiffSide::Path(&x.wc_path),
+        }
+    }
+    for (o, n) in pairs {
+        let (old_file, new_file) = (&old[o], &new[n]);
+        log::debug!("rename {} to {}", old_file.path, new_file.path);
+        let patch_file = diff_paths(index.hash, side(old_file), side(new_file), options.text())?;
+        patch.files.insert(new_file.path.clone(), patch_file);
+        patch.renamed.insert(new_file.path.clone(), old_file.path.clone());
+    }
+    let renamed = &patch.renamed;
+    patch.added.retain(|x| !renamed.contains_key(x));
+    patch.deleted.retain(|x| !renamed.values().any(|y| y == x));
+    patch.deleted_hashes.retain(|x, _| !renamed.values().any(|y| y == x));
+    Ok(())
+}
+
+/// Builds the patch from the directory `from` to the directory `to` without a
+/// store. Files are paired by their path relative to the directories, which
+/// the paths of the patch are relative to as well. The directories are
+/// traversed like the working copy, so their ignore rules apply. Files that
+/// can not be read are left out unless `DiffOptions::strict` is set.
+pub fn diff_dirs(qop_dir: &QopDir, from: &Path, to: &Path, options: &DiffOptions) -> Result<DiffReport> {
+    let (from, to) = if options.reverse { (to, from) } else { (from, to) };
+    let algorithm = HashAlgorithm::default();
+    let relative_files = |dir: &Path| -> Result<BTreeSet<String>> {
+        collect_files(qop_dir, dir, &mut Vec::new())?
+            .into_iter()
+            .map(|x| path_key(x.strip_prefix(dir)?))
+            .filter(|x| !x.as_ref().is_ok_and(|x| !options.paths.matches(x)))
+            .collect()
+    };
+    let (pre, post) = (relative_files(from)?, relative_files(to)?);
+
+    let deleted = pre.difference(&post).cloned().collect::<Vec<_>>();
+    // a deleted file that can not be read is deleted without checking it
+    let deleted_hashes = deleted
+        .par_iter()
+        .filter_map(|x| hash_file(algorithm, from.join(x)).ok().map(|hash| (x.clone(), hash)))
+        .collect();
+    let mut patch = Patch {
+        version: PATCH_VERSION,
+        hash: algorithm,
+        files: BTreeMap::new(),
+        deleted,
+        deleted_hashes,
+        added: post.difference(&pre).cloned().collect(),
+        renamed: BTreeMap::new(),
+    };
+    let diff_one = |path: &String| -> Result<Option<(String, PatchFile)>> {
+        let (pre_path, post_path) = (from.join(path), to.join(path));
+        if !pre.contains(path) {
+            let patch_file = diff_paths(algorithm, DiffSide::Missing, DiffSide::Path(&post_path), options.text())?;
+            return Ok(Some((path.clone(), patch_file)));
+        }
+        let pre_hash = hash_file(algorithm, &pre_path)?;
+        if pre_hash == hash_file(algorithm, &post_path)? {
+            let patch_file = metadata_change(
+                pre_hash,
+                FileMetadata::read(&pre_path, options.include_metadata)?,
+                FileMetadata::read(&post_path, options.include_metadata)?,
+            );
+            return Ok(patch_file.map(|x| (path.clone(), x)));
+        }
+        let patch_file = diff_paths(
+            algorithm,
+            DiffSide::Path(&pre_path),
+            DiffSide::Path(&post_path),
+            options.text(),
+        )?;
+        if is_unchanged(&patch_file) {
+            return Ok(None);
+        }
+        Ok(Some((path.clone(), patch_file)))
+    };
+    let results = post.par_iter().map(|path| (path.clone(), diff_one(path))).collect::<Vec<_>>();
+    let (files, failed) = split_failures(results, options.strict)?;
+    patch.files.extend(files);
+    Ok(DiffReport { patch, failed })
+}
+
+/// Builds full deletion hunks for the files in `Patch::deleted` of a patch
+/// returned by `diff_dirs`.
+pub fn dir_deletion_hunks(
+    from: &Path,
+    to: &Path,
+    patch: &Patch,
+    options: &DiffOptions,
+) -> Result<BTreeMap<String, PatchFile>> {
+    let from = if options.reverse { to } else { from };
+    patch
+        .deleted
+        .iter()
+        .map(|path| {
+            let patch_file = diff_paths(
+                patch.hash,
+                DiffSide::Path(&from.join(path)),
+                DiffSide::Missing,
+                options.text(),
+            )?;
+            Ok((path.clone(), patch_file))
+        })
+        .collect()
+}
+
+/// Builds full deletion hunks for the files in `Patch::deleted` of a patch
+/// returned by `compute_diff`. The patch only records their paths, the unified
+/// format needs their content.
+pub fn deletion_hunks(
+    qop_dir: &QopDir,
+    from: &str,
+    patch: &Patch,
+    options: &DiffOptions,
+) -> Result<BTreeMap<String, PatchFile>> {
+    let index = match &options.index {
+        | Some(v) => v.clone(),
+        | None => read_index(qop_dir)?,
+    };
+    let files = resolve_checkpoint(&index, from)?;
+    let mut deleted = BTreeMap::new();
+    for path in &patch.deleted {
+        let (content, wc_path);
+        let pre = match files.get(path).filter(|_| !options.reverse) {
+            | Some(file) => {
+                content = read_stored(qop_dir, index.compression, &file.hash)
+                    .with_context(|| format!("failed to read the stored content of {}", path))?;
+                DiffSide::Stored(&content, file)
+            },
+            | None => {
+                wc_path = key_path(path);
+                DiffSide::Path(&wc_path)
+            },
+        };
+        deleted.insert(
+            path.clone(),
+            diff_paths(index.hash, pre, DiffSide::Missing, options.text())?,
+        );
+    }
+    Ok(deleted)
+}
+
+/// One side of `diff_paths`.
+#[derive(Debug, Clone, Copy)]
+pub enum DiffSide<'a> {
+    /// A file that does not exist.
+    Missing,
+    /// A file in the working copy.
+    Path(&'a Path),
+    /// A file of a checkpoint and its stored content.
+    Stored(&'a [u8], &'a IndexFile),
+}
+
+/// Builds the patch that turns the file at `pre` into the one at `post`.
+/// Symlinks are recorded by their target instead of being followed, a regular
+/// file on the other side is then stored as binary content.
+pub fn diff_paths(algorithm: HashAlgorithm, pre: DiffSide, post: DiffSide, text: TextOptions) -> Result<PatchFile> {
+    #[derive(Default)]
+    struct Side {
+        content: Vec<u8>,
+        link: Option<String>,
+        hash: String,
+        mode: Option<u32>,
+    }
+    let read = |side: DiffSide| -> Result<Side> {
+        let path = match side {
+            | DiffSide::Missing => {
+                return Ok(Side {
+                    hash: hash(algorithm, []),
+                    ..Default::default()
+                });
+            },
+            | DiffSide::Path(path) => path,
+            // the stored content of a symlink is its target
+            | DiffSide::Stored(content, file) if file.symlink => {
+                return Ok(Side {
+                    hash: file.hash.clone(),
+                    link: Some(String::from_utf8(content.to_vec())?),
+                    ..Default::default()
+                });
+            },
+            | DiffSide::Stored(content, file) => {
+                return Ok(Side {
+                    hash: file.hash.clone(),
+                    content: content.to_vec(),
+                    link: None,
+                    mode: file.mode,
+                });
+            },
+        };
+        if let Some(target) = read_symlink(path)? {
+            return Ok(Side {
+                hash: hash_symlink(algorithm, &target),
+                link: Some(target),
+                ..Default::default()
+            });
+        }
+        let content = std::fs::read(path)?;
+        Ok(Side {
+            hash: hash(algorithm, &content),
+            content,
+            link: None,
+            mode: file_mode(path)?,
+        })
+    };
+    let (pre_side, post_side) = (read(pre)?, read(post)?);
+
+    let mut patch_file = if pre_side.link.is_none() && post_side.link.is_none() {
+        diff_file(
+            &pre_side.content,
+            pre_side.hash,
+            &post_side.content,
+            post_side.hash,
+            text,
+        )?
+    } else {
+        let exists = |side| !matches!(side, DiffSide::Missing);
+        let regular = (exists(pre) && pre_side.link.is_none()) || (exists(post) && post_side.link.is_none());
+        PatchFile {
+            pre_hash: pre_side.hash,
+            post_hash: post_side.hash,
+            hunks: Vec::new(),
+            line_ending: None,
+            binary: regular.then(|| {
+                PatchFileBinary {
+                    pre: base64::engine::general_purpose::STANDARD.encode(&pre_side.content),
+                    post: base64::engine::general_purpose::STANDARD.encode(&post_side.content),
+                }
+            }),
+            symlink: Some(PatchFileSymlink {
+                pre: pre_side.link,
+                post: post_side.link,
+            }),
+            pre_mode: None,
+            post_mode: None,
+            pre_mtime: None,
+            post_mtime: None,
+            comment: None,
+        }
+    };
+    patch_file.pre_mode = pre_side.mode;
+    patch_file.post_mode = post_side.mode;
+    Ok(patch_file)
+}
+
+/// Builds the patch that turns `pre` into `post`.
+///
+/// Text content is compared with normalized line endings so that only real
+/// changes show up. The line ending to re-emit on apply is recorded in the
+/// patch. Hunks include up to `TextOptions::context` unchanged lines around
+/// their changes, lines that only differ in ignored whitespace are equal.
+pub fn diff_file(pre: &[u8], pre_hash: String, post: &[u8], post_hash: String, text: TextOptions) -> Result<PatchFile> {
+    if is_binary(pre) || is_binary(post) {
+        return Ok(PatchFile {
+            pre_hash,
+            post_hash,
+            hunks: Vec::new(),
+            line_ending: None,
+            binary: Some(PatchFileBinary {
+                pre: base64::engine::general_purpose::STANDARD.encode(pre),
+                post: base64::engine::general_purpose::STANDARD.encode(post),
+            }),
+            symlink: None,
+            pre_mode: None,
+            post_mode: None,
+            pre_mtime: None,
+            post_mtime: None,
+            comment: None,
+        });
+    }
+    // both sides were checked by `is_binary`, so they are valid utf-8
+    let pre = std::str::from_utf8(pre)?;
+    let post = std::str::from_utf8(post)?;
+
+    let line_ending = match text.line_endings {
+        | LineEndings::Preserve => LineEnding::detect(post),
+        | LineEndings::Lf => Some(LineEnding::Lf),
+        | LineEndings::Crlf => Some(LineEnding::Crlf),
+    };
+    let pre = pre.replace("\r\n", "\n");
+    let post = post.replace("\r\n", "\n");
+
+    // the lines are compared by their normalized content, the hunks are
+    // written from the actual lines
+    let (pre_lines, post_lines) = (
+        pre.split_inclusive('\n').collect::<Vec<_>>(),
+        post.split_inclusive('\n').collect::<Vec<_>>(),
+    );
+    let pre_keys = pre_lines.iter().map(|x| text.whitespace.normalize(x)).collect::<Vec<_>>();
+    let post_keys = post_lines.iter().map(|x| text.whitespace.normalize(x)).collect::<Vec<_>>();
+    let (pre_keys, post_keys) = (
+        pre_keys.iter().map(|x| x.as_ref()).collect::<Vec<_>>(),
+        post_keys.iter().map(|x| x.as_ref()).collect::<Vec<_>>(),
+    );
+    let diff = similar::TextDiff::configure().algorithm(text.algorithm.into()).diff_slices(&pre_keys, &post_keys);
+    // equal lines that differ in ignored whitespace are not changed on apply
+    let dropped = diff.ops().iter().any(|op| {
+        let (tag, old, new) = op.as_tag_tuple();
+        tag == similar::DiffTag::Equal && pre_lines[old] != post_lines[new]
+    });
+
+    let mut diff_hunks = Vec::<PatchFileHunk>::new();
+    for ops in diff.grouped_ops(text.context) {
+        let first_op = ops[0];
+        let last_op = ops[ops.len() - 1];
+
+        let mut diff = Vec::<String>::new();
+        let (mut old_no_newline, mut new_no_newline) = (false, false);
+        for op in &ops {
+            let (tag, old, new) = op.as_tag_tuple();
+            let changes = match tag {
+                | similar::DiffTag::Equal => pre_lines[old].iter().map(|x| (' ', *x)).collect::<Vec<_>>(),
+                | _ => {
+                    pre_lines[old].iter().map(|x| ('-', *x)).chain(post_lines[new].iter().map(|x| ('+', *x))).collect()
+                },
+            };
+            for (prefix, line) in changes {
+                // only the last line of a side can lack the newline, hunk
+                // lines always end with one and the missing newline is
+                // marked instead
+                let value = match line.strip_suffix('\n') {
+                    | Some(v) => v,
+                    | None => {
+                        if prefix != '+' {
+                            old_no_newline = true;
+                        }
+                        if prefix != '-' {
+                            new_no_newline = true;
+                        }
+                        line
+                    },
+                };
+                diff.push(format!("{}{}\n", prefix, value));
+            }
+        }
+
+        diff_hunks.push(PatchFileHunk {
+            old_range: (first_op.old_range().start, last_op.old_range().end),
+            new_range: (first_op.new_range().start, last_op.new_range().end),
+            diff: diff.concat(),
+            old_no_newline,
+            new_no_newline,
+            comment: None,
+        });
+    }
+    // patches are written in the order `apply` expects
+    diff_hunks.sort_by_key(|x| x.old_range.0);
+
+    let post_hash = match (dropped, diff_hunks.is_empty()) {
+        | (false, _) => post_hash,
+        // the content is unchanged apart from ignored whitespace
+        | (true, true) => pre_hash.clone(),
+        | (true, false) => String::new(),
+    };
+    Ok(PatchFile {
+        pre_hash,
+        post_hash,
+        hunks: diff_hunks,
+        line_ending,
+        binary: None,
+        symlink: None,
+        pre_mode: None,
+        post_mode: None,
+        pre_mtime: None,
+        post_mtime: None,
+        comment: None,
+    })
+}
+
+/// Permissions and modification time of one side of a file.
+struct FileMetadata {
+    mode: Option<u32>,
+    mtime: Option<i64>,
+}
+
+impl FileMetadata {
+    /// Reads the permissions of a file, and its modification time if `mtime`
+    /// is set.
+    fn read(path: &Path, mtime: bool) -> std::io::Result<Self> {
+        Ok(Self {
+            mode: file_mode(path)?,
+            mtime: if mtime {
+                file_stamp(path)?.map(|x| x.mtime)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Builds the patch file for a file whose content is unchanged if its
+/// permissions or modification time differ.
+fn metadata_change(hash: String, pre: FileMetadata, post: FileMetadata) -> Option<PatchFile> {
+    // checkpoints do not record the modification time of files written right
+    // before them
+    let mtime_changed = pre.mtime.is_some() && post.mtime.is_some() && pre.mtime != post.mtime;
+    if pre.mode == post.mode && !mtime_changed {
+        return None;
+    }
+    Some(PatchFile {
+        pre_hash: hash.clone(),
+        post_hash: hash,
+        hunks: Vec::new(),
+        line_ending: None,
+        binary: None,
+        symlink: None,
+        pre_mode: pre.mode,
+        post_mode: post.mode,
+        pre_mtime: pre.mtime.filter(|_| mtime_changed),
+        post_mtime: post.mtime.filter(|_| mtime_changed),
+        comment: None,
+    })
+}
+
+/// Whether a file of the patch only differs in whitespace that was ignored.
+fn is_unchanged(patch_file: &PatchFile) -> bool {
+    patch_file.pre_hash == patch_file.post_hash
+        && patch_file.pre_mode == patch_file.post_mode
+        && patch_file.pre_mtime == patch_file.post_mtime
+}
+
+/// Treats content as binary if it contains a NUL byte or is not valid UTF-8.
+fn is_binary(content: &[u8]) -> bool {
+    content.contains(&0) || std::str::from_utf8(content).is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apply::apply_patch,
+        patch::{
+            parse_patch,
+            write_patch,
+            PatchFormat,
+        },
+        store::checkpoint,
+        testing::{
+            apply_options,
+            diff_options,
+            in_dir,
+            TempDir,
+        },
+    };
+
+    #[test]
+    fn binary_files_round_trip_through_diff_and_apply() {
+        let (pre, post) = (b"\x89PNG\r\n\x00\x01\x02\n", b"\x89PNG\r\n\x00\xff\xfe\x03");
+        let dir = TempDir::new();
+        dir.write("image.bin", pre);
+        in_dir(dir.path(), || {
+            let qop_dir = QopDir::new(dir.path().join(".qop"));
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            dir.write("image.bin", post);
+            let patch = compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch;
+            assert!(patch.files["./image.bin"].binary.is_some());
+            assert!(patch.files["./image.bin"].hunks.is_empty());
+
+            // the same way as through a patch file
+            let patch = parse_patch(&write_patch(&patch, PatchFormat::Toml).unwrap(), PatchFormat::Toml).unwrap();
+            dir.write("image.bin", pre);
+            let report = apply_patch(&qop_dir, patch, apply_options(dir.path())).unwrap();
+            assert!(report.rejects.is_empty());
+            assert!(report.post_hash_mismatches.is_empty());
+        });
+        assert_eq!(dir.read("image.bin"), post);
+    }
+
+    #[test]
+    fn hunks_have_the_requested_context() {
+        let pre = (1..=20).map(|x| format!("{}\n", x)).collect::<String>();
+        let post = pre.replace("10\n", "ten\n");
+        let diff = |context| {
+            let options = TextOptions {
+                context,
+                ..diff_options().text()
+            };
+            diff_file(pre.as_bytes(), String::new(), post.as_bytes(), String::new(), options).unwrap().hunks
+        };
+
+        let hunks = diff(3);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].old_range, (6, 13));
+        assert_eq!(hunks[0].diff, " 7\n 8\n 9\n-10\n+ten\n 11\n 12\n 13\n");
+        assert_eq!(diff(0)[0].diff, "-10\n+ten\n");
+        // the context stops at the start and the end of the file
+        assert_eq!(diff(30)[0].diff.lines().count(), 21);
+    }
+
+    #[test]
+    fn diffs_against_a_store_in_another_directory() {
+        let (dir, stores) = (TempDir::new(), TempDir::new());
+        dir.write("file.txt", "old\n");
+        let qop_dir = QopDir::new(stores.path().join("project"));
+        let patch = in_dir(dir.path(), || {
+            checkpoint(&qop_dir, None, Some(Index::default()), None, None, true, &()).unwrap();
+            dir.write("file.txt", "new\n");
+            compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch
+        });
+        assert!(qop_dir.index().exists());
+        assert!(!dir.path().join(".qop").exists());
+        assert_eq!(patch.files.keys().collect::<Vec<_>>(), ["./file.txt"]);
+        assert_eq!(patch.files["./file.txt"].hunks[0].diff, "-old\n+new\n");
+    }
+
+    #[test]
+    fn patches_are_written_sorted_and_stable() {
+        let dir = TempDir::new();
+        for path in ["b.txt", "a/z.txt", "a/b.txt", "a.txt", "deleted.txt"] {
+            dir.write(path, "old\n");
+        }
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        in_dir(dir.path(), || {
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            for path in ["b.txt", "a/z.txt", "a/b.txt", "a.txt", "c/new.txt"] {
+                dir.write(path, "new\n");
+            }
+            std::fs::remove_file("deleted.txt").unwrap();
+        });
+        let write = |format| {
+            let patch = in_dir(dir.path(), || {
+                compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch
+            });
+            write_patch(&patch, format).unwrap()
+        };
+
+        let key = |format, path| {
+            match format {
+                | PatchFormat::Toml => format!("[files.\"{}\"]", path),
+                | _ => format!("\"{}\": {{", path),
+            }
+        };
+        for format in [PatchFormat::Toml, PatchFormat::Json] {
+            let first = write(format);
+            assert_eq!(first, write(format));
+            let positions = ["./a.txt", "./a/b.txt", "./a/z.txt", "./b.txt", "./c/new.txt"]
+                .map(|x| first.find(&key(format, x)).unwrap());
+            assert!(positions.is_sorted(), "{:?}", positions);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn includes_modification_times_only_if_asked() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (from, to) = (TempDir::new(), TempDir::new());
+        let day = |n: u64| std::time::UNIX_EPOCH + std::time::Duration::from_secs(86_400 * n);
+        let touch = |path: &Path, mode, mtime| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+            std::fs::File::open(path).unwrap().set_modified(mtime).unwrap();
+        };
+        for dir in [&from, &to] {
+            dir.write("mode.sh", "echo\n");
+            dir.write("touched.txt", "same\n");
+            dir.write("unchanged.txt", "same\n");
+            touch(&dir.path().join("unchanged.txt"), 0o644, day(1));
+        }
+        touch(&from.path().join("mode.sh"), 0o644, day(1));
+        touch(&to.path().join("mode.sh"), 0o755, day(1));
+        touch(&from.path().join("touched.txt"), 0o644, day(1));
+        touch(&to.path().join("touched.txt"), 0o644, day(2));
+        let qop_dir = QopDir::new(from.path().join(".qop"));
+
+        // the permissions are always compared, the modification time only if
+        // asked
+        let patch = diff_dirs(&qop_dir, from.path(), to.path(), &diff_options()).unwrap().patch;
+        assert_eq!(patch.files.keys().collect::<Vec<_>>(), ["./mode.sh"]);
+
+        let options = DiffOptions {
+            include_metadata: true,
+            ..diff_options()
+        };
+        let patch = diff_dirs(&qop_dir, from.path(), to.path(), &options).unwrap().patch;
+        assert_eq!(patch.files.keys().collect::<Vec<_>>(), ["./mode.sh", "./touched.txt"]);
+        let (mode, touched) = (&patch.files["./mode.sh"], &patch.files["./touched.txt"]);
+        assert!(mode.hunks.is_empty() && touched.hunks.is_empty());
+        assert_eq!((mode.pre_mode, mode.post_mode), (Some(0o644), Some(0o755)));
+        assert_eq!((mode.pre_mtime, mode.post_mtime), (None, None));
+        assert_eq!(touched.post_mtime, Some(86_400 * 2 * 1_000_000_000));
+
+        let patch = parse_patch(&write_patch(&patch, PatchFormat::Toml).unwrap(), PatchFormat::Toml).unwrap();
+        apply_patch(&qop_dir, patch, apply_options(from.path())).unwrap();
+        let metadata = |path: &str| std::fs::metadata(from.path().join(path)).unwrap();
+        assert_eq!(metadata("mode.sh").permissions().mode() & 0o7777, 0o755);
+        assert_eq!(metadata("touched.txt").modified().unwrap(), day(2));
+    }
+
+    #[test]
+    fn includes_modification_times_changed_since_the_checkpoint() {
+        let dir = TempDir::new();
+        let file = dir.write("file.txt", "same\n");
+        let day = |n: u64| std::time::UNIX_EPOCH + std::time::Duration::from_secs(86_400 * n);
+        std::fs::File::open(&file).unwrap().set_modified(day(1)).unwrap();
+        let options = DiffOptions {
+            include_metadata: true,
+            ..diff_options()
+        };
+        in_dir(dir.path(), || {
+            let qop_dir = QopDir::new(dir.path().join(".qop"));
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            std::fs::File::open(&file).unwrap().set_modified(day(2)).unwrap();
+            assert!(compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch.files.is_empty());
+
+            let patch = compute_diff(&qop_dir, "latest", &options).unwrap().patch;
+            let patch_file = &patch.files["./file.txt"];
+            assert_eq!(
+                (patch_file.pre_mtime, patch_file.post_mtime),
+                (Some(86_400 * 1_000_000_000), Some(86_400 * 2 * 1_000_000_000))
+            );
+            // the reverse patch sets the time of the checkpoint again
+            let reverse = DiffOptions {
+                reverse: true,
+                ..options
+            };
+            let patch = compute_diff(&qop_dir, "latest", &reverse).unwrap().patch;
+            apply_patch(&qop_dir, patch, apply_options(dir.path())).unwrap();
+        });
+        assert_eq!(std::fs::metadata(&file).unwrap().modified().unwrap(), day(1));
+    }
+}
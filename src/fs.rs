@@ -0,0 +1,189 @@
+use std::{
+    io::Write,
+    path::Path,
+};
+
+use anyhow::Result;
+
+/// Writes to a temporary file next to `path` and renames it over `path`, so
+/// the file is either fully written or left untouched. Missing parent
+/// directories are created.
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    write_atomic_with(path, |out| Ok(out.write_all(content)?))
+}
+
+/// Like `write_atomic`, but the content is streamed into the buffered writer
+/// passed to `write`.
+pub(crate) fn write_atomic_with(path: &Path, write: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+    let file_name = path.file_name().ok_or_else(|| anyhow::anyhow!("invalid file path: {}", path.display()))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.qop-tmp-{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let result = (|| -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        write(&mut out)?;
+        let file = out.into_inner().map_err(|x| x.into_error())?;
+        file.sync_all()?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            std::fs::set_permissions(&tmp_path, metadata.permissions())?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Returns the Unix permission bits of a file. Always `None` for symlinks and
+/// on other platforms.
+pub(crate) fn file_mode(path: &Path) -> std::io::Result<Option<u32>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = std::fs::symlink_metadata(path)?;
+        if metadata.file_type().is_symlink() {
+            return Ok(None);
+        }
+        Ok(Some(metadata.permissions().mode() & 0o7777))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(None)
+    }
+}
+
+/// Sets the Unix permission bits of a file. Does nothing if `mode` is `None`
+/// or on other platforms.
+pub(crate) fn set_file_mode(path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Sets the modification time of a file, given in nanoseconds since the Unix
+/// epoch.
+pub(crate) fn set_file_mtime(path: &Path, mtime: Option<i64>) -> std::io::Result<()> {
+    let Some(mtime) = mtime else {
+        return Ok(());
+    };
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_nanos(mtime.max(0) as u64);
+    // on Unix the owner sets the times without write access to the file
+    #[cfg(unix)]
+    let file = std::fs::File::open(path)?;
+    #[cfg(not(unix))]
+    let file = std::fs::File::options().write(true).open(path)?;
+    file.set_modified(time)
+}
+
+/// Returns the target of `path` if it is a symlink.
+pub(crate) fn read_symlink(path: &Path) -> std::io::Result<Option<String>> {
+    if !std::fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Ok(None);
+    }
+    Ok(Some(std::fs::read_link(path)?.to_string_lossy().to_string()))
+}
+
+#[cfg(unix)]
+pub(crate) fn create_symlink(target: &str, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_symlink(_target: &str, path: &Path) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "symlinks are not supported on this platform: {}",
+        path.display()
+    ))
+}
+
+/// Copies a file, recreating symlinks instead of following them.
+pub(crate) fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    let target = read_symlink(from)?;
+    // copying onto an existing symlink would write to its target
+    let replace = match std::fs::symlink_metadata(to) {
+        | Ok(v) => target.is_some() || v.file_type().is_symlink(),
+        | Err(_) => false,
+    };
+    if replace {
+        std::fs::remove_file(to)?;
+    }
+    match target {
+        | Some(target) => create_symlink(&target, to),
+        | None => {
+            std::fs::copy(from, to)?;
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn failed_writes_leave_the_file_intact() {
+        let dir = TempDir::new();
+        let path = dir.write("file.txt", "original\n");
+        let result = write_atomic_with(&path, |out| {
+            out.write_all(b"partial")?;
+            Err(anyhow::anyhow!("interrupted"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(dir.read("file.txt"), b"original\n");
+        // the temporary file is removed again
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn writes_replace_the_whole_file() {
+        let dir = TempDir::new();
+        let path = dir.write("file.txt", "a much longer original content\n");
+        write_atomic(&path, b"new\n").unwrap();
+        assert_eq!(dir.read("file.txt"), b"new\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copies_symlinks_as_symlinks() {
+        let dir = TempDir::new();
+        dir.write("target.txt", "content\n");
+        dir.write("other.txt", "other\n");
+        std::os::unix::fs::symlink("target.txt", dir.path().join("link")).unwrap();
+        // copying onto a symlink replaces it instead of writing to its target
+        std::os::unix::fs::symlink("other.txt", dir.path().join("copy")).unwrap();
+        copy_file(&dir.path().join("link"), &dir.path().join("copy")).unwrap();
+
+        assert_eq!(
+            read_symlink(&dir.path().join("copy")).unwrap().as_deref(),
+            Some("target.txt")
+        );
+        assert_eq!(read_symlink(&dir.path().join("target.txt")).unwrap(), None);
+        assert_eq!(dir.read("other.txt"), b"other\n");
+    }
+
+    #[test]
+    fn writes_create_missing_parents() {
+        let dir = TempDir::new();
+        std::fs::create_dir(dir.path().join("a")).unwrap();
+        write_atomic(&dir.path().join("a/b/c/new.txt"), b"new\n").unwrap();
+        assert_eq!(dir.read("a/b/c/new.txt"), b"new\n");
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    io::Read,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::Result;
+use sha2::Digest;
+
+use crate::fs::read_symlink;
+
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Algorithm used for the content hashes of a store and its patches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            | "sha256" => Ok(Self::Sha256),
+            | "blake3" => Ok(Self::Blake3),
+            | _ => Err(anyhow::anyhow!("unknown hash algorithm")),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            | Self::Sha256 => "sha256",
+            | Self::Blake3 => "blake3",
+        }
+    }
+}
+
+pub fn hash(algorithm: HashAlgorithm, content: impl AsRef<[u8]>) -> String {
+    match algorithm {
+        | HashAlgorithm::Sha256 => hex::encode(sha2::Sha256::digest(content)),
+        | HashAlgorithm::Blake3 => blake3::hash(content.as_ref()).to_hex().to_string(),
+    }
+}
+
+/// Incremental hash of content that is not available at once.
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            | HashAlgorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            | HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, content: &[u8]) {
+        match self {
+            | Self::Sha256(v) => v.update(content),
+            | Self::Blake3(v) => {
+                v.update(content);
+            },
+        }
+    }
+
+    pub fn finalize(self) -> String {
+        match self {
+            | Self::Sha256(v) => hex::encode(v.finalize()),
+            | Self::Blake3(v) => v.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Hashes everything read through it.
+pub struct HashReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(algorithm: HashAlgorithm, inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(algorithm),
+        }
+    }
+
+    /// The hash of the content read so far.
+    pub fn finalize(self) -> String {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Hashes a file in fixed size chunks so that large files are never held in
+/// memory as a whole.
+pub fn hash_file(algorithm: HashAlgorithm, path: impl AsRef<Path>) -> std::io::Result<String> {
+    if let Some(target) = read_symlink(path.as_ref())? {
+        return Ok(hash_symlink(algorithm, &target));
+    }
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = vec![0_u8; HASH_CHUNK_SIZE];
+    let mut hasher = Hasher::new(algorithm);
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+/// Symlinks are hashed by their target, prefixed so that they do not match a
+/// regular file containing the plain target.
+pub fn hash_symlink(algorithm: HashAlgorithm, target: &str) -> String {
+    hash(algorithm, format!("symlink:{}", target))
+}
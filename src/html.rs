@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+
+use qop::patch::{
+    unified_range,
+    Patch,
+    PatchFile,
+    PatchFileHunk,
+};
+
+/// Styles of the page, inline so that the file can be shared on its own.
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #24292f; }
+.summary { margin-bottom: 1.5em; }
+.file { border: 1px solid #d0d7de; border-radius: 6px; margin-bottom: 1.5em; overflow: hidden; }
+.path { background: #f6f8fa; border-bottom: 1px solid #d0d7de; padding: 0.5em 1em; font-weight: bold; }
+.status { color: #57606a; font-weight: normal; }
+.note { padding: 0.5em 1em; color: #57606a; }
+table { border-collapse: collapse; width: 100%; font-family: monospace; font-size: 0.9em; }
+td { padding: 0 0.5em; white-space: pre-wrap; vertical-align: top; }
+td.no { color: #8c959f; text-align: right; width: 1%; user-select: none; }
+tr.header td { background: #ddf4ff; color: #57606a; padding: 0.2em 0.5em; }
+tr.add td { background: #e6ffec; }
+tr.del td { background: #ffebe9; }
+.comment { padding: 0.5em 1em; background: #fff8c5; white-space: pre-wrap; }
+ins { background: #abf2bc; text-decoration: none; }
+del { background: #ff8182; text-decoration: none; }
+";
+
+/// Renders a patch as a standalone HTML page with the hunks of every file and
+/// the changed words of replaced lines highlighted. `deleted` holds full
+/// deletion hunks for the files in `Patch::deleted`.
+pub fn render_html(patch: &Patch, deleted: &BTreeMap<String, PatchFile>) -> String {
+    let mut paths = patch.files.keys().chain(deleted.keys()).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+
+    let mut files = String::new();
+    let (mut insertions, mut deletions) = (0, 0);
+    for path in &paths {
+        let (patch_file, status) = if let Some(v) = deleted.get(*path) {
+            (v, "deleted".to_owned())
+        } else if patch.added.contains(path) {
+            (&patch.files[*path], "added".to_owned())
+        } else if let Some(old) = patch.renamed.get(*path) {
+            (&patch.files[*path], format!("renamed from {}", old))
+        } else {
+            (&patch.files[*path], String::new())
+        };
+        let (added, removed) = patch_file.line_counts();
+        insertions += added;
+        deletions += removed;
+
+        files.push_str("<div class=\"file\">\n");
+        files.push_str(&format!("<div class=\"path\">{}", escape(path)));
+        if !status.is_empty() {
+            files.push_str(&format!(" <span class=\"status\">({})</span>", escape(&status)));
+        }
+        files.push_str("</div>\n");
+        if let Some(comment) = &patch_file.comment {
+            files.push_str(&format!("<div class=\"comment\">{}</div>\n", escape(comment)));
+        }
+        if patch_file.symlink.is_some() {
+            files.push_str("<div class=\"note\">symlink changed</div>\n");
+        } else if patch_file.binary.is_some() {
+            files.push_str("<div class=\"note\">binary content changed</div>\n");
+        }
+        if let (Some(pre), Some(post)) = (patch_file.pre_mode, patch_file.post_mode) {
+            if pre != post {
+                files.push_str(&format!("<div class=\"note\">mode {:o} &rarr; {:o}</div>\n", pre, post));
+            }
+        }
+        if patch_file.pre_mtime != patch_file.post_mtime {
+            files.push_str("<div class=\"note\">modification time changed</div>\n");
+        }
+        if !patch_file.hunks.is_empty() {
+            files.push_str("<table>\n");
+            for hunk in &patch_file.hunks {
+                render_hunk(&mut files, hunk);
+            }
+            files.push_str("</table>\n");
+        }
+        files.push_str("</div>\n");
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>qop \
+         diff</title>\n<style>\n{}</style>\n</head>\n<body>\n<div class=\"summary\">{} files changed, {} \
+         insertions(+), {} deletions(-)</div>\n{}</body>\n</html>\n",
+        STYLE,
+        paths.len(),
+        insertions,
+        deletions,
+        files
+    )
+}
+
+/// Renders the rows of one hunk with the line numbers of both sides.
+fn render_hunk(out: &mut String, hunk: &PatchFileHunk) {
+    out.push_str(&format!(
+        "<tr class=\"header\"><td class=\"no\"></td><td class=\"no\"></td><td>@@ -{} +{} @@</td></tr>\n",
+        unified_range(hunk.old_range),
+        unified_range(hunk.new_range)
+    ));
+    if let Some(comment) = &hunk.comment {
+        out.push_str(&format!(
+            "<tr><td class=\"no\"></td><td class=\"no\"></td><td class=\"comment\">{}</td></tr>\n",
+            escape(comment)
+        ));
+    }
+    let (mut old_no, mut new_no) = (hunk.old_range.0 + 1, hunk.new_range.0 + 1);
+    let mut row = |class: &str, old: Option<usize>, new: Option<usize>, content: &str| {
+        let no = |x: Option<usize>| x.map(|x| x.to_string()).unwrap_or_default();
+        out.push_str(&format!(
+            "<tr class=\"{}\"><td class=\"no\">{}</td><td class=\"no\">{}</td><td>{}</td></tr>\n",
+            class,
+            no(old),
+            no(new),
+            content
+        ));
+    };
+
+    let lines = hunk.diff.lines().collect::<Vec<_>>();
+    let mut i = 0;
+    while i < lines.len() {
+        // removed lines directly followed by added lines are paired up to
+        // highlight the words that changed
+        let removed = lines[i..].iter().take_while(|x| x.starts_with('-')).count();
+        let added = lines[i + removed..].iter().take_while(|x| x.starts_with('+')).count();
+        if removed > 0 {
+            let old = &lines[i..i + removed];
+            let new = &lines[i + removed..i + removed + added];
+            let pairs = old.iter().zip(new.iter()).map(|(a, b)| words(&a[1..], &b[1..])).collect::<Vec<_>>();
+            for (n, line) in old.iter().enumerate() {
+                let content = pairs.get(n).map_or_else(|| escape(&line[1..]), |x| x.0.clone());
+                row("del", Some(old_no), None, &content);
+                old_no += 1;
+            }
+            for (n, line) in new.iter().enumerate() {
+                let content = pairs.get(n).map_or_else(|| escape(&line[1..]), |x| x.1.clone());
+                row("add", None, Some(new_no), &content);
+                new_no += 1;
+            }
+            i += removed + added;
+            continue;
+        }
+        let line = lines[i];
+        if let Some(content) = line.strip_prefix('+') {
+            row("add", None, Some(new_no), &escape(content));
+            new_no += 1;
+        } else {
+            row(
+                "context",
+                Some(old_no),
+                Some(new_no),
+                &escape(line.get(1..).unwrap_or("")),
+            );
+            old_no += 1;
+            new_no += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Marks the words that differ between a removed and an added line, returns
+/// the markup of both lines.
+fn words(old: &str, new: &str) -> (String, String) {
+    let (mut old_out, mut new_out) = (String::new(), String::new());
+    for change in similar::TextDiff::from_words(old, new).iter_all_changes() {
+        let value = escape(change.value());
+        match change.tag() {
+            | similar::ChangeTag::Equal => {
+                old_out.push_str(&value);
+                new_out.push_str(&value);
+            },
+            | similar::ChangeTag::Delete => old_out.push_str(&format!("<del>{}</del>", value)),
+            | similar::ChangeTag::Insert => new_out.push_str(&format!("<ins>{}</ins>", value)),
+        }
+    }
+    // adjacent changed words are shown as one span
+    (old_out.replace("</del><del>", ""), new_out.replace("</ins><ins>", ""))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
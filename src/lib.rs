@@ -0,0 +1,17 @@
+//! Checkpoints of a directory tree and patches between them and the working
+//! copy. The functions do not print, they return data for the caller to
+//! format; the `qop` binary is a thin command line interface on top of them.
+
+include!("check_features.rs");
+
+pub mod apply;
+pub mod diff;
+mod fs;
+pub mod hash;
+mod merge;
+pub mod patch;
+pub mod progress;
+pub mod store;
+#[cfg(test)]
+mod testing;
+pub mod walk;
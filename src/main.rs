@@ -1,30 +1,58 @@
 include!("check_features.rs");
 
 pub mod args;
+pub mod html;
 pub mod reference;
 
 use std::{
-    collections::HashMap,
-    io::Read,
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    io::{
+        IsTerminal,
+        Read,
+        Write,
+    },
     path::{
         Path,
         PathBuf,
     },
 };
 
-use anyhow::Result;
-use args::ManualFormat;
-use chrono::{
-    DateTime,
-    Utc,
+use anyhow::{
+    Context,
+    Result,
+};
+use args::{
+    DiffFormat,
+    DiffSource,
+    ManualFormat,
+};
+use qop::{
+    apply::ApplyOptions,
+    diff::DiffOptions,
+    patch::{
+        render_hunk,
+        render_unified,
+        unified_range,
+        ChangeFilter,
+        Patch,
+        PatchFile,
+        PatchFileHunk,
+        PatchFormat,
+    },
+    store::QopDir,
+    walk::PathFilter,
 };
-use sha2::Digest;
-
-const STORE_PATH: &'static str = "./.qop/store";
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let cmd = crate::args::ClapArgumentLoader::load()?;
+    init_logger(cmd.log_level);
+    let mut qop_dir = QopDir::new(cmd.qop_dir);
+    qop_dir.set_hidden(cmd.hidden);
+    qop_dir.set_max_file_size(cmd.max_file_size);
+    qop_dir.set_qopfile_name(cmd.qopfile_name);
 
     match cmd.command {
         | crate::args::Command::Manual { path, format } => {
@@ -46,256 +74,1368 @@ async fn main() -> Result<()> {
             reference::build_shell_completion(&out_path, &shell)?;
             Ok(())
         },
-        | crate::args::Command::Init => {
-            write_index().await?;
+        | crate::args::Command::Init {
+            hash,
+            compression,
+            manifest_only,
+            dry_run,
+        } => {
+            if dry_run {
+                return plan_checkpoint(&qop_dir);
+            }
+            let _lock = qop_dir.lock()?;
+            let index = qop::store::Index {
+                hash,
+                compression,
+                manifest_only,
+                ..Default::default()
+            };
+            checkpoint(&qop_dir, None, Some(index), None, None, false)?;
+            Ok(())
+        },
+        | crate::args::Command::Checkpoint {
+            name,
+            message,
+            author,
+            strict,
+            dry_run,
+        } => {
+            if dry_run {
+                return plan_checkpoint(&qop_dir);
+            }
+            let _lock = qop_dir.lock()?;
+            checkpoint(&qop_dir, name, None, message, author, strict)?;
+            Ok(())
+        },
+        | crate::args::Command::Apply {
+            files,
+            format,
+            reverse,
+            interactive,
+            changes,
+            line_endings,
+            fuzz,
+            force,
+            merge,
+            dry_run,
+            backup,
+            reject,
+            atomic,
+            output_dir,
+            copy_unchanged,
+            report,
+            root,
+            paths,
+        } => {
+            let _lock = if dry_run { None } else { Some(qop_dir.lock()?) };
+            let selection = Selection {
+                paths: PathFilter::new(&paths)?,
+                reverse,
+                interactive,
+                changes,
+            };
+            let output = match report {
+                | _ if dry_run => ApplyOutput::DryRun,
+                | Some(v) => ApplyOutput::Report(v),
+                | None => ApplyOutput::Log,
+            };
+            let options = ApplyOptions {
+                root: PathBuf::from(root),
+                line_endings,
+                fuzz,
+                force,
+                merge,
+                backup,
+                reject,
+            };
+            let destination = Destination {
+                atomic,
+                output_dir: output_dir.map(PathBuf::from),
+                copy_unchanged,
+            };
+            apply(&qop_dir, files, format, &selection, output, options, &destination)?;
+            Ok(())
+        },
+        | crate::args::Command::Diff {
+            from,
+            reverse,
+            line_endings,
+            format,
+            pretty,
+            word_diff,
+            stat,
+            interactive,
+            algorithm,
+            whitespace,
+            context,
+            quiet,
+            exit_code,
+            index,
+            find_renames,
+            paths,
+            output,
+            strict,
+            include_metadata,
+            fast_path,
+        } => {
+            let index = match index {
+                | Some(v) => Some(qop::store::parse_foreign_index(Path::new(&v), &read_input(&v)?)?),
+                | None => None,
+            };
+            let changed = diff(
+                &qop_dir,
+                from,
+                DiffOptions {
+                    reverse,
+                    line_endings,
+                    context,
+                    paths: PathFilter::new(&paths)?,
+                    index,
+                    find_renames,
+                    strict,
+                    algorithm,
+                    whitespace,
+                    include_metadata,
+                    fast_path,
+                },
+                match stat {
+                    | Some(json) => DiffOutput::Stat { json },
+                    | None if pretty => DiffOutput::Pretty { word_diff },
+                    | None => {
+                        match format {
+                            | DiffFormat::Patch(v) => DiffOutput::Patch(v),
+                            | DiffFormat::Html => DiffOutput::Html,
+                        }
+                    },
+                },
+                interactive,
+                if quiet { None } else { Some(&output) },
+            )?;
+            if exit_code && changed {
+                std::process::exit(1);
+            }
             Ok(())
         },
-        | crate::args::Command::Checkpoint => {
-            write_index().await?;
+        | crate::args::Command::Restore {
+            checkpoint,
+            dry_run,
+            force,
+        } => {
+            let _lock = if dry_run { None } else { Some(qop_dir.lock()?) };
+            restore(&qop_dir, checkpoint, dry_run, force)?;
             Ok(())
         },
-        | crate::args::Command::Apply { file } => {
-            apply(file).await?;
+        | crate::args::Command::Checkout { checkpoint, force } => {
+            let _lock = qop_dir.lock()?;
+            let plan = qop::store::checkout(&qop_dir, &checkpoint, force)?;
+            log::info!(
+                "restored {} files, removed {} files, checked out {}",
+                plan.restored.len(),
+                plan.removed.len(),
+                checkpoint
+            );
             Ok(())
         },
-        | crate::args::Command::Diff { reverse } => {
-            diff(reverse).await?;
+        | crate::args::Command::Log { json } => {
+            log(&qop_dir, json)?;
             Ok(())
         },
-        | crate::args::Command::Reverse { file } => {
-            reverse(file).await?;
+        | crate::args::Command::Prune { keep, before } => {
+            let _lock = qop_dir.lock()?;
+            let report = qop::store::prune(&qop_dir, keep, before)?;
+            log::info!(
+                "pruned {} checkpoints, reclaimed {}",
+                report.removed.len(),
+                format_size(report.reclaimed)
+            );
             Ok(())
         },
+        | crate::args::Command::Watch { interval } => watch(&qop_dir, std::time::Duration::from_secs(interval)),
+        | crate::args::Command::Migrate => {
+            let _lock = qop_dir.lock()?;
+            let report = qop::store::migrate(&qop_dir)?;
+            log::info!(
+                "migrated {} checkpoints to {} stored files",
+                report.checkpoints,
+                report.blobs
+            );
+            Ok(())
+        },
+        | crate::args::Command::Fsck => {
+            if !fsck(&qop_dir)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+        | crate::args::Command::Doctor => {
+            if !doctor(&qop_dir)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+        | crate::args::Command::Status { short } => {
+            status(&qop_dir, short)?;
+            Ok(())
+        },
+        | crate::args::Command::LsFiles { with_hash, json, paths } => {
+            ls_files(&qop_dir, &PathFilter::new(&paths)?, with_hash, json)
+        },
+        | crate::args::Command::Manifest { format } => manifest(&qop_dir, format),
+        | crate::args::Command::Hash { files, algorithm } => hash(&qop_dir, &files, algorithm),
+        | crate::args::Command::Du { bytes } => du(&qop_dir, bytes),
+        | crate::args::Command::Reverse { file, format, output } => {
+            reverse(&qop_dir, file, format, &output)?;
+            Ok(())
+        },
+        | crate::args::Command::Blame { path, json } => blame(&qop_dir, &path, json),
+        | crate::args::Command::Validate { file, format, fix } => validate(&file, format, fix),
     }
 }
 
-async fn write_index() -> Result<()> {
-    fn process_files(path: PathBuf, ignore_stack: &mut Vec<Vec<String>>) -> Result<HashMap<String, String>> {
-        let mut files = HashMap::new();
-        let dir = std::fs::read_dir(&path)?.filter_map(|entry| entry.ok()).collect::<Vec<_>>();
-
-        let ignore_patterns = match std::fs::read_to_string(Path::join(&path, ".qopfile")) {
-            | Ok(s) => {
-                let f = toml::from_str::<QopFile>(&s)?;
-                f.ignore.iter().map(|x| Path::join(&path, x).to_str().unwrap().to_owned()).collect::<Vec<_>>()
-            },
-            | Err(_) => Vec::<String>::new(),
-        };
-        ignore_stack.push(ignore_patterns);
-
-        'entries: for d in dir {
-            for ignore_list in ignore_stack.iter() {
-                for ignore_pattern in ignore_list {
-                    if d.path().starts_with(ignore_pattern) {
-                        continue 'entries;
-                    }
-                }
+/// Logs to stderr. Everything below warnings is printed without a prefix since
+/// it is regular output. `RUST_LOG` overrides the level.
+fn init_logger(level: log::LevelFilter) {
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| {
+            match record.level() {
+                | log::Level::Error => writeln!(buf, "error: {}", record.args()),
+                | log::Level::Warn => writeln!(buf, "warning: {}", record.args()),
+                | _ => writeln!(buf, "{}", record.args()),
             }
+        })
+        .init();
+}
 
-            let rel_path = d.path();
-            let new_path = Path::new(STORE_PATH).join(&rel_path);
-            if d.file_type()?.is_dir() {
-                std::fs::create_dir(&new_path)?;
-                files.extend(process_files(d.path(), ignore_stack)?);
-            } else {
-                let hash = hex::encode(sha2::Sha256::digest(std::fs::read(&rel_path)?));
-                files.insert(d.path().to_string_lossy().to_string(), hash);
-                std::fs::copy(&rel_path, new_path)?;
-            }
+/// Progress bar on stderr. It is hidden if stderr is no terminal or info
+/// messages are not logged.
+struct ProgressBar(indicatif::ProgressBar);
+
+impl ProgressBar {
+    fn new() -> Result<Self> {
+        if !console::Term::stderr().is_term() || !log::log_enabled!(log::Level::Info) {
+            return Ok(Self(indicatif::ProgressBar::hidden()));
         }
-        ignore_stack.pop();
+        let style = indicatif::ProgressStyle::with_template("{bar:30} {pos}/{len} files {wide_msg}")?;
+        Ok(Self(indicatif::ProgressBar::new(0).with_style(style)))
+    }
+}
 
-        Ok(files)
+impl qop::progress::Progress for ProgressBar {
+    fn start(&self, total: usize) {
+        self.0.set_length(total as u64);
     }
 
-    let _ = std::fs::remove_dir_all(STORE_PATH);
-    std::fs::create_dir_all(STORE_PATH)?;
+    fn advance(&self, path: &Path) {
+        self.0.set_message(path.display().to_string());
+        self.0.inc(1);
+    }
+}
 
-    let index = Index {
-        latest: None,
-        entries: HashMap::new(),
-        files: process_files(PathBuf::from("."), &mut Vec::new())?,
-    };
+/// Snapshots the working copy into a new checkpoint, see
+/// `qop::store::checkpoint`.
+fn checkpoint(
+    qop_dir: &QopDir,
+    name: Option<String>,
+    fresh: Option<qop::store::Index>,
+    message: Option<String>,
+    author: Option<String>,
+    strict: bool,
+) -> Result<()> {
+    let progress = ProgressBar::new()?;
+    let report = qop::store::checkpoint(qop_dir, name, fresh, message, author, strict, &progress);
+    progress.0.finish_and_clear();
+    let report = report?;
+    for path in &report.too_large {
+        log::warn!("skipped {} since it is larger than the size limit", path);
+    }
+    for path in &report.changed {
+        log::warn!(
+            "{} changed while it was copied, the checkpoint may hold a partial write",
+            path
+        );
+    }
+    if report.hashed > 0 {
+        log::info!("recorded the hashes of {} files", report.hashed);
+        return Ok(());
+    }
+    log::info!(
+        "copied {} files, skipped {} unchanged files",
+        report.copied,
+        report.unchanged
+    );
+    Ok(())
+}
 
-    std::fs::write("./.qop/index.toml", toml::to_string(&index)?)?;
+/// Prints the files a checkpoint would store, see
+/// `qop::store::plan_checkpoint`.
+fn plan_checkpoint(qop_dir: &QopDir) -> Result<()> {
+    let plan = qop::store::plan_checkpoint(qop_dir)?;
+    for path in &plan.too_large {
+        log::warn!("would skip {} since it is larger than the size limit", path);
+    }
+    for path in &plan.files {
+        println!("{}", path);
+    }
+    log::info!("would store {} files", plan.files.len());
     Ok(())
 }
 
-async fn diff(reverse: bool) -> Result<()> {
-    let index = toml::from_str::<Index>(&std::fs::read_to_string("./.qop/index.toml")?)?;
-    let mut patch = Patch { files: HashMap::new() };
-    for (path, store_hash) in index.files {
-        let store_path = Path::new(STORE_PATH).join(&path);
-        let wc_path = Path::new(&path);
+/// Time without changes after which `watch` considers an edit complete.
+const WATCH_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(500);
 
-        let wc_file_content = std::fs::read_to_string(&wc_path)?;
-        let wc_hash = hex::encode(sha2::Sha256::digest(&wc_file_content));
+/// Creates a checkpoint after files changed, once the tree has been quiet for
+/// `WATCH_DEBOUNCE` and at least `interval` has passed since the previous one.
+/// Changes to ignored files do not show up in the status and therefore never
+/// cause a checkpoint.
+fn watch(qop_dir: &QopDir, interval: std::time::Duration) -> Result<()> {
+    qop::store::read_index(qop_dir)?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    notify::Watcher::watch(&mut watcher, Path::new("."), notify::RecursiveMode::Recursive)?;
+    // writes of the checkpoints themselves must not trigger the next one
+    let own = std::path::absolute(qop_dir.path())?;
+    let relevant = |event: notify::Result<notify::Event>| -> Result<bool> {
+        Ok(event?.paths.iter().any(|x| !std::path::absolute(x).is_ok_and(|x| x.starts_with(&own))))
+    };
 
-        if wc_hash == store_hash {
+    log::info!("watching for changes");
+    let mut last: Option<std::time::Instant> = None;
+    loop {
+        if !relevant(rx.recv()?)? {
             continue;
         }
-        let store_file_content = std::fs::read_to_string(&store_path)?;
+        loop {
+            let wait = last.map_or(WATCH_DEBOUNCE, |x| {
+                interval.saturating_sub(x.elapsed()).max(WATCH_DEBOUNCE)
+            });
+            match rx.recv_timeout(wait) {
+                | Ok(event) => {
+                    relevant(event)?;
+                },
+                | Err(std::sync::mpsc::RecvTimeoutError::Timeout) => break,
+                | Err(e) => return Err(e.into()),
+            }
+        }
+        if qop::store::status(qop_dir)?.is_empty() {
+            continue;
+        }
+        let _lock = match qop_dir.lock() {
+            | Ok(v) => v,
+            | Err(e) => {
+                log::warn!("skipped checkpoint: {}", e);
+                continue;
+            },
+        };
+        checkpoint(qop_dir, None, None, None, None, false)?;
+        last = Some(std::time::Instant::now());
+    }
+}
 
-        let diff = if !reverse {
-            similar::TextDiff::from_lines(&store_file_content, &wc_file_content)
-        } else {
-            similar::TextDiff::from_lines(&wc_file_content, &store_file_content)
+/// How `diff` writes its result.
+enum DiffOutput {
+    Patch(PatchFormat),
+    /// A human readable diff, see `render_pretty`.
+    Pretty {
+        word_diff: bool,
+    },
+    /// Only the number of changed lines, see `DiffStat`.
+    Stat {
+        json: bool,
+    },
+    /// A standalone HTML page, see `html::render_html`.
+    Html,
+}
+
+/// Writes the patch from a checkpoint to the working copy or between two
+/// directories to `output`, or nowhere if it is `None`. With `interactive`,
+/// only the hunks selected by the user are kept. Returns whether there are any
+/// differences.
+fn diff(
+    qop_dir: &QopDir,
+    from: DiffSource,
+    options: DiffOptions,
+    how: DiffOutput,
+    interactive: bool,
+    output: Option<&str>,
+) -> Result<bool> {
+    let report = match &from {
+        | DiffSource::Checkpoint(v) => qop::diff::compute_diff(qop_dir, v, &options)?,
+        | DiffSource::Dirs(a, b) => qop::diff::diff_dirs(qop_dir, Path::new(a), Path::new(b), &options)?,
+    };
+    for file in &report.failed {
+        log::warn!("left {} out of the diff: {:#}", file.path, file.error);
+    }
+    if !report.failed.is_empty() {
+        log::warn!(
+            "{} files could not be read, use --strict to fail instead",
+            report.failed.len()
+        );
+    }
+    let mut patch = report.patch;
+    if interactive {
+        select_hunks(&mut patch)?;
+    }
+    let changed = !patch.files.is_empty() || !patch.deleted.is_empty();
+    let Some(output) = output else {
+        return Ok(changed);
+    };
+    if let DiffOutput::Patch(format @ (PatchFormat::Toml | PatchFormat::Json)) = how {
+        write_output(output, &format!("{}\n", qop::patch::write_patch(&patch, format)?))?;
+        return Ok(changed);
+    }
+
+    let deleted = match &from {
+        | DiffSource::Checkpoint(v) => qop::diff::deletion_hunks(qop_dir, v, &patch, &options)?,
+        | DiffSource::Dirs(a, b) => qop::diff::dir_deletion_hunks(Path::new(a), Path::new(b), &patch, &options)?,
+    };
+    if output != "-" {
+        console::set_colors_enabled(false);
+    }
+    match how {
+        | DiffOutput::Pretty { word_diff } => write_output(output, &render_pretty(&patch, &deleted, word_diff))?,
+        | DiffOutput::Stat { json: false } => write_output(output, &render_stat(&diff_stat(&patch, &deleted)))?,
+        | DiffOutput::Stat { json: true } => {
+            write_output(
+                output,
+                &format!("{}\n", serde_json::to_string_pretty(&diff_stat(&patch, &deleted))?),
+            )?
+        },
+        | DiffOutput::Patch(_) => write_output(output, &render_unified(&patch, &deleted))?,
+        | DiffOutput::Html => write_output(output, &crate::html::render_html(&patch, &deleted))?,
+    }
+    Ok(changed)
+}
+
+/// Asks on the terminal for every hunk of the patch whether to keep it, like
+/// `git add -p`. Changes without hunks, like deletions, binary files and
+/// permissions, are asked for as a whole. Without a terminal everything is
+/// kept. Returns the hunks that were left out by path.
+fn select_hunks(patch: &mut Patch) -> Result<BTreeMap<String, Vec<PatchFileHunk>>> {
+    let mut left_out = BTreeMap::new();
+    let term = console::Term::stderr();
+    if !term.is_term() || !std::io::stdin().is_terminal() {
+        log::warn!("no terminal to ask on, all changes of the patch are kept");
+        return Ok(left_out);
+    }
+    // y and n decide a single change, a and d the rest of the file, q all
+    // remaining changes
+    let ask = |question: &str| -> Result<char> {
+        loop {
+            term.write_str(&format!("{} [y,n,a,d,q,?]? ", console::style(question).blue().bold()))?;
+            let answer = term.read_char()?;
+            term.write_line(&answer.to_string())?;
+            match answer {
+                | 'y' | 'n' | 'a' | 'd' | 'q' => return Ok(answer),
+                | _ => {
+                    term.write_line(
+                        "y - include this change\nn - leave this change out\na - include this and the remaining \
+                         changes of the file\nd - leave this and the remaining changes of the file out\nq - leave \
+                         this and all remaining changes out",
+                    )?;
+                },
+            }
+        }
+    };
+
+    let mut quit = false;
+    for path in patch.files.keys().cloned().collect::<Vec<_>>() {
+        let file = &patch.files[&path];
+        term.write_line(&console::style(&path).bold().to_string())?;
+        term.write_str(&render_comment(file.comment.as_deref()))?;
+        if file.hunks.is_empty() {
+            let keep = !quit && {
+                let answer = ask("Include the change of this file")?;
+                quit = answer == 'q';
+                matches!(answer, 'y' | 'a')
+            };
+            if !keep {
+                patch.files.remove(&path);
+                patch.added.retain(|x| *x != path);
+                patch.renamed.remove(&path);
+            }
+            continue;
+        }
+        let mut removed = BTreeSet::new();
+        let mut rest = None;
+        for (i, hunk) in file.hunks.iter().enumerate() {
+            let keep = match rest {
+                | Some(v) => v,
+                | None if quit => false,
+                | None => {
+                    term.write_str(&render_comment(hunk.comment.as_deref()))?;
+                    term.write_str(&render_hunk(hunk))?;
+                    let answer = ask("Include this hunk")?;
+                    match answer {
+                        | 'a' => rest = Some(true),
+                        | 'd' => rest = Some(false),
+                        | 'q' => quit = true,
+                        | _ => (),
+                    }
+                    matches!(answer, 'y' | 'a')
+                },
+            };
+            if !keep {
+                removed.insert(i);
+            }
+        }
+        if !removed.is_empty() {
+            left_out.insert(path.clone(), removed.iter().map(|x| file.hunks[*x].clone()).collect());
+        }
+        qop::patch::remove_hunks(patch, &path, &removed);
+    }
+    let mut deleted = Vec::new();
+    for path in std::mem::take(&mut patch.deleted) {
+        let keep = !quit && {
+            term.write_line(&console::style(&path).bold().to_string())?;
+            let answer = ask("Delete this file")?;
+            quit = answer == 'q';
+            matches!(answer, 'y' | 'a')
         };
+        if keep {
+            deleted.push(path);
+        }
+    }
+    patch.deleted = deleted;
+    Ok(left_out)
+}
 
-        let mut diff_hunks = Vec::<PatchFileHunk>::new();
-        for hunk in diff.unified_diff().context_radius(0).iter_hunks() {
-            let ops = hunk.ops();
-            let first_op = ops[0];
-            let last_op = ops[ops.len() - 1];
-
-            let mut diff = Vec::<String>::new();
-            for c in hunk.iter_changes() {
-                match c.tag() {
-                    | similar::ChangeTag::Equal => {
-                        diff.push(format!(" {}", c.value()));
-                    },
-                    | similar::ChangeTag::Insert => {
-                        diff.push(format!("+{}", c.value()));
-                    },
-                    | similar::ChangeTag::Delete => {
-                        diff.push(format!("-{}", c.value()));
-                    },
+/// Renders a patch for humans with a summary of the changes. `deleted` holds
+/// full deletion hunks for the files in `Patch::deleted`. Colors are only
+/// emitted if stdout is a terminal. With `word_diff`, replaced lines are shown
+/// once with their changed words marked.
+fn render_pretty(patch: &Patch, deleted: &BTreeMap<String, PatchFile>, word_diff: bool) -> String {
+    let mut out = String::new();
+    let (mut insertions, mut deletions) = (0, 0);
+    let mut paths = patch.files.keys().chain(deleted.keys()).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    for path in &paths {
+        let (patch_file, status) = if let Some(v) = deleted.get(*path) {
+            (v, " (deleted)".to_owned())
+        } else if patch.added.contains(path) {
+            (&patch.files[*path], " (added)".to_owned())
+        } else if let Some(old) = patch.renamed.get(*path) {
+            (&patch.files[*path], format!(" (renamed from {})", old))
+        } else {
+            (&patch.files[*path], String::new())
+        };
+        let kind = if patch_file.symlink.is_some() {
+            " (symlink)"
+        } else if patch_file.binary.is_some() {
+            " (binary)"
+        } else {
+            ""
+        };
+        out.push_str(&format!(
+            "{}\n",
+            console::style(format!("{}{}{}", path, status, kind)).bold()
+        ));
+        out.push_str(&render_comment(patch_file.comment.as_deref()));
+        for hunk in &patch_file.hunks {
+            out.push_str(&format!(
+                "{}\n",
+                console::style(format!(
+                    "@@ -{} +{} @@",
+                    unified_range(hunk.old_range),
+                    unified_range(hunk.new_range)
+                ))
+                .cyan()
+            ));
+            out.push_str(&render_comment(hunk.comment.as_deref()));
+            let lines = hunk.diff.lines().collect::<Vec<_>>();
+            let mut i = 0;
+            while i < lines.len() {
+                if word_diff {
+                    // a block of removed lines directly followed by added lines
+                    // is shown as one with the changed words marked
+                    let removed = lines[i..].iter().take_while(|x| x.starts_with('-')).count();
+                    let added = lines[i + removed..].iter().take_while(|x| x.starts_with('+')).count();
+                    if removed > 0 && added > 0 {
+                        let old = lines[i..i + removed].iter().map(|x| &x[1..]).collect::<Vec<_>>();
+                        let new = lines[i + removed..i + removed + added].iter().map(|x| &x[1..]).collect::<Vec<_>>();
+                        out.push_str(&render_words(&old.join("\n"), &new.join("\n")));
+                        deletions += removed;
+                        insertions += added;
+                        i += removed + added;
+                        continue;
+                    }
                 }
+                let line = lines[i];
+                let styled = if line.starts_with('+') {
+                    insertions += 1;
+                    console::style(line).green()
+                } else if line.starts_with('-') {
+                    deletions += 1;
+                    console::style(line).red()
+                } else {
+                    console::style(line)
+                };
+                out.push_str(&format!("{}\n", styled));
+                i += 1;
             }
-
-            diff_hunks.push(PatchFileHunk {
-                old_range: (first_op.old_range().start, last_op.old_range().end),
-                new_range: (first_op.new_range().start, last_op.new_range().end),
-                diff: diff.concat(),
-            });
         }
+    }
+    out.push_str(&render_summary(paths.len(), insertions, deletions));
+    out
+}
+
+/// Shows the comment of a file or hunk of the patch as lines starting with
+/// `# `.
+fn render_comment(comment: Option<&str>) -> String {
+    comment
+        .into_iter()
+        .flat_map(|x| x.lines())
+        .map(|x| format!("{}\n", console::style(format!("# {}", x)).dim()))
+        .collect()
+}
 
-        patch.files.insert(path, PatchFile {
-            pre_hash: store_hash,
-            post_hash: wc_hash,
-            hunks: diff_hunks,
+fn render_summary(files: usize, insertions: usize, deletions: usize) -> String {
+    format!(
+        "{} files changed, {} insertions(+), {} deletions(-)\n",
+        files, insertions, deletions
+    )
+}
+
+/// Number of inserted and deleted lines of a diff.
+#[derive(Debug, serde::Serialize)]
+struct DiffStat {
+    files: Vec<FileStat>,
+    insertions: usize,
+    deletions: usize,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct FileStat {
+    path: String,
+    /// One of `added`, `deleted`, `renamed` and `modified`.
+    status: &'static str,
+    insertions: usize,
+    deletions: usize,
+    /// Binary files and symlinks are changed as a whole, without lines.
+    binary: bool,
+}
+
+/// Counts the changed lines of a patch. `deleted` holds full deletion hunks
+/// for the files in `Patch::deleted`.
+fn diff_stat(patch: &Patch, deleted: &BTreeMap<String, PatchFile>) -> DiffStat {
+    let mut files = Vec::new();
+    for (path, patch_file) in patch.files.iter().chain(deleted.iter()) {
+        let status = if deleted.contains_key(path) {
+            "deleted"
+        } else if patch.added.contains(path) {
+            "added"
+        } else if patch.renamed.contains_key(path) {
+            "renamed"
+        } else {
+            "modified"
+        };
+        let (insertions, deletions) = patch_file.line_counts();
+        files.push(FileStat {
+            path: path.clone(),
+            status,
+            insertions,
+            deletions,
+            binary: patch_file.binary.is_some() || patch_file.symlink.is_some(),
         });
     }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    DiffStat {
+        insertions: files.iter().map(|x| x.insertions).sum(),
+        deletions: files.iter().map(|x| x.deletions).sum(),
+        files,
+    }
+}
 
-    println!("{}", toml::to_string(&patch)?);
-    Ok(())
+/// Renders one line per file like `git diff --stat` and the summary of
+/// `render_pretty`.
+fn render_stat(stat: &DiffStat) -> String {
+    let width = stat.files.iter().map(|x| x.path.chars().count()).max().unwrap_or(0);
+    let mut out = String::new();
+    for file in &stat.files {
+        let change = if file.binary {
+            "binary".to_owned()
+        } else {
+            format!("+{} -{}", file.insertions, file.deletions)
+        };
+        out.push_str(&format!("{:<width$} | {}\n", file.path, change, width = width));
+    }
+    out.push_str(&render_summary(stat.files.len(), stat.insertions, stat.deletions));
+    out
 }
 
-async fn apply(file: String) -> Result<()> {
-    let patch = if file == "-" {
-        toml::from_str::<Patch>(&{
-            let mut s = String::new();
-            std::io::stdin().read_to_string(&mut s)?;
-            s
-        })?
-    } else {
-        toml::from_str::<Patch>(&std::fs::read_to_string(file)?)?
+/// Renders replaced lines with the changed words marked in the style of `git
+/// diff --word-diff` as `[-removed-]` and `{+added+}`.
+fn render_words(old: &str, new: &str) -> String {
+    let mut out = String::from(" ");
+    let mut push = |tag: similar::ChangeTag, text: &str| {
+        // styles are applied per line so that they do not span line breaks
+        for (n, part) in text.split('\n').enumerate() {
+            if n > 0 {
+                out.push_str("\n ");
+            }
+            if part.is_empty() {
+                continue;
+            }
+            match tag {
+                | similar::ChangeTag::Equal => out.push_str(part),
+                | similar::ChangeTag::Delete => {
+                    out.push_str(&console::style(format!("[-{}-]", part)).red().to_string());
+                },
+                | similar::ChangeTag::Insert => {
+                    out.push_str(&console::style(format!("{{+{}+}}", part)).green().to_string());
+                },
+            }
+        }
     };
+    let diff = similar::TextDiff::from_words(old, new);
+    let mut run = (similar::ChangeTag::Equal, String::new());
+    for change in diff.iter_all_changes() {
+        if change.tag() != run.0 {
+            push(run.0, &run.1);
+            run = (change.tag(), String::new());
+        }
+        run.1.push_str(change.value());
+    }
+    push(run.0, &run.1);
+    out.push('\n');
+    out
+}
 
-    for mut patch_file in patch.files {
-        patch_file.1.hunks.sort_by(|a, b| a.old_range.0.cmp(&b.old_range.0));
-
-        let mut line_idx = 0_usize;
-        let mut file_new = Vec::<String>::new();
-        let file_old = std::fs::read_to_string(&patch_file.0)?;
-        let mut file_old_iter = file_old.lines();
-        'eof: for hunk in patch_file.1.hunks {
-            while line_idx < hunk.new_range.0 {
-                if let Some(v) = file_old_iter.next() {
-                    file_new.push(v.to_owned());
-                    line_idx += 1;
-                } else {
-                    break 'eof;
-                }
+/// What `apply` prints besides its log.
+#[derive(Debug, Clone, Copy)]
+enum ApplyOutput {
+    Log,
+    /// Only prints what would be changed.
+    DryRun,
+    /// Prints a receipt of all patches once they are applied.
+    Report(crate::args::ReportFormat),
+}
+
+/// Outcome of one patch in the receipt of `apply --report`.
+#[derive(Debug, serde::Serialize)]
+struct ApplyReceipt {
+    patch: String,
+    files: Vec<qop::apply::AppliedFile>,
+    deleted: Vec<String>,
+    rejects: Vec<String>,
+    conflicts: Vec<String>,
+}
+
+/// Applies the patches in order, each one to the result of the previous ones.
+/// Stops at the first patch that fails or leaves rejects or conflicts.
+fn apply(
+    qop_dir: &QopDir,
+    files: Vec<String>,
+    format: PatchFormat,
+    selection: &Selection,
+    output: ApplyOutput,
+    mut options: ApplyOptions,
+    destination: &Destination,
+) -> Result<()> {
+    let files = patch_files(files, format)?;
+    let output_dir = match &destination.output_dir {
+        | Some(dir) => {
+            let output_dir = qop::apply::OutputDir::create(qop_dir, &options.root, dir, destination.copy_unchanged)?;
+            // the patches are applied to the copies
+            options.root = output_dir.path().to_owned();
+            Some(output_dir)
+        },
+        | None => None,
+    };
+    let mut target = Target {
+        transaction: if destination.atomic {
+            Some(qop::apply::Transaction::begin(qop_dir, &options.root)?)
+        } else {
+            None
+        },
+        output_dir,
+    };
+    let mut receipts = Vec::new();
+    // patches with rejected hunks or conflicts changed files as well, they are
+    // part of the receipt
+    let mut apply_one = |file: &str| -> Result<()> {
+        let Some(receipt) = apply_file(qop_dir, file, format, selection, output, options.clone(), &mut target)? else {
+            return Ok(());
+        };
+        let result = if !receipt.rejects.is_empty() {
+            Err(anyhow::anyhow!("rejected hunks:\n{}", receipt.rejects.join("\n")))
+        } else if !receipt.conflicts.is_empty() {
+            Err(anyhow::anyhow!("merge conflicts in:\n{}", receipt.conflicts.join("\n")))
+        } else {
+            Ok(())
+        };
+        receipts.push(receipt);
+        result
+    };
+    let result = if files.len() == 1 {
+        apply_one(&files[0])
+    } else {
+        (|| -> Result<()> {
+            for (n, file) in files.iter().enumerate() {
+                log::info!("applying {}", file);
+                apply_one(file)
+                    .with_context(|| format!("failed to apply {}, applied {} of {} patches", file, n, files.len()))?;
             }
-            // skip remove lines
-            for _ in 0..(hunk.old_range.1 - hunk.old_range.0) {
-                let _ = file_old_iter.next();
+            if !matches!(output, ApplyOutput::DryRun) {
+                log::info!("applied {} patches", files.len());
             }
-            // insert new lines
-            for add_line in hunk.diff.lines().filter(|x| x.starts_with('+')) {
-                file_new.push(add_line[1..].to_owned());
+            Ok(())
+        })()
+    };
+    let result = match target.transaction {
+        | Some(transaction) if result.is_err() => {
+            let restored = transaction.rollback().context("failed to roll back the applied patches")?;
+            log::info!("rolled back all patches, restored {} files", restored);
+            result
+        },
+        | Some(transaction) => transaction.commit().and(result),
+        | None => result,
+    };
+    if let (Ok(()), Some(dir)) = (&result, &destination.output_dir) {
+        log::info!("wrote the patched files to {}", dir.display());
+    }
+    if let ApplyOutput::Report(crate::args::ReportFormat::Json) = output {
+        #[derive(serde::Serialize)]
+        struct Receipt {
+            patches: Vec<ApplyReceipt>,
+        }
+        println!("{}", serde_json::to_string_pretty(&Receipt { patches: receipts })?);
+    }
+    result
+}
+
+/// Where `apply` writes the patched files.
+struct Destination {
+    /// Applies all patches or none of them.
+    atomic: bool,
+    /// Writes the patched files below this directory instead of the root of
+    /// the patch.
+    output_dir: Option<PathBuf>,
+    /// Copies the other files of the root to `output_dir` as well.
+    copy_unchanged: bool,
+}
+
+/// State that spans all patches of one `apply`.
+struct Target {
+    transaction: Option<qop::apply::Transaction>,
+    output_dir: Option<qop::apply::OutputDir>,
+}
+
+/// Which changes of the patches `apply` applies.
+struct Selection {
+    paths: PathFilter,
+    reverse: bool,
+    /// Asks for every hunk whether to apply it, the others are written to
+    /// `.rej` files.
+    interactive: bool,
+    /// Applies only the additions or only the deletions.
+    changes: Option<ChangeFilter>,
+}
+
+/// Applies one patch file. Returns its outcome unless it is a dry run.
+fn apply_file(
+    qop_dir: &QopDir,
+    file: &str,
+    format: PatchFormat,
+    selection: &Selection,
+    output: ApplyOutput,
+    options: ApplyOptions,
+    target: &mut Target,
+) -> Result<Option<ApplyReceipt>> {
+    let mut patch = read_patch(file, format)?;
+    qop::patch::retain_paths(&mut patch, &selection.paths);
+    let mut patch = if selection.reverse {
+        qop::patch::reverse_patch(qop_dir, patch)?
+    } else {
+        patch
+    };
+    if let Some(filter) = selection.changes {
+        for skipped in qop::patch::filter_changes(&mut patch, filter) {
+            match skipped.hunks {
+                | Some(hunks) => log::info!("skipped {} hunks of {}", hunks, skipped.path),
+                | None => log::info!("skipped {}", skipped.path),
             }
-            line_idx = hunk.new_range.1;
         }
-        while let Some(line) = file_old_iter.next() {
-            file_new.push(line.to_owned());
+    }
+    let left_out = if selection.interactive {
+        select_hunks(&mut patch)?
+    } else {
+        BTreeMap::new()
+    };
+    if let ApplyOutput::DryRun = output {
+        print_apply_summary(&patch, &qop::apply::mismatched_files(&patch, &options.root)?);
+        return Ok(None);
+    }
+    for (path, hunks) in &left_out {
+        // removed again on rollback, a file whose hunks were all left out is
+        // no longer part of the patch
+        if let Some(transaction) = &mut target.transaction {
+            transaction.record_reject(path)?;
+        }
+        let reject_path = qop::apply::write_rejects(&options.root, path, hunks)?;
+        log::info!(
+            "wrote {} left out hunks of {} to {}",
+            hunks.len(),
+            path,
+            reject_path.display()
+        );
+    }
+
+    if let Some(output_dir) = &mut target.output_dir {
+        output_dir.stage(&patch)?;
+    }
+    if let Some(transaction) = &mut target.transaction {
+        transaction.record(&patch)?;
+    }
+    let report = qop::apply::apply_patch(qop_dir, patch, options)?;
+    if let Some((dir, count)) = &report.backup {
+        log::info!("backed up {} files to {}", count, dir.display());
+    }
+    for path in &report.post_hash_mismatches {
+        log::warn!("{} does not match the post hash of the patch", path);
+    }
+    log::info!(
+        "patched {} files, deleted {} files",
+        report.patched,
+        report.deleted.len()
+    );
+    if report.renamed > 0 {
+        log::info!("renamed {} files", report.renamed);
+    }
+    Ok(Some(ApplyReceipt {
+        patch: file.to_owned(),
+        files: report.files,
+        deleted: report.deleted,
+        rejects: report.rejects,
+        conflicts: report.conflicts,
+    }))
+}
+
+/// Expands directories to the patches in them with the extension of the
+/// format, sorted by name.
+fn patch_files(files: Vec<String>, format: PatchFormat) -> Result<Vec<String>> {
+    let extensions: &[&str] = match format {
+        | PatchFormat::Toml => &["toml"],
+        | PatchFormat::Json => &["json"],
+        | PatchFormat::Unified => &["patch", "diff"],
+    };
+    let mut expanded = Vec::new();
+    for file in files {
+        if !Path::new(&file).is_dir() {
+            expanded.push(file);
+            continue;
+        }
+        let mut entries = std::fs::read_dir(&file)?
+            .map(|x| x.map(|x| x.path()))
+            .collect::<std::io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|x| x.is_file() && x.extension().is_some_and(|x| extensions.iter().any(|e| x == *e)))
+            .map(|x| x.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return Err(anyhow::anyhow!("no *.{} patches in {}", extensions.join(", *."), file));
+        }
+        entries.sort();
+        expanded.extend(entries);
+    }
+    Ok(expanded)
+}
+
+/// Prints what applying `patch` would change, per file.
+fn print_apply_summary(patch: &Patch, mismatched: &[String]) {
+    let mut paths = patch.files.keys().chain(patch.deleted.iter()).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    for path in paths {
+        if patch.deleted.contains(path) {
+            println!("would delete {}", path);
+            continue;
+        }
+        let patch_file = &patch.files[path];
+        let action = if patch.added.contains(path) {
+            "add".to_owned()
+        } else if let Some(old) = patch.renamed.get(path) {
+            format!("rename {} to", old)
+        } else {
+            "modify".to_owned()
+        };
+        let changes = if patch_file.symlink.is_some() {
+            "symlink".to_owned()
+        } else if patch_file.binary.is_some() {
+            "binary".to_owned()
+        } else {
+            let (added, removed) = patch_file.line_counts();
+            format!("+{} -{}", added, removed)
+        };
+        let pre_hash = if patch_file.pre_hash.is_empty() {
+            "no pre hash"
+        } else if mismatched.contains(path) {
+            "pre hash mismatch"
+        } else {
+            "pre hash matches"
+        };
+        println!("would {} {} ({}, {})", action, path, changes, pre_hash);
+    }
+    println!(
+        "{} of {} files do not match the pre hash",
+        mismatched.len(),
+        patch.files.len()
+    );
+}
+
+/// Reads a patch from a file or from stdin if `file` is `-`.
+fn read_patch(file: &str, format: PatchFormat) -> Result<Patch> {
+    qop::patch::parse_patch(&read_input(file)?, format)
+}
+
+/// Reads a file, `-` reads stdin.
+fn read_input(file: &str) -> Result<String> {
+    if file == "-" {
+        let mut s = String::new();
+        std::io::stdin().read_to_string(&mut s)?;
+        return Ok(s);
+    }
+    std::fs::read_to_string(file).with_context(|| format!("failed to read {}", file))
+}
+
+/// Writes to a file, `-` writes to stdout.
+fn write_output(file: &str, content: &str) -> Result<()> {
+    if file == "-" {
+        print!("{}", content);
+        return Ok(());
+    }
+    std::fs::write(file, content).with_context(|| format!("failed to write {}", file))
+}
+
+/// Prints the problems of a patch and exits with 1 if it has any. With `fix`,
+/// the hunks are sorted first and the patch is written back.
+fn validate(file: &str, format: PatchFormat, fix: bool) -> Result<()> {
+    let mut patch = read_patch(file, format)?;
+    if fix {
+        qop::patch::sort_hunks(&mut patch);
+        let content = qop::patch::write_patch(&patch, format)?;
+        if file == "-" {
+            println!("{}", content);
+        } else {
+            std::fs::write(file, content).with_context(|| format!("failed to write {}", file))?;
+        }
+    }
+    let problems = qop::patch::validate_patch(&patch);
+    if problems.is_empty() {
+        // stdout holds the patch when it is fixed from stdin
+        if fix && file == "-" {
+            log::info!("OK");
+        } else {
+            println!("OK");
         }
+        return Ok(());
+    }
+    for problem in &problems {
+        log::error!("{}", problem);
+    }
+    std::process::exit(1);
+}
 
-        std::fs::write(&patch_file.0, file_new.join("\n"))?;
+/// Lists all checkpoints from the oldest to the newest.
+fn log(qop_dir: &QopDir, json: bool) -> Result<()> {
+    let entries = qop::store::checkpoints(qop_dir)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
     }
 
+    let name_width = entries.iter().map(|x| x.name.len()).max().unwrap_or(0).max("NAME".len());
+    let author_width =
+        entries.iter().map(|x| x.author.as_deref().unwrap_or("").len()).max().unwrap_or(0).max("AUTHOR".len());
+    println!(
+        "  {:<name_width$}  {:<20}  {:<5}  {:<author_width$}  MESSAGE",
+        "NAME", "INSTANT", "FILES", "AUTHOR"
+    );
+    for entry in entries {
+        // only the first line of a message fits into the table
+        let message = entry.message.as_deref().and_then(|x| x.lines().next()).unwrap_or("");
+        let line = format!(
+            "{} {:<name_width$}  {:<20}  {:<5}  {:<author_width$}  {}",
+            if entry.latest { "*" } else { " " },
+            entry.name,
+            entry.instant.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            entry.files,
+            entry.author.as_deref().unwrap_or(""),
+            message
+        );
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// Prints the change timeline of a file, from the oldest checkpoint to the
+/// working copy.
+fn blame(qop_dir: &QopDir, path: &str, json: bool) -> Result<()> {
+    let blame = qop::store::blame(qop_dir, path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&blame)?);
+        return Ok(());
+    }
+
+    let name_width = blame.checkpoints.iter().map(|x| x.name.len()).max().unwrap_or(0).max("working copy".len());
+    println!("  {:<name_width$}  {:<20}  CHANGE", "NAME", "INSTANT");
+    for entry in &blame.checkpoints {
+        println!(
+            "{} {:<name_width$}  {:<20}  {}",
+            if entry.latest { "*" } else { " " },
+            entry.name,
+            entry.instant.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            entry.change.as_str()
+        );
+    }
+    println!(
+        "  {:<name_width$}  {:<20}  {}",
+        "working copy",
+        "",
+        blame.working_copy.as_str()
+    );
     Ok(())
 }
 
-async fn reverse(file: String) -> Result<()> {
-    let mut patch = if file == "-" {
-        toml::from_str::<Patch>(&{
-            let mut s = String::new();
-            std::io::stdin().read_to_string(&mut s)?;
-            s
-        })?
+/// Prints the size of the stored content per checkpoint and the totals. A
+/// checkpoint's exclusive size is what pruning only it would reclaim.
+fn du(qop_dir: &QopDir, bytes: bool) -> Result<()> {
+    let usage = qop::store::disk_usage(qop_dir)?;
+    let size = |x: u64| if bytes { x.to_string() } else { format_size(x) };
+    let name_width = usage.checkpoints.iter().map(|x| x.name.len()).max().unwrap_or(0).max("NAME".len());
+    let sizes = usage.checkpoints.iter().map(|x| (size(x.size), size(x.exclusive))).collect::<Vec<_>>();
+    let size_width = sizes.iter().map(|x| x.0.len()).max().unwrap_or(0).max("SIZE".len());
+    println!("{:<name_width$}  {:>size_width$}  EXCLUSIVE", "NAME", "SIZE");
+    for (checkpoint, (total, exclusive)) in usage.checkpoints.iter().zip(&sizes) {
+        println!(
+            "{:<name_width$}  {:>size_width$}  {}",
+            checkpoint.name, total, exclusive
+        );
+    }
+    println!();
+    println!("stored content: {}", size(usage.content));
+    println!("orphaned:       {}", size(usage.orphaned));
+    println!("total:          {}", size(usage.total));
+    Ok(())
+}
+
+/// Formats a number of bytes with a binary unit.
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
     } else {
-        toml::from_str::<Patch>(&std::fs::read_to_string(file)?)?
-    };
+        format!("{:.1} {}", size, units[unit])
+    }
+}
 
-    for patch_file in &mut patch.files {
-        for hunk in patch_file.1.hunks.iter_mut() {
-            let mut diff = Vec::<String>::new();
-            for c in hunk.diff.lines() {
-                match c.chars().next() {
-                    | Some('+') => {
-                        diff.push(format!("-{}", &c[1..]));
-                    },
-                    | Some('-') => {
-                        diff.push(format!("+{}", &c[1..]));
-                    },
-                    | _ => {
-                        diff.push(c.to_owned());
-                    },
-                }
+/// Lists modified, deleted and untracked files compared to the latest
+/// checkpoint.
+fn status(qop_dir: &QopDir, short: bool) -> Result<()> {
+    let status = qop::store::status(qop_dir)?;
+    let groups = [
+        ("M", "modified", status.modified),
+        ("D", "deleted", status.deleted),
+        ("?", "untracked", status.untracked),
+    ];
+    if short {
+        for (code, _, paths) in &groups {
+            for path in paths {
+                println!("{} {}", code, path);
             }
-            hunk.diff = diff.join("\n");
-            std::mem::swap(&mut hunk.new_range, &mut hunk.old_range);
+        }
+        return Ok(());
+    }
+    if groups.iter().all(|x| x.2.is_empty()) {
+        println!("nothing changed since the latest checkpoint");
+        return Ok(());
+    }
+    for (_, name, paths) in &groups {
+        if paths.is_empty() {
+            continue;
+        }
+        println!("{}:", name);
+        for path in paths {
+            println!("    {}", path);
         }
     }
+    Ok(())
+}
 
-    println!("{}", toml::to_string(&patch)?);
+/// A file of the latest checkpoint as listed by `ls-files --json`.
+#[derive(Debug, serde::Serialize)]
+struct TrackedFile<'a> {
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<&'a str>,
+}
+
+/// Prints the paths of the latest checkpoint, one per line. Only the index is
+/// read, the working copy is not looked at.
+fn ls_files(qop_dir: &QopDir, paths: &PathFilter, with_hash: bool, json: bool) -> Result<()> {
+    let index = qop::store::read_index(qop_dir)?;
+    let files = index
+        .files
+        .iter()
+        .filter(|(path, _)| paths.matches(path))
+        .map(|(path, file)| {
+            TrackedFile {
+                path,
+                hash: with_hash.then_some(file.hash.as_str()),
+            }
+        })
+        .collect::<Vec<_>>();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&files)?);
+        return Ok(());
+    }
+    for file in files {
+        match file.hash {
+            | Some(hash) => println!("{}  {}", file.path, hash),
+            | None => println!("{}", file.path),
+        }
+    }
     Ok(())
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct QopFile {
-    pub ignore: Vec<String>,
+/// Prints the path and hash of every file of the latest checkpoint in the
+/// format of a checksum tool. Symlinks are left out, the tools hash their
+/// target while the index hashes the link.
+fn manifest(qop_dir: &QopDir, format: crate::args::ManifestFormat) -> Result<()> {
+    let index = qop::store::read_index(qop_dir)?;
+    let tool = match (format, index.hash) {
+        | (crate::args::ManifestFormat::Sha256sum, qop::hash::HashAlgorithm::Blake3) => Some("b3sum"),
+        | (crate::args::ManifestFormat::B3sum, qop::hash::HashAlgorithm::Sha256) => Some("sha256sum"),
+        | _ => None,
+    };
+    if let Some(tool) = tool {
+        return Err(anyhow::anyhow!(
+            "the store uses {} hashes, use --format {} or bsd",
+            index.hash.as_str(),
+            tool
+        ));
+    }
+    let tag = match index.hash {
+        | qop::hash::HashAlgorithm::Sha256 => "SHA256",
+        | qop::hash::HashAlgorithm::Blake3 => "BLAKE3",
+    };
+    for (path, file) in &index.files {
+        if file.symlink {
+            log::warn!("left out the symlink {}", path);
+            continue;
+        }
+        match format {
+            | crate::args::ManifestFormat::Bsd => println!("{} ({}) = {}", tag, path, file.hash),
+            // like the tools, names with a backslash or newline are escaped and
+            // the line is marked with a leading backslash
+            | _ if path.contains(['\\', '\n']) => {
+                println!("\\{}  {}", file.hash, path.replace('\\', "\\\\").replace('\n', "\\n"))
+            },
+            | _ => println!("{}  {}", file.hash, path),
+        }
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Index {
-    pub latest: Option<String>,
-    pub entries: HashMap<String, IndexEntry>,
-    pub files: HashMap<String, String>,
+/// Prints the hash of every file, symlinks are hashed by their target like in
+/// the index.
+fn hash(qop_dir: &QopDir, files: &[String], algorithm: Option<qop::hash::HashAlgorithm>) -> Result<()> {
+    let algorithm = match algorithm {
+        | Some(v) => v,
+        | None if qop_dir.index().exists() => qop::store::read_index(qop_dir)?.hash,
+        | None => qop::hash::HashAlgorithm::default(),
+    };
+    for file in files {
+        let hash = qop::hash::hash_file(algorithm, file).with_context(|| format!("failed to hash {}", file))?;
+        println!("{}  {}", hash, file);
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct IndexEntry {
-    pub instant: DateTime<Utc>,
+/// Lists the problems found in the store. Returns whether there are none.
+fn fsck(qop_dir: &QopDir) -> Result<bool> {
+    let report = qop::store::fsck(qop_dir)?;
+    let orphaned = report.orphaned.iter().map(|x| x.display().to_string()).collect::<Vec<_>>();
+    let groups = [
+        ("missing", &report.missing),
+        ("corrupt", &report.corrupt),
+        ("orphaned", &orphaned),
+    ];
+    for (name, paths) in groups {
+        if paths.is_empty() {
+            continue;
+        }
+        println!("{}:", name);
+        for path in paths {
+            println!("    {}", path);
+        }
+    }
+    log::info!(
+        "checked {} stored files, {} missing, {} corrupt, {} orphaned",
+        report.checked,
+        report.missing.len(),
+        report.corrupt.len(),
+        report.orphaned.len()
+    );
+    Ok(report.is_ok())
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct Patch {
-    pub files: HashMap<String, PatchFile>,
+/// Prints the problems of the qop directory with their fixes. Returns whether
+/// there are none.
+fn doctor(qop_dir: &QopDir) -> Result<bool> {
+    let problems = qop::store::doctor(qop_dir)?;
+    for problem in &problems {
+        println!("{}", problem.problem);
+        println!("    fix: {}", problem.fix);
+    }
+    if problems.is_empty() {
+        log::info!("no problems found");
+    } else {
+        log::info!("found {} problems", problems.len());
+    }
+    Ok(problems.is_empty())
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct PatchFile {
-    pub pre_hash: String,
-    pub post_hash: String,
-    pub hunks: Vec<PatchFileHunk>,
+/// Resets the working copy to the state of a checkpoint.
+fn restore(qop_dir: &QopDir, checkpoint: String, dry_run: bool, force: bool) -> Result<()> {
+    let plan = qop::store::restore(qop_dir, &checkpoint, dry_run, force)?;
+    let prefix = if dry_run { "would " } else { "" };
+    // a dry run prints what it would do, a real run logs it
+    if dry_run {
+        for path in &plan.restored {
+            println!("would restore {}", path);
+        }
+        for path in &plan.removed {
+            println!("would remove {}", path);
+        }
+        for path in &plan.local_changes {
+            println!("would lose local changes to {}", path);
+        }
+    }
+    let summary = format!(
+        "{}restore {} files, {}remove {} files",
+        prefix,
+        plan.restored.len(),
+        prefix,
+        plan.removed.len()
+    );
+    if dry_run {
+        println!("{}", summary);
+    } else {
+        log::info!("{}", summary);
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct PatchFileHunk {
-    pub old_range: (usize, usize),
-    pub new_range: (usize, usize),
-    pub diff: String,
+fn reverse(qop_dir: &QopDir, file: String, format: PatchFormat, output: &str) -> Result<()> {
+    let patch = qop::patch::reverse_patch(qop_dir, read_patch(&file, format)?)?;
+    write_output(output, &format!("{}\n", qop::patch::write_patch(&patch, format)?))
 }
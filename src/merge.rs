@@ -0,0 +1,109 @@
+/// A contiguous change of one side of a three-way merge. `start..end` is the
+/// replaced range of the base, `new_start..new_end` the replacement in the
+/// side.
+struct MergeChange {
+    start: usize,
+    end: usize,
+    new_start: usize,
+    new_end: usize,
+}
+
+fn merge_changes(base: &[String], side: &[String]) -> Vec<MergeChange> {
+    let mut changes = Vec::<MergeChange>::new();
+    let mut extend_last = false;
+    for op in similar::capture_diff_slices(similar::Algorithm::Myers, base, side) {
+        if op.tag() == similar::DiffTag::Equal {
+            extend_last = false;
+            continue;
+        }
+        match changes.last_mut() {
+            | Some(last) if extend_last => {
+                last.end = op.old_range().end;
+                last.new_end = op.new_range().end;
+            },
+            | _ => {
+                changes.push(MergeChange {
+                    start: op.old_range().start,
+                    end: op.old_range().end,
+                    new_start: op.new_range().start,
+                    new_end: op.new_range().end,
+                });
+            },
+        }
+        extend_last = true;
+    }
+    changes
+}
+
+/// Content of `side` for the base range `start..end`, given the side's changes
+/// within that range.
+fn merge_side(base: &[String], side: &[String], changes: &[MergeChange], start: usize, end: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pos = start;
+    for c in changes {
+        lines.extend_from_slice(&base[pos..c.start]);
+        lines.extend_from_slice(&side[c.new_start..c.new_end]);
+        pos = c.end;
+    }
+    lines.extend_from_slice(&base[pos..end]);
+    lines
+}
+
+/// Line based three-way merge. Changes of only one side are taken as they are,
+/// overlapping changes of both sides are wrapped in conflict markers. Returns
+/// the merged lines and whether there were conflicts.
+pub(crate) fn merge3(base: &[String], ours: &[String], theirs: &[String]) -> (Vec<String>, bool) {
+    let ours_changes = merge_changes(base, ours);
+    let theirs_changes = merge_changes(base, theirs);
+
+    let mut merged = Vec::new();
+    let mut conflict = false;
+    let (mut i, mut j, mut pos) = (0_usize, 0_usize, 0_usize);
+    while i < ours_changes.len() || j < theirs_changes.len() {
+        let start = match (ours_changes.get(i), theirs_changes.get(j)) {
+            | (Some(o), Some(t)) => o.start.min(t.start),
+            | (Some(o), None) => o.start,
+            | (None, Some(t)) => t.start,
+            | (None, None) => unreachable!(),
+        };
+        merged.extend_from_slice(&base[pos..start]);
+
+        // grow the region as long as changes of either side overlap with it
+        let (i0, j0) = (i, j);
+        let mut end = start;
+        loop {
+            let mut grown = false;
+            if let Some(c) = ours_changes.get(i).filter(|c| c.start == start || c.start < end) {
+                end = end.max(c.end);
+                i += 1;
+                grown = true;
+            }
+            if let Some(c) = theirs_changes.get(j).filter(|c| c.start == start || c.start < end) {
+                end = end.max(c.end);
+                j += 1;
+                grown = true;
+            }
+            if !grown {
+                break;
+            }
+        }
+
+        let ours_lines = merge_side(base, ours, &ours_changes[i0..i], start, end);
+        let theirs_lines = merge_side(base, theirs, &theirs_changes[j0..j], start, end);
+        if i0 == i {
+            merged.extend(theirs_lines);
+        } else if j0 == j || ours_lines == theirs_lines {
+            merged.extend(ours_lines);
+        } else {
+            conflict = true;
+            merged.push("<<<<<<< working copy".to_owned());
+            merged.extend(ours_lines);
+            merged.push("=======".to_owned());
+            merged.extend(theirs_lines);
+            merged.push(">>>>>>> patch".to_owned());
+        }
+        pos = end;
+    }
+    merged.extend_from_slice(&base[pos..]);
+    (merged, conflict)
+}
@@ -0,0 +1,968 @@
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{
+    Context,
+    Result,
+};
+
+use crate::{
+    diff::{
+        diff_paths,
+        DiffAlgorithm,
+        DiffSide,
+        TextOptions,
+        Whitespace,
+    },
+    hash::HashAlgorithm,
+    store::{
+        read_index,
+        read_stored,
+        Index,
+        QopDir,
+    },
+    walk::{
+        normalize_key,
+        PathFilter,
+    },
+};
+
+/// Version of the patch format written by this version of qop. Patches
+/// without a version are version 0, they only lack fields that are optional
+/// in version 1 and are read as such. Version 2 added the comments of files
+/// and hunks.
+pub const PATCH_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub enum LineEndings {
+    Preserve,
+    Lf,
+    Crlf,
+}
+
+impl FromStr for LineEndings {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            | "preserve" => Ok(Self::Preserve),
+            | "lf" => Ok(Self::Lf),
+            | "crlf" => Ok(Self::Crlf),
+            | _ => Err(anyhow::anyhow!("unknown line endings")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PatchFormat {
+    Toml,
+    Json,
+    Unified,
+}
+
+impl FromStr for PatchFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            | "toml" => Ok(Self::Toml),
+            | "json" => Ok(Self::Json),
+            | "unified" => Ok(Self::Unified),
+            | _ => Err(anyhow::anyhow!("unknown patch format")),
+        }
+    }
+}
+
+/// Parses a patch in the given format. Paths written by an older version with
+/// Windows separators are converted to `/`, patches without a version are
+/// upgraded to `PATCH_VERSION` and newer versions are refused. Line endings of
+/// the patch file itself, like CRLF after saving it on Windows, are removed
+/// from the hunks; the endings of the patched files are recorded separately.
+pub fn parse_patch(content: &str, format: PatchFormat) -> Result<Patch> {
+    let mut patch = match format {
+        | PatchFormat::Toml => toml::from_str::<Patch>(content)?,
+        | PatchFormat::Json => serde_json::from_str::<Patch>(content)?,
+        | PatchFormat::Unified => return parse_unified(content),
+    };
+    if patch.version > PATCH_VERSION {
+        return Err(anyhow::anyhow!(
+            "the patch has format version {}, this qop version reads up to {}",
+            patch.version,
+            PATCH_VERSION
+        ));
+    }
+    patch.version = PATCH_VERSION;
+    for hunk in patch.files.values_mut().flat_map(|x| x.hunks.iter_mut()) {
+        if hunk.diff.contains('\r') {
+            hunk.diff = hunk.diff.replace("\r\n", "\n");
+        }
+    }
+    patch.files = std::mem::take(&mut patch.files).into_iter().map(|(k, v)| (normalize_key(k), v)).collect();
+    patch.deleted = std::mem::take(&mut patch.deleted).into_iter().map(normalize_key).collect();
+    patch.deleted_hashes =
+        std::mem::take(&mut patch.deleted_hashes).into_iter().map(|(k, v)| (normalize_key(k), v)).collect();
+    patch.added = std::mem::take(&mut patch.added).into_iter().map(normalize_key).collect();
+    patch.renamed =
+        std::mem::take(&mut patch.renamed).into_iter().map(|(k, v)| (normalize_key(k), normalize_key(v))).collect();
+    Ok(patch)
+}
+
+/// Serializes a patch. The unified format is lossy and only rendered by
+/// `render_unified`.
+pub fn write_patch(patch: &Patch, format: PatchFormat) -> Result<String> {
+    match format {
+        | PatchFormat::Toml => Ok(toml::to_string(patch)?),
+        | PatchFormat::Json => Ok(serde_json::to_string_pretty(patch)?),
+        | PatchFormat::Unified => Err(anyhow::anyhow!("patches can not be written in the unified format")),
+    }
+}
+
+/// Parses a unified diff as produced by `diff -u` or `git diff`. The
+/// resulting patch has no hashes since the format does not carry any.
+pub fn parse_unified(input: &str) -> Result<Patch> {
+    fn parse_path(line: &str) -> Option<String> {
+        // strip timestamps as written by `diff -u`
+        let path = line.split('\t').next().unwrap_or(line).trim_end();
+        if path == "/dev/null" {
+            return None;
+        }
+        let path = path.strip_prefix("a/").or_else(|| path.strip_prefix("b/")).unwrap_or(path);
+        Some(format!("./{}", path.strip_prefix("./").unwrap_or(path)))
+    }
+    fn parse_range(range: &str, line_no: usize) -> Result<(usize, usize)> {
+        let (start, len) = match range.split_once(',') {
+            | Some((start, len)) => (start, len),
+            | None => (range, "1"),
+        };
+        let invalid = || anyhow::anyhow!("line {}: invalid hunk range: {}", line_no, range);
+        let start = start.parse::<usize>().map_err(|_| invalid())?;
+        let len = len.parse::<usize>().map_err(|_| invalid())?;
+        // empty ranges refer to the line before them
+        let start = if len == 0 {
+            start
+        } else {
+            start.checked_sub(1).ok_or_else(invalid)?
+        };
+        Ok((start, start + len))
+    }
+
+    let mut patch = Patch {
+        version: PATCH_VERSION,
+        hash: HashAlgorithm::default(),
+        files: BTreeMap::new(),
+        deleted: Vec::new(),
+        deleted_hashes: BTreeMap::new(),
+        added: Vec::new(),
+        renamed: BTreeMap::new(),
+    };
+    let empty_file = || {
+        PatchFile {
+            pre_hash: String::new(),
+            post_hash: String::new(),
+            hunks: Vec::new(),
+            line_ending: None,
+            binary: None,
+            symlink: None,
+            pre_mode: None,
+            post_mode: None,
+            pre_mtime: None,
+            post_mtime: None,
+            comment: None,
+        }
+    };
+    let lines = input.lines().collect::<Vec<_>>();
+    let mut i = 0;
+    while i < lines.len() {
+        // git writes renames as extended header lines, a pure rename has no
+        // hunks that follow
+        if let Some(from) = lines[i].strip_prefix("rename from ") {
+            let to = lines
+                .get(i + 1)
+                .and_then(|x| x.strip_prefix("rename to "))
+                .ok_or_else(|| anyhow::anyhow!("line {}: expected rename to after rename from", i + 2))?;
+            // the paths of these lines have no a/ and b/ prefixes
+            let [from, to] = [from, to].map(|x| format!("./{}", x.strip_prefix("./").unwrap_or(x)));
+            patch.files.insert(to.clone(), empty_file());
+            patch.renamed.insert(to, from);
+            i += 2;
+            continue;
+        }
+        let Some(old_path) = lines[i].strip_prefix("--- ") else {
+            if lines[i].starts_with("Binary files ") {
+                return Err(anyhow::anyhow!("line {}: binary patches are not supported", i + 1));
+            }
+            if lines[i].starts_with("Symbolic links ") {
+                return Err(anyhow::anyhow!("line {}: symlink patches are not supported", i + 1));
+            }
+            i += 1;
+            continue;
+        };
+        let new_path = lines
+            .get(i + 1)
+            .and_then(|x| x.strip_prefix("+++ "))
+            .ok_or_else(|| anyhow::anyhow!("line {}: expected +++ after ---", i + 2))?;
+        let (old_path, new_path) = (parse_path(old_path), parse_path(new_path));
+        i += 2;
+
+        let path = match (&old_path, &new_path) {
+            | (_, Some(v)) => v.clone(),
+            | (Some(v), None) => {
+                patch.deleted.push(v.clone());
+                v.clone()
+            },
+            | (None, None) => return Err(anyhow::anyhow!("line {}: both paths are /dev/null", i - 1)),
+        };
+        if old_path.is_none() {
+            patch.added.push(path.clone());
+        }
+
+        let mut hunks = Vec::<PatchFileHunk>::new();
+        while let Some(header) = lines.get(i).and_then(|x| x.strip_prefix("@@ ")) {
+            let line_no = i + 1;
+            let ranges = header
+                .split_once(" @@")
+                .map(|x| x.0)
+                .ok_or_else(|| anyhow::anyhow!("line {}: malformed hunk header", line_no))?;
+            let (old, new) = match ranges.split_once(' ') {
+                | Some((old, new)) if old.starts_with('-') && new.starts_with('+') => {
+                    (parse_range(&old[1..], line_no)?, parse_range(&new[1..], line_no)?)
+                },
+                | _ => return Err(anyhow::anyhow!("line {}: malformed hunk header", line_no)),
+            };
+            i += 1;
+
+            let (mut old_pos, mut new_pos) = (old.0, new.0);
+            let mut hunk = PatchFileHunk {
+                old_range: old,
+                new_range: new,
+                diff: String::new(),
+                old_no_newline: false,
+                new_no_newline: false,
+                comment: None,
+            };
+            let mut last_tag = ' ';
+            while old_pos < old.1 || new_pos < new.1 || lines.get(i).is_some_and(|x| x.starts_with('\\')) {
+                let line = lines.get(i).ok_or_else(|| {
+                    anyhow::anyhow!("line {}: unexpected end of hunk started at line {}", i + 1, line_no)
+                })?;
+                // some editors strip the trailing space of empty context lines
+                let tag = line.chars().next().unwrap_or(' ');
+                let value = line.get(1..).unwrap_or("");
+                match tag {
+                    | ' ' | '-' | '+' => {
+                        hunk.diff.push_str(&format!("{}{}\n", tag, value));
+                        if tag != '+' {
+                            old_pos += 1;
+                        }
+                        if tag != '-' {
+                            new_pos += 1;
+                        }
+                    },
+                    | '\\' => {
+                        // `\ No newline at end of file` refers to the previous line
+                        if last_tag != '+' {
+                            hunk.old_no_newline = true;
+                        }
+                        if last_tag != '-' {
+                            hunk.new_no_newline = true;
+                        }
+                    },
+                    | _ => return Err(anyhow::anyhow!("line {}: unexpected line in hunk: {}", i + 1, line)),
+                }
+                if old_pos > old.1 || new_pos > new.1 {
+                    return Err(anyhow::anyhow!(
+                        "line {}: hunk started at line {} is longer than its header states",
+                        i + 1,
+                        line_no
+                    ));
+                }
+                if tag != '\\' {
+                    last_tag = tag;
+                }
+                i += 1;
+            }
+            hunks.push(hunk);
+        }
+
+        if new_path.is_some() {
+            patch.files.insert(path, PatchFile { hunks, ..empty_file() });
+        }
+    }
+    Ok(patch)
+}
+
+/// Renders a patch in the unified diff format understood by `patch(1)`.
+/// `deleted` holds full deletion hunks for the files in `Patch::deleted`.
+pub fn render_unified(patch: &Patch, deleted: &BTreeMap<String, PatchFile>) -> String {
+    let mut out = String::new();
+    let mut paths = patch.files.keys().chain(deleted.keys()).collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    for path in paths {
+        let name = path.strip_prefix("./").unwrap_or(path);
+        let (patch_file, old_name, new_name) = if let Some(v) = deleted.get(path) {
+            (v, format!("a/{}", name), "/dev/null".to_owned())
+        } else if patch.added.contains(path) {
+            (&patch.files[path], "/dev/null".to_owned(), format!("b/{}", name))
+        } else if let Some(old) = patch.renamed.get(path) {
+            let old = old.strip_prefix("./").unwrap_or(old);
+            out.push_str(&format!(
+                "diff --git a/{} b/{}\nrename from {}\nrename to {}\n",
+                old, name, old, name
+            ));
+            (&patch.files[path], format!("a/{}", old), format!("b/{}", name))
+        } else {
+            (&patch.files[path], format!("a/{}", name), format!("b/{}", name))
+        };
+
+        if patch_file.symlink.is_some() {
+            out.push_str(&format!("Symbolic links {} and {} differ\n", old_name, new_name));
+            continue;
+        }
+        if patch_file.binary.is_some() {
+            out.push_str(&format!("Binary files {} and {} differ\n", old_name, new_name));
+            continue;
+        }
+        if patch_file.hunks.is_empty() {
+            continue;
+        }
+        out.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+        for hunk in &patch_file.hunks {
+            out.push_str(&render_hunk(hunk));
+        }
+    }
+    out
+}
+
+/// Renders a single hunk with its header in the unified diff format.
+pub fn render_hunk(hunk: &PatchFileHunk) -> String {
+    let mut out = format!("{}\n", hunk_header(hunk));
+    let lines = hunk.diff.lines().collect::<Vec<_>>();
+    let last_old = lines.iter().rposition(|x| x.starts_with([' ', '-']));
+    let last_new = lines.iter().rposition(|x| x.starts_with([' ', '+']));
+    for (i, line) in lines.iter().enumerate() {
+        out.push_str(line);
+        out.push('\n');
+        if (hunk.old_no_newline && last_old == Some(i)) || (hunk.new_no_newline && last_new == Some(i)) {
+            out.push_str("\\ No newline at end of file\n");
+        }
+    }
+    out
+}
+
+/// Formats a zero based, end exclusive line range as `start,length` with a one
+/// based start. Empty ranges refer to the line before them.
+pub fn unified_range(range: (usize, usize)) -> String {
+    let len = range.1 - range.0;
+    if len == 0 {
+        format!("{},0", range.0)
+    } else {
+        format!("{},{}", range.0 + 1, len)
+    }
+}
+
+/// Inverts a patch so that it undoes its changes. Files deleted by the patch
+/// are recreated from the newest checkpoint that contains them.
+pub fn reverse_patch(qop_dir: &QopDir, mut patch: Patch) -> Result<Patch> {
+    for patch_file in &mut patch.files {
+        std::mem::swap(&mut patch_file.1.pre_hash, &mut patch_file.1.post_hash);
+        std::mem::swap(&mut patch_file.1.pre_mode, &mut patch_file.1.post_mode);
+        std::mem::swap(&mut patch_file.1.pre_mtime, &mut patch_file.1.post_mtime);
+        if let Some(symlink) = &mut patch_file.1.symlink {
+            std::mem::swap(&mut symlink.pre, &mut symlink.post);
+        }
+        if let Some(binary) = &mut patch_file.1.binary {
+            std::mem::swap(&mut binary.pre, &mut binary.post);
+        }
+        for hunk in patch_file.1.hunks.iter_mut() {
+            let mut diff = Vec::<String>::new();
+            for c in hunk.diff.lines() {
+                match c.chars().next() {
+                    | Some('+') => {
+                        diff.push(format!("-{}", &c[1..]));
+                    },
+                    | Some('-') => {
+                        diff.push(format!("+{}", &c[1..]));
+                    },
+                    | _ => {
+                        diff.push(c.to_owned());
+                    },
+                }
+            }
+            hunk.diff = diff.join("\n");
+            std::mem::swap(&mut hunk.new_range, &mut hunk.old_range);
+            std::mem::swap(&mut hunk.new_no_newline, &mut hunk.old_no_newline);
+        }
+    }
+
+    // a reversed rename moves the file back, its changes are then relative to
+    // the new path
+    for (new, old) in std::mem::take(&mut patch.renamed) {
+        if let Some(patch_file) = patch.files.remove(&new) {
+            patch.files.insert(old.clone(), patch_file);
+        }
+        patch.renamed.insert(old, new);
+    }
+
+    // a reversed addition deletes the file again, as long as it still has the
+    // content the patch added
+    let added = std::mem::take(&mut patch.added);
+    patch.deleted_hashes.clear();
+    for path in &added {
+        if let Some(patch_file) = patch.files.remove(path).filter(|x| !x.pre_hash.is_empty()) {
+            patch.deleted_hashes.insert(path.clone(), patch_file.pre_hash);
+        }
+    }
+    // a reversed deletion recreates the file from the content in the store
+    let deleted = std::mem::replace(&mut patch.deleted, added);
+    let index = if deleted.is_empty() {
+        Index::default()
+    } else {
+        read_index(qop_dir)?
+    };
+    if !deleted.is_empty() {
+        index.require_content("recreating the deleted files")?;
+    }
+    let mut checkpoints = index.entries.iter().collect::<Vec<_>>();
+    checkpoints.sort_by(|a, b| b.1.instant.cmp(&a.1.instant).then_with(|| b.0.cmp(a.0)));
+    for path in deleted {
+        let file = match index.files.get(&path) {
+            | Some(v) => v,
+            | None => {
+                checkpoints
+                    .iter()
+                    .find_map(|x| x.1.files.get(&path))
+                    .ok_or_else(|| anyhow::anyhow!("can not recreate {}, it is in no checkpoint", path))?
+            },
+        };
+        let content = read_stored(qop_dir, index.compression, &file.hash)
+            .with_context(|| format!("failed to read the stored content of {}", path))?;
+        patch.files.insert(
+            path.clone(),
+            diff_paths(
+                patch.hash,
+                DiffSide::Missing,
+                DiffSide::Stored(&content, file),
+                TextOptions {
+                    line_endings: LineEndings::Preserve,
+                    context: 0,
+                    algorithm: DiffAlgorithm::default(),
+                    whitespace: Whitespace::Exact,
+                },
+            )?,
+        );
+        patch.added.push(path);
+    }
+    Ok(patch)
+}
+
+/// Drops the changes of all paths that are not selected by the filter. A
+/// rename is kept if either of its paths is selected.
+pub fn retain_paths(patch: &mut Patch, filter: &PathFilter) {
+    patch.renamed.retain(|new, old| filter.matches(new) || filter.matches(old));
+    let renamed = &patch.renamed;
+    patch.files.retain(|path, _| filter.matches(path) || renamed.contains_key(path));
+    patch.deleted.retain(|path| filter.matches(path));
+    patch.deleted_hashes.retain(|path, _| filter.matches(path));
+    patch.added.retain(|path| filter.matches(path));
+}
+
+/// The kind of changes `filter_changes` keeps.
+#[derive(Debug, Clone, Copy)]
+pub enum ChangeFilter {
+    /// Hunks that only insert lines and added files.
+    Additions,
+    /// Hunks that only delete lines and deleted files.
+    Deletions,
+}
+
+/// A change that `filter_changes` left out.
+#[derive(Debug, Clone)]
+pub struct SkippedChange {
+    pub path: String,
+    /// Number of left out hunks, `None` if all changes of the file were left
+    /// out.
+    pub hunks: Option<usize>,
+}
+
+/// Drops every change that is not of the kind of the filter. Hunks that both
+/// insert and delete lines are dropped under either filter, like binary and
+/// symlink changes, permission changes and renames, which change no lines.
+/// Returns the dropped changes sorted by path.
+pub fn filter_changes(patch: &mut Patch, filter: ChangeFilter) -> Vec<SkippedChange> {
+    let mut skipped = Vec::new();
+    let additions = matches!(filter, ChangeFilter::Additions);
+    let paths = patch.files.keys().cloned().collect::<Vec<_>>();
+    for path in paths {
+        let file = &patch.files[&path];
+        let added = patch.added.contains(&path);
+        let whole = if added {
+            !additions
+        } else {
+            patch.renamed.contains_key(&path)
+                || file.binary.is_some()
+                || file.symlink.is_some()
+                || file.pre_mode != file.post_mode
+                || file.pre_mtime != file.post_mtime
+        };
+        if whole {
+            patch.files.remove(&path);
+            patch.added.retain(|x| *x != path);
+            patch.renamed.remove(&path);
+            skipped.push(SkippedChange { path, hunks: None });
+            continue;
+        }
+        let unwanted = if additions { '-' } else { '+' };
+        let positions = file
+            .hunks
+            .iter()
+            .enumerate()
+            .filter(|(_, hunk)| hunk.diff.lines().any(|x| x.starts_with(unwanted)))
+            .map(|(i, _)| i)
+            .collect::<BTreeSet<_>>();
+        if positions.is_empty() {
+            continue;
+        }
+        let hunks = if positions.len() == file.hunks.len() {
+            None
+        } else {
+            Some(positions.len())
+        };
+        remove_hunks(patch, &path, &positions);
+        skipped.push(SkippedChange { path, hunks });
+    }
+    if additions {
+        patch.deleted_hashes.clear();
+        skipped.extend(std::mem::take(&mut patch.deleted).into_iter().map(|path| SkippedChange { path, hunks: None }));
+    }
+    skipped.sort_by(|a, b| a.path.cmp(&b.path));
+    skipped
+}
+
+/// Drops the hunks at the given positions from a file of the patch and shifts
+/// the new ranges of the hunks after them. The result of the remaining hunks is
+/// not known, so the post hash is cleared. Without any hunks left the content
+/// stays as it is, and a file that neither changes its permissions or
+/// modification time nor is renamed is dropped from the patch.
+pub fn remove_hunks(patch: &mut Patch, path: &str, positions: &BTreeSet<usize>) {
+    let Some(file) = patch.files.get_mut(path) else {
+        return;
+    };
+    if positions.is_empty() {
+        return;
+    }
+    // lines the removed hunks would have added minus the ones they removed
+    let mut shift = 0_isize;
+    let hunks = std::mem::take(&mut file.hunks);
+    for (i, mut hunk) in hunks.into_iter().enumerate() {
+        let len = |range: (usize, usize)| range.1 as isize - range.0 as isize;
+        if positions.contains(&i) {
+            shift += len(hunk.new_range) - len(hunk.old_range);
+            continue;
+        }
+        hunk.new_range = (
+            hunk.new_range.0.saturating_add_signed(-shift),
+            hunk.new_range.1.saturating_add_signed(-shift),
+        );
+        file.hunks.push(hunk);
+    }
+    if !file.hunks.is_empty() {
+        file.post_hash.clear();
+        return;
+    }
+    file.post_hash = file.pre_hash.clone();
+    let added = patch.added.iter().any(|x| x == path);
+    if added
+        || (file.pre_mode == file.post_mode && file.pre_mtime == file.post_mtime && !patch.renamed.contains_key(path))
+    {
+        patch.files.remove(path);
+        patch.added.retain(|x| x != path);
+    }
+}
+
+/// A violated invariant of a patch, found by `validate_patch`.
+#[derive(Debug, Clone)]
+pub struct PatchProblem {
+    pub path: String,
+    /// Zero based index of the hunk the problem is in.
+    pub hunk: Option<usize>,
+    /// One based line of the diff of the hunk.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for PatchProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(hunk) = self.hunk {
+            write!(f, ": hunk {}", hunk + 1)?;
+        }
+        if let Some(line) = self.line {
+            write!(f, ", line {}", line)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Checks the structural invariants of a patch that `apply` relies on: hashes
+/// of the patch's algorithm, consistent file lists and for every file hunks
+/// with ordered, non-overlapping ranges that match their diff lines.
+pub fn validate_patch(patch: &Patch) -> Vec<PatchProblem> {
+    let mut problems = Vec::new();
+    let mut problem = |path: &str, hunk: Option<usize>, line: Option<usize>, message: String| {
+        problems.push(PatchProblem {
+            path: path.to_owned(),
+            hunk,
+            line,
+            message,
+        });
+    };
+    let hash_len = crate::hash::hash(patch.hash, []).len();
+    let paths = patch.files.keys().chain(&patch.deleted).chain(&patch.added).chain(patch.renamed.values());
+    for path in paths.collect::<BTreeSet<_>>() {
+        if crate::walk::path_key(Path::new(path)).is_err() {
+            problem(path, None, None, "path is absolute or leaves the directory".to_owned());
+        }
+    }
+    for path in &patch.added {
+        if !patch.files.contains_key(path) {
+            problem(path, None, None, "added file has no entry in files".to_owned());
+        }
+    }
+    for path in &patch.deleted {
+        if patch.files.contains_key(path) {
+            problem(path, None, None, "deleted file has an entry in files".to_owned());
+        }
+    }
+    for (path, old) in &patch.renamed {
+        if !patch.files.contains_key(path) {
+            problem(
+                path,
+                None,
+                None,
+                format!("file renamed from {} has no entry in files", old),
+            );
+        }
+    }
+
+    for (path, file) in &patch.files {
+        for (side, hash) in [("pre", &file.pre_hash), ("post", &file.post_hash)] {
+            if !hash.is_empty() && (hash.len() != hash_len || !hash.bytes().all(|x| x.is_ascii_hexdigit())) {
+                problem(
+                    path,
+                    None,
+                    None,
+                    format!("{} hash is no {} hash: {}", side, patch.hash.as_str(), hash),
+                );
+            }
+        }
+        if !file.hunks.is_empty() && (file.binary.is_some() || file.symlink.is_some()) {
+            problem(path, None, None, "binary or symlink file has hunks".to_owned());
+        }
+        // hunks shift the lines after them by the lines they add or remove
+        let mut offset = 0_isize;
+        let mut previous: Option<&PatchFileHunk> = None;
+        for (n, hunk) in file.hunks.iter().enumerate() {
+            let mut problem = |line: Option<usize>, message: String| problem(path, Some(n), line, message);
+            let (old, new) = (hunk.old_range, hunk.new_range);
+            if old.0 > old.1 || new.0 > new.1 {
+                problem(None, format!("range ends before it starts: {}", hunk_header(hunk)));
+                continue;
+            }
+            if let Some(previous) = previous {
+                if old.0 < previous.old_range.1 || new.0 < previous.new_range.1 {
+                    problem(
+                        None,
+                        format!("overlaps or precedes the previous hunk: {}", hunk_header(hunk)),
+                    );
+                }
+            }
+            if new.0 as isize - old.0 as isize != offset {
+                problem(
+                    None,
+                    format!(
+                        "new range starts at line {} but the previous hunks put it at line {}",
+                        new.0 + 1,
+                        old.0 as isize + offset + 1
+                    ),
+                );
+            }
+            let (mut old_len, mut new_len) = (0, 0);
+            for (i, line) in hunk.diff.lines().enumerate() {
+                match line.chars().next() {
+                    | Some(' ') => {
+                        old_len += 1;
+                        new_len += 1;
+                    },
+                    | Some('-') => old_len += 1,
+                    | Some('+') => new_len += 1,
+                    | _ => {
+                        problem(
+                            Some(i + 1),
+                            format!("line does not start with ' ', '+' or '-': {:?}", line),
+                        )
+                    },
+                }
+            }
+            if old_len != old.1 - old.0 || new_len != new.1 - new.0 {
+                problem(
+                    None,
+                    format!(
+                        "{} spans {} old and {} new lines but its diff has {} and {}",
+                        hunk_header(hunk),
+                        old.1 - old.0,
+                        new.1 - new.0,
+                        old_len,
+                        new_len
+                    ),
+                );
+            }
+            offset += (new.1 - new.0) as isize - (old.1 - old.0) as isize;
+            previous = Some(hunk);
+        }
+    }
+    problems
+}
+
+fn hunk_header(hunk: &PatchFileHunk) -> String {
+    format!(
+        "@@ -{} +{} @@",
+        unified_range(hunk.old_range),
+        unified_range(hunk.new_range)
+    )
+}
+
+/// Sorts the hunks of every file by their position in the old file.
+pub fn sort_hunks(patch: &mut Patch) {
+    for file in patch.files.values_mut() {
+        file.hunks.sort_by_key(|x| (x.old_range, x.new_range));
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Patch {
+    /// Format version, see `PATCH_VERSION`.
+    #[serde(default)]
+    pub version: u32,
+    /// Algorithm of the pre and post hashes.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    pub files: BTreeMap<String, PatchFile>,
+    /// Paths that are removed by this patch.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<String>,
+    /// Hashes of the deleted files before the patch, by path. Patches imported
+    /// from other formats carry none.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deleted_hashes: BTreeMap<String, String>,
+    /// Paths that are created by this patch. Their content is contained in
+    /// `files`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<String>,
+    /// Files that are moved by this patch, from the old path in the value to
+    /// the new path in the key. The entry of the new path in `files` holds the
+    /// changes relative to the content of the old path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub renamed: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PatchFile {
+    /// Hash of the file before the patch. Empty if unknown.
+    pub pre_hash: String,
+    /// Hash of the file after the patch. Empty if unknown.
+    pub post_hash: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hunks: Vec<PatchFileHunk>,
+    /// Line ending the patched file is written with. Hunks are always stored
+    /// with `\n`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_ending: Option<LineEnding>,
+    /// Full before/after content for files that can not be diffed line by line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary: Option<PatchFileBinary>,
+    /// Symlink targets for files that are a symlink before or after the patch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<PatchFileSymlink>,
+    /// Unix permission bits of the file before the patch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_mode: Option<u32>,
+    /// Unix permission bits the patched file is written with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_mode: Option<u32>,
+    /// Modification time of the file before the patch in nanoseconds since the
+    /// Unix epoch. Only recorded by `diff --include-metadata` for files whose
+    /// content is unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_mtime: Option<i64>,
+    /// Modification time the patched file is set to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_mtime: Option<i64>,
+    /// Note for the readers of the patch, `apply` ignores it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+impl PatchFile {
+    /// Returns the number of lines the hunks add and remove.
+    pub fn line_counts(&self) -> (usize, usize) {
+        let lines = self.hunks.iter().flat_map(|x| x.diff.lines()).collect::<Vec<_>>();
+        let added = lines.iter().filter(|x| x.starts_with('+')).count();
+        let removed = lines.iter().filter(|x| x.starts_with('-')).count();
+        (added, removed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    /// Returns the line ending used by the majority of lines in `content`, if
+    /// it has any.
+    pub fn detect(content: &str) -> Option<Self> {
+        let total = content.matches('\n').count();
+        let crlf = content.matches("\r\n").count();
+        if total == 0 {
+            None
+        } else if crlf * 2 > total {
+            Some(Self::Crlf)
+        } else {
+            Some(Self::Lf)
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            | Self::Lf => "\n",
+            | Self::Crlf => "\r\n",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PatchFileBinary {
+    /// Base64 encoded content before the patch.
+    pub pre: String,
+    /// Base64 encoded content after the patch.
+    pub post: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PatchFileSymlink {
+    /// Target before the patch. Unset if the file was no symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre: Option<String>,
+    /// Target after the patch. Unset if the file is no symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PatchFileHunk {
+    pub old_range: (usize, usize),
+    pub new_range: (usize, usize),
+    pub diff: String,
+    /// Marks that the old side of this hunk ends at the end of a file without a
+    /// final newline (`\ No newline at end of file`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub old_no_newline: bool,
+    /// Marks that the new side of this hunk ends at the end of a file without a
+    /// final newline.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub new_no_newline: bool,
+    /// Note for the readers of the patch, `apply` ignores it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apply::apply_patch,
+        diff::compute_diff,
+        store::checkpoint,
+        testing::{
+            apply_options,
+            diff_options,
+            file_patch,
+            in_dir,
+            TempDir,
+        },
+    };
+
+    #[test]
+    fn deletions_apply_to_fresh_copies_and_reverse_from_the_store() {
+        let (dir, copy) = (TempDir::new(), TempDir::new());
+        for tree in [&dir, &copy] {
+            tree.write("kept.txt", "kept\n");
+            tree.write("sub/deleted.txt", "deleted\n");
+        }
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        let patch = in_dir(dir.path(), || {
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            std::fs::remove_file("sub/deleted.txt").unwrap();
+            compute_diff(&qop_dir, "latest", &diff_options()).unwrap().patch
+        });
+        assert_eq!(patch.deleted, ["./sub/deleted.txt"]);
+        assert!(patch.files.is_empty());
+        let patch = parse_patch(&write_patch(&patch, PatchFormat::Toml).unwrap(), PatchFormat::Toml).unwrap();
+
+        let report = apply_patch(&qop_dir, patch.clone(), apply_options(copy.path())).unwrap();
+        assert_eq!(report.deleted, ["./sub/deleted.txt"]);
+        assert!(!copy.path().join("sub/deleted.txt").exists());
+        assert_eq!(copy.read("kept.txt"), b"kept\n");
+
+        // the deleted content is only in the store
+        let reversed = reverse_patch(&qop_dir, patch).unwrap();
+        assert!(reversed.deleted.is_empty());
+        assert_eq!(reversed.added, ["./sub/deleted.txt"]);
+        for tree in [&copy, &dir] {
+            apply_patch(&qop_dir, reversed.clone(), apply_options(tree.path())).unwrap();
+            assert_eq!(tree.read("sub/deleted.txt"), b"deleted\n");
+        }
+    }
+
+    #[test]
+    fn normalizes_keys_written_on_windows() {
+        let mut patch = file_patch(".\\sub\\a.txt", b"a\n", b"b\n");
+        patch.added.push(".\\sub\\a.txt".to_owned());
+        patch.deleted.push(".\\old\\b.txt".to_owned());
+        patch.renamed.insert(".\\sub\\a.txt".to_owned(), ".\\old\\a.txt".to_owned());
+        for format in [PatchFormat::Toml, PatchFormat::Json] {
+            let parsed = parse_patch(&write_patch(&patch, format).unwrap(), format).unwrap();
+            assert_eq!(parsed.files.keys().collect::<Vec<_>>(), ["./sub/a.txt"]);
+            assert_eq!(parsed.added, ["./sub/a.txt"]);
+            assert_eq!(parsed.deleted, ["./old/b.txt"]);
+            assert_eq!(parsed.renamed["./sub/a.txt"], "./old/a.txt");
+        }
+    }
+
+    #[test]
+    fn strips_carriage_returns_of_patches_saved_with_crlf() {
+        let patch = file_patch("./file.txt", b"a\nb\nc\n", b"a\nB\nc\n");
+        for format in [PatchFormat::Toml, PatchFormat::Json] {
+            let written = write_patch(&patch, format).unwrap();
+            let saved = written.replace("\\n", "\\r\\n").replace('\n', "\r\n");
+            let parsed = parse_patch(&saved, format).unwrap();
+            let hunk = &parsed.files["./file.txt"].hunks[0];
+            assert_eq!(hunk.diff, patch.files["./file.txt"].hunks[0].diff);
+            assert!(!hunk.diff.contains('\r'));
+
+            let dir = TempDir::new();
+            dir.write("file.txt", "a\nb\nc\n");
+            apply_patch(&QopDir::new(dir.path().join(".qop")), parsed, apply_options(dir.path())).unwrap();
+            assert_eq!(dir.read("file.txt"), b"a\nB\nc\n");
+        }
+    }
+}
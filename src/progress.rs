@@ -0,0 +1,15 @@
+use std::path::Path;
+
+/// Receives the progress of long running operations such as `checkpoint`. The
+/// methods are called from the worker threads, in the order the work
+/// completes.
+pub trait Progress: Sync {
+    /// Called once the number of files to process is known.
+    fn start(&self, _total: usize) {}
+
+    /// Called after a file was processed.
+    fn advance(&self, _path: &Path) {}
+}
+
+/// Ignores all progress.
+impl Progress for () {}
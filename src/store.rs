@@ -0,0 +1,1759 @@
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+        HashSet,
+    },
+    io::{
+        Read,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+};
+
+use anyhow::{
+    Context,
+    Result,
+};
+use chrono::{
+    DateTime,
+    Utc,
+};
+use rayon::prelude::*;
+
+use crate::{
+    fs::{
+        create_symlink,
+        file_mode,
+        read_symlink,
+        set_file_mode,
+        write_atomic,
+    },
+    hash::{
+        hash,
+        hash_file,
+        hash_symlink,
+        HashAlgorithm,
+        HashReader,
+    },
+    progress::Progress,
+    walk::{
+        collect_files,
+        key_path,
+        normalize_key,
+        path_key,
+        walk_files,
+        Collected,
+    },
+};
+
+/// Version of the index format written by this version of qop. Version 1
+/// indexes keep a full copy of every checkpoint and have to be converted with
+/// `migrate`.
+pub const INDEX_VERSION: u32 = 2;
+
+pub fn read_index(qop_dir: &QopDir) -> Result<Index> {
+    let content = match std::fs::read_to_string(qop_dir.index()) {
+        | Ok(v) => v,
+        | Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(anyhow::anyhow!(
+                "no qop store found in {}, run `qop init` first",
+                qop_dir.path.display()
+            ));
+        },
+        | Err(e) => return Err(e).with_context(|| format!("failed to read {}", qop_dir.index().display())),
+    };
+    parse_index(qop_dir, &content)
+}
+
+fn parse_index(qop_dir: &QopDir, content: &str) -> Result<Index> {
+    if content.trim().is_empty() {
+        return Err(anyhow::anyhow!(
+            "empty index {}, run `qop init` to recreate it",
+            qop_dir.index().display()
+        ));
+    }
+    let version = index_version(&qop_dir.index(), content)?;
+    if version < INDEX_VERSION {
+        return Err(anyhow::anyhow!(
+            "the store in {} uses the layout of an older qop version, run `qop migrate` first",
+            qop_dir.path.display()
+        ));
+    }
+    if version > INDEX_VERSION {
+        return Err(anyhow::anyhow!(
+            "the store in {} was written by a newer qop version",
+            qop_dir.path.display()
+        ));
+    }
+    let index =
+        toml::from_str::<Index>(content).with_context(|| format!("malformed index {}", qop_dir.index().display()))?;
+    Ok(normalize_keys(index))
+}
+
+fn index_version(source: &Path, content: &str) -> Result<u32> {
+    #[derive(serde::Deserialize)]
+    struct Header {
+        #[serde(default = "legacy_version")]
+        version: u32,
+    }
+    let header = toml::from_str::<Header>(content).with_context(|| format!("malformed index {}", source.display()))?;
+    Ok(header.version)
+}
+
+/// Parses an index from outside the store, such as the one of a teammate's
+/// copy. The content it refers to is still read from this store, `diff`
+/// checks that it is there.
+pub fn parse_foreign_index(source: &Path, content: &str) -> Result<Index> {
+    let version = index_version(source, content)?;
+    if version != INDEX_VERSION {
+        return Err(anyhow::anyhow!(
+            "the index {} has layout version {}, this qop version reads {}",
+            source.display(),
+            version,
+            INDEX_VERSION
+        ));
+    }
+    let index = toml::from_str::<Index>(content).with_context(|| format!("malformed index {}", source.display()))?;
+    Ok(normalize_keys(index))
+}
+
+/// Converts keys that an older version wrote with Windows separators, so that
+/// the index matches the one of any platform.
+fn normalize_keys(mut index: Index) -> Index {
+    let normalize =
+        |files: BTreeMap<String, IndexFile>| files.into_iter().map(|(k, v)| (normalize_key(k), v)).collect();
+    index.files = normalize(std::mem::take(&mut index.files));
+    for entry in index.entries.values_mut() {
+        entry.files = normalize(std::mem::take(&mut entry.files));
+    }
+    index
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+/// Resolves a checkpoint name to its files. `latest` refers to the most recent
+/// checkpoint and `head` to the checked out one.
+pub fn resolve_checkpoint<'a>(index: &'a Index, name: &str) -> Result<&'a BTreeMap<String, IndexFile>> {
+    let name = match (name, &index.head) {
+        | ("head", Some(head)) => head.as_str(),
+        | ("head", None) => "latest",
+        | _ => name,
+    };
+    if name == "latest" {
+        return Ok(&index.files);
+    }
+    match index.entries.get(name) {
+        | Some(entry) => Ok(&entry.files),
+        | None => {
+            let mut names = index.entries.keys().map(|x| x.as_str()).collect::<Vec<_>>();
+            names.sort();
+            Err(anyhow::anyhow!(
+                "unknown checkpoint: {}\navailable checkpoints:\n{}",
+                name,
+                names.join("\n")
+            ))
+        },
+    }
+}
+
+/// Outcome of `checkpoint`.
+#[derive(Debug, Clone)]
+pub struct CheckpointReport {
+    pub name: String,
+    /// Number of files whose content was added to the store.
+    pub copied: usize,
+    /// Number of files whose content was already stored.
+    pub unchanged: usize,
+    /// Number of files of which only the hash was recorded, see
+    /// `Index::manifest_only`.
+    pub hashed: usize,
+    /// Sorted paths of files that kept changing while they were copied. The
+    /// checkpoint holds whatever was read of them.
+    pub changed: Vec<String>,
+    /// Sorted paths of files that were skipped since they exceed the size
+    /// limit.
+    pub too_large: Vec<String>,
+}
+
+/// Snapshots the working copy into a new checkpoint. With `fresh`, all previous
+/// checkpoints are discarded and the store starts over with the given empty
+/// index, which sets the hash algorithm, the compression and whether content is
+/// stored at all. The message and author are recorded with the checkpoint.
+pub fn checkpoint(
+    qop_dir: &QopDir,
+    name: Option<String>,
+    fresh: Option<Index>,
+    message: Option<String>,
+    author: Option<String>,
+    strict: bool,
+    progress: &dyn Progress,
+) -> Result<CheckpointReport> {
+    // a fresh store is built next to the old one, which stays intact until the
+    // new index replaces the old one
+    let staging = fresh.is_some().then(|| QopDir::new(qop_dir.staging()));
+    let mut index = if let Some(index) = fresh {
+        let _ = std::fs::remove_dir_all(qop_dir.staging());
+        index
+    } else {
+        match std::fs::read_to_string(qop_dir.index()) {
+            | Ok(s) => parse_index(qop_dir, &s)?,
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => Index::default(),
+            | Err(e) => return Err(e.into()),
+        }
+    };
+
+    let instant = Utc::now();
+    let name = name.unwrap_or_else(|| instant.format("%Y%m%d%H%M%S%3f").to_string());
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name == "latest"
+        || name == "head"
+        || name.contains(['/', '\\'])
+    {
+        return Err(anyhow::anyhow!("invalid checkpoint name: {}", name));
+    }
+    if index.entries.contains_key(&name) {
+        return Err(anyhow::anyhow!("checkpoint already exists: {}", name));
+    }
+
+    // the traversal is cheap compared to hashing and copying, which runs on the
+    // thread pool
+    let Collected {
+        files: paths,
+        too_large,
+    } = walk_files(qop_dir, Path::new("."), &mut Vec::new())?;
+    let mut too_large = too_large.iter().map(|x| path_key(x)).collect::<Result<Vec<_>>>()?;
+    too_large.sort();
+    progress.start(paths.len());
+    let results = paths
+        .par_iter()
+        .map(|path| {
+            let key = path_key(path)?;
+            let (file, stored) = if index.manifest_only {
+                (hash_only(index.hash, path)?, Stored::Hashed)
+            } else {
+                store_file(staging.as_ref().unwrap_or(qop_dir), index.hash, index.compression, path)?
+            };
+            match stored {
+                | Stored::Existing => log::trace!("unchanged {}", key),
+                | Stored::Hashed => log::trace!("hashed {}", key),
+                | Stored::Copied => log::debug!("copied {}", key),
+                | Stored::Changed if strict => {
+                    return Err(anyhow::anyhow!("{} changed while it was copied", key));
+                },
+                | Stored::Changed => log::debug!("copied {} while it changed", key),
+            }
+            progress.advance(path);
+            Ok((key, file, stored))
+        })
+        .collect::<Result<Vec<_>>>();
+    // the current store is untouched until everything is stored
+    let results = match results {
+        | Ok(v) => v,
+        | Err(e) => {
+            if let Some(staging) = &staging {
+                let _ = std::fs::remove_dir_all(staging.path());
+            }
+            return Err(e);
+        },
+    };
+
+    let copied = results.iter().filter(|x| matches!(x.2, Stored::Copied | Stored::Changed)).count();
+    let hashed = results.iter().filter(|x| x.2 == Stored::Hashed).count();
+    let mut changed = results.iter().filter(|x| x.2 == Stored::Changed).map(|x| x.0.clone()).collect::<Vec<_>>();
+    changed.sort();
+    let report = CheckpointReport {
+        name: name.clone(),
+        copied,
+        unchanged: results.len() - copied - hashed,
+        hashed,
+        changed,
+        too_large,
+    };
+    let files = results.into_iter().map(|x| (x.0, x.1)).collect::<BTreeMap<_, _>>();
+
+    index.entries.insert(name.clone(), IndexEntry {
+        instant,
+        message,
+        author,
+        files: files.clone(),
+    });
+    index.head = Some(name.clone());
+    index.latest = Some(name);
+    index.files = files;
+
+    match staging {
+        | Some(staging) => replace_store(qop_dir, &staging, &index)?,
+        | None => write_index(qop_dir, &index)?,
+    }
+    Ok(report)
+}
+
+/// Files `checkpoint` would store, see `plan_checkpoint`.
+#[derive(Debug, Clone)]
+pub struct CheckpointPlan {
+    /// Sorted paths of the files.
+    pub files: Vec<String>,
+    /// Sorted paths of files that would be skipped since they exceed the size
+    /// limit.
+    pub too_large: Vec<String>,
+}
+
+/// Walks the working copy like `checkpoint` without reading the files, and
+/// without touching the store or the index.
+pub fn plan_checkpoint(qop_dir: &QopDir) -> Result<CheckpointPlan> {
+    let Collected { files, too_large } = walk_files(qop_dir, Path::new("."), &mut Vec::new())?;
+    let keys = |paths: Vec<PathBuf>| -> Result<Vec<String>> {
+        let mut keys = paths.iter().map(|x| path_key(x)).collect::<Result<Vec<_>>>()?;
+        keys.sort();
+        Ok(keys)
+    };
+    Ok(CheckpointPlan {
+        files: keys(files)?,
+        too_large: keys(too_large)?,
+    })
+}
+
+/// Replaces the stored content and the index with the fresh store built in
+/// `staging`. The old content is moved aside instead of deleted and put back
+/// if the swap fails, a swap that is interrupted is reported by `doctor`.
+fn replace_store(qop_dir: &QopDir, staging: &QopDir, index: &Index) -> Result<()> {
+    let _ = std::fs::remove_dir_all(qop_dir.previous_blobs());
+    let had_blobs = qop_dir.blobs().exists();
+    if had_blobs {
+        std::fs::rename(qop_dir.blobs(), qop_dir.previous_blobs())?;
+    }
+    let swapped = (|| -> Result<()> {
+        if staging.blobs().exists() {
+            std::fs::rename(staging.blobs(), qop_dir.blobs())?;
+        }
+        write_index(qop_dir, index)
+    })();
+    if let Err(e) = swapped {
+        let _ = std::fs::remove_dir_all(qop_dir.blobs());
+        if had_blobs {
+            std::fs::rename(qop_dir.previous_blobs(), qop_dir.blobs())?;
+        }
+        let _ = std::fs::remove_dir_all(staging.path());
+        return Err(e);
+    }
+    let _ = std::fs::remove_dir_all(qop_dir.previous_blobs());
+    let _ = std::fs::remove_dir_all(qop_dir.store());
+    let _ = std::fs::remove_dir_all(staging.path());
+    Ok(())
+}
+
+/// Times a file is copied again when its content differs from the hash taken
+/// right before, because it is being written to.
+const CHANGED_RETRIES: usize = 2;
+
+/// How `store_file` got the content of a file into the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stored {
+    /// The content was stored already.
+    Existing,
+    Copied,
+    /// The file changed during every attempt to copy it. The copy of the last
+    /// attempt is stored under its own hash.
+    Changed,
+    /// Only the hash was recorded since the store holds no content.
+    Hashed,
+}
+
+/// Returns the stamp of a regular file, `None` for symlinks and for
+/// modification times the platform does not report.
+pub fn file_stamp(path: &Path) -> std::io::Result<Option<FileStamp>> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+        .and_then(|x| i64::try_from(x.as_nanos()).ok());
+    Ok(mtime.map(|mtime| {
+        FileStamp {
+            size: metadata.len(),
+            mtime,
+        }
+    }))
+}
+
+/// Returns the stamp to record for a file that is about to be hashed. Files
+/// modified within the last second get none: a write right after hashing could
+/// leave the same modification time on file systems with coarse timestamps.
+fn settled_stamp(path: &Path) -> std::io::Result<Option<FileStamp>> {
+    let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) else {
+        return Ok(None);
+    };
+    let settled = now.as_nanos().saturating_sub(1_000_000_000);
+    Ok(file_stamp(path)?.filter(|x| (x.mtime as u128) < settled))
+}
+
+/// Returns the index record of a file without storing its content.
+fn hash_only(algorithm: HashAlgorithm, path: &Path) -> Result<IndexFile> {
+    let stamp = settled_stamp(path)?;
+    Ok(IndexFile {
+        hash: hash_file(algorithm, path)?,
+        mode: file_mode(path)?,
+        symlink: read_symlink(path)?.is_some(),
+        stamp,
+    })
+}
+
+/// Adds the content of a file to the store unless it is stored already.
+/// Returns the index record of the file and how its content was stored.
+fn store_file(
+    qop_dir: &QopDir,
+    algorithm: HashAlgorithm,
+    compression: Compression,
+    path: &Path,
+) -> Result<(IndexFile, Stored)> {
+    let target = read_symlink(path)?;
+    let mode = file_mode(path)?;
+    for attempt in 0..=CHANGED_RETRIES {
+        let stamp = settled_stamp(path)?;
+        let file = IndexFile {
+            hash: hash_file(algorithm, path)?,
+            mode,
+            symlink: target.is_some(),
+            stamp,
+        };
+        let blob = qop_dir.blob(&file.hash);
+        if blob.exists() {
+            return Ok((file, Stored::Existing));
+        }
+
+        // concurrent writers of the same content and interrupted runs must
+        // never leave a partial blob behind
+        if let Some(parent) = blob.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = blob.with_extension(format!(
+            "tmp-{}-{}",
+            std::process::id(),
+            rayon::current_thread_index().unwrap_or(0)
+        ));
+        let copied = (|| -> std::io::Result<String> {
+            let mut out = std::fs::File::create(&tmp)?;
+            match &target {
+                | Some(target) => {
+                    compression.encode(target.as_bytes(), &mut out)?;
+                    Ok(file.hash.clone())
+                },
+                | None => {
+                    let mut from = HashReader::new(algorithm, std::fs::File::open(path)?);
+                    compression.encode(&mut from, &mut out)?;
+                    Ok(from.finalize())
+                },
+            }
+        })();
+        let (copied, stored) = match copied {
+            | Ok(v) if v == file.hash => (v, Stored::Copied),
+            | Ok(v) if attempt == CHANGED_RETRIES => (v, Stored::Changed),
+            | result => {
+                let _ = std::fs::remove_file(&tmp);
+                result?;
+                continue;
+            },
+        };
+        let blob = qop_dir.blob(&copied);
+        let renamed = blob.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|_| std::fs::rename(&tmp, &blob));
+        if renamed.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        renamed?;
+        // the file was written to while it was copied
+        let stamp = file.stamp.filter(|_| stored == Stored::Copied);
+        return Ok((
+            IndexFile {
+                hash: copied,
+                stamp,
+                ..file
+            },
+            stored,
+        ));
+    }
+    unreachable!("the last attempt always returns")
+}
+
+/// Writes a stored file to `path` with its recorded permissions. Missing parent
+/// directories are created.
+pub fn restore_file(qop_dir: &QopDir, compression: Compression, file: &IndexFile, path: &Path) -> Result<()> {
+    let blob = qop_dir.blob(&file.hash);
+    if !blob.exists() {
+        return Err(anyhow::anyhow!(
+            "the content of {} is missing in the store ({})",
+            path.display(),
+            blob.display()
+        ));
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // writing onto an existing symlink would write to its target
+    if std::fs::symlink_metadata(path).is_ok_and(|x| file.symlink || x.file_type().is_symlink()) {
+        std::fs::remove_file(path)?;
+    }
+    if file.symlink {
+        let target = String::from_utf8(read_stored(qop_dir, compression, &file.hash)?)?;
+        return create_symlink(&target, path);
+    }
+    compression.decode(std::fs::File::open(&blob)?, &mut std::fs::File::create(path)?)?;
+    set_file_mode(path, file.mode)?;
+    Ok(())
+}
+
+/// Reads the stored content with the given hash.
+pub fn read_stored(qop_dir: &QopDir, compression: Compression, hash: &str) -> std::io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    compression.decode(std::fs::File::open(qop_dir.blob(hash))?, &mut content)?;
+    Ok(content)
+}
+
+/// Replaces the index, a crash leaves either the old or the new one.
+fn write_index(qop_dir: &QopDir, index: &Index) -> Result<()> {
+    write_atomic(&qop_dir.index(), toml::to_string(index)?.as_bytes())
+}
+
+/// Lists all checkpoints from the oldest to the newest.
+pub fn checkpoints(qop_dir: &QopDir) -> Result<Vec<LogEntry>> {
+    let index = read_index(qop_dir)?;
+    let mut entries = index
+        .entries
+        .iter()
+        .map(|(name, entry)| {
+            LogEntry {
+                name: name.clone(),
+                instant: entry.instant,
+                message: entry.message.clone(),
+                author: entry.author.clone(),
+                files: entry.files.len(),
+                latest: index.latest.as_ref() == Some(name),
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.instant.cmp(&b.instant).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// State of a file in a checkpoint or the working copy compared to the
+/// checkpoint before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileChange {
+    Added,
+    Changed,
+    Unchanged,
+    Deleted,
+    /// The file is in neither.
+    Absent,
+}
+
+impl FileChange {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            | Self::Added => "added",
+            | Self::Changed => "changed",
+            | Self::Unchanged => "unchanged",
+            | Self::Deleted => "deleted",
+            | Self::Absent => "absent",
+        }
+    }
+
+    fn between(pre: Option<&str>, post: Option<&str>) -> Self {
+        match (pre, post) {
+            | (None, Some(_)) => Self::Added,
+            | (Some(_), None) => Self::Deleted,
+            | (None, None) => Self::Absent,
+            | (Some(a), Some(b)) if a == b => Self::Unchanged,
+            | (Some(_), Some(_)) => Self::Changed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BlameEntry {
+    pub name: String,
+    pub instant: DateTime<Utc>,
+    pub change: FileChange,
+    pub latest: bool,
+}
+
+/// Timeline of a file, returned by `blame`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Blame {
+    /// The checkpoints from the oldest to the newest.
+    pub checkpoints: Vec<BlameEntry>,
+    /// The working copy compared to the latest checkpoint.
+    pub working_copy: FileChange,
+}
+
+/// Tells for every checkpoint whether the content of a file changed since the
+/// checkpoint before, so that it can be seen when it last changed.
+pub fn blame(qop_dir: &QopDir, path: &str) -> Result<Blame> {
+    let index = read_index(qop_dir)?;
+    let key = format!("./{}", path.strip_prefix("./").unwrap_or(path));
+    let mut entries = index.entries.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.1.instant.cmp(&b.1.instant).then_with(|| a.0.cmp(b.0)));
+
+    let mut previous: Option<&str> = None;
+    let mut checkpoints = Vec::new();
+    for (name, entry) in entries {
+        let current = entry.files.get(&key).map(|x| x.hash.as_str());
+        checkpoints.push(BlameEntry {
+            name: name.clone(),
+            instant: entry.instant,
+            change: FileChange::between(previous, current),
+            latest: index.latest.as_ref() == Some(name),
+        });
+        previous = current;
+    }
+    let latest = index.files.get(&key).map(|x| x.hash.as_str());
+    let working_copy = match hash_file(index.hash, key_path(&key)) {
+        | Ok(v) => FileChange::between(latest, Some(&v)),
+        | Err(e) if e.kind() == std::io::ErrorKind::NotFound => FileChange::between(latest, None),
+        | Err(e) => return Err(e.into()),
+    };
+    if working_copy == FileChange::Absent && checkpoints.iter().all(|x| x.change == FileChange::Absent) {
+        return Err(anyhow::anyhow!("file is in no checkpoint: {}", path));
+    }
+    Ok(Blame {
+        checkpoints,
+        working_copy,
+    })
+}
+
+/// Outcome of `prune`.
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    /// Names of the removed checkpoints.
+    pub removed: Vec<String>,
+    /// Bytes of stored content that is no longer referenced by any checkpoint.
+    pub reclaimed: u64,
+}
+
+/// Removes checkpoints and the stored content only they refer to. A checkpoint
+/// is kept if it is among the `keep` newest ones or not older than `before`;
+/// with both, it is kept if either applies. The latest and the checked out
+/// checkpoint are always kept.
+pub fn prune(qop_dir: &QopDir, keep: Option<usize>, before: Option<DateTime<Utc>>) -> Result<PruneReport> {
+    let mut index = read_index(qop_dir)?;
+    let mut entries = index.entries.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.1.instant.cmp(&a.1.instant).then_with(|| b.0.cmp(a.0)));
+    let mut report = PruneReport::default();
+    for (n, (name, entry)) in entries.into_iter().enumerate() {
+        let kept = keep.is_some_and(|x| n < x) || before.is_some_and(|x| entry.instant >= x);
+        if kept || index.latest.as_ref() == Some(name) || index.head.as_ref() == Some(name) {
+            continue;
+        }
+        report.removed.push(name.clone());
+    }
+    report.removed.sort();
+    for name in &report.removed {
+        log::debug!("remove checkpoint {}", name);
+        index.entries.remove(name);
+    }
+    write_index(qop_dir, &index)?;
+
+    // content is shared between checkpoints, it can only be removed once no
+    // checkpoint refers to it anymore
+    let referenced = index
+        .entries
+        .values()
+        .flat_map(|x| x.files.values())
+        .chain(index.files.values())
+        .map(|x| x.hash.as_str())
+        .collect::<HashSet<_>>();
+    for blob in stored_blobs(qop_dir)? {
+        let name = blob.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        if referenced.contains(name.as_str()) {
+            continue;
+        }
+        report.reclaimed += blob.symlink_metadata()?.len();
+        log::trace!("remove {}", blob.display());
+        std::fs::remove_file(&blob)?;
+    }
+    Ok(report)
+}
+
+/// Sizes on disk of a store, see `disk_usage`.
+#[derive(Debug, Clone, Default)]
+pub struct DiskUsage {
+    /// Size of everything in the qop directory.
+    pub total: u64,
+    /// Size of the stored content.
+    pub content: u64,
+    /// Size of the stored content no checkpoint refers to.
+    pub orphaned: u64,
+    /// From the oldest to the newest checkpoint.
+    pub checkpoints: Vec<CheckpointUsage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckpointUsage {
+    pub name: String,
+    /// Size of the stored content the checkpoint refers to.
+    pub size: u64,
+    /// Size of the stored content only this checkpoint refers to, which
+    /// pruning it would reclaim.
+    pub exclusive: u64,
+}
+
+/// Sums the sizes of the files in the qop directory and of the stored content
+/// per checkpoint. Content shared by checkpoints counts for each of them.
+pub fn disk_usage(qop_dir: &QopDir) -> Result<DiskUsage> {
+    let index = read_index(qop_dir)?;
+    let mut usage = DiskUsage {
+        total: dir_size(qop_dir.path())?,
+        ..Default::default()
+    };
+    let mut sizes = BTreeMap::new();
+    for blob in stored_blobs(qop_dir)? {
+        let size = blob.symlink_metadata()?.len();
+        usage.content += size;
+        sizes.insert(
+            blob.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default(),
+            size,
+        );
+    }
+
+    let mut entries = index.entries.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.1.instant.cmp(&b.1.instant).then_with(|| a.0.cmp(b.0)));
+    let hashes = |files: &BTreeMap<String, IndexFile>| files.values().map(|x| x.hash.clone()).collect::<BTreeSet<_>>();
+    let mut references = BTreeMap::<String, usize>::new();
+    for hash in entries.iter().flat_map(|x| hashes(&x.1.files)) {
+        *references.entry(hash).or_default() += 1;
+    }
+    let size = |hash: &String| sizes.get(hash).copied().unwrap_or(0);
+    for (name, entry) in entries {
+        let hashes = hashes(&entry.files);
+        usage.checkpoints.push(CheckpointUsage {
+            name: name.clone(),
+            size: hashes.iter().map(size).sum(),
+            exclusive: hashes.iter().filter(|x| references[*x] == 1).map(size).sum(),
+        });
+    }
+    // the files of the index belong to the latest checkpoint
+    let latest = hashes(&index.files);
+    usage.orphaned = sizes.iter().filter(|x| !references.contains_key(x.0) && !latest.contains(x.0)).map(|x| x.1).sum();
+    Ok(usage)
+}
+
+/// Sums the sizes of all files below a directory, symlinks are not followed.
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Lists all files in the store.
+fn stored_blobs(qop_dir: &QopDir) -> Result<Vec<PathBuf>> {
+    let shards = match std::fs::read_dir(qop_dir.blobs()) {
+        | Ok(v) => v,
+        | Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        | Err(e) => return Err(e.into()),
+    };
+    let mut blobs = Vec::new();
+    for shard in shards {
+        let shard = shard?.path();
+        if !shard.is_dir() {
+            continue;
+        }
+        for blob in std::fs::read_dir(&shard)? {
+            blobs.push(blob?.path());
+        }
+    }
+    blobs.sort();
+    Ok(blobs)
+}
+
+/// Problems found by `fsck`. Paths are sorted and listed once even if several
+/// checkpoints refer to them.
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Number of distinct stored contents that were checked.
+    pub checked: usize,
+    /// Paths whose stored content is missing.
+    pub missing: Vec<String>,
+    /// Paths whose stored content does not match its hash.
+    pub corrupt: Vec<String>,
+    /// Stored contents no checkpoint refers to.
+    pub orphaned: Vec<PathBuf>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+/// Hashes the stored content of all checkpoints again and compares it to the
+/// index.
+pub fn fsck(qop_dir: &QopDir) -> Result<FsckReport> {
+    let index = read_index(qop_dir)?;
+    let mut referenced = BTreeMap::<&str, (&IndexFile, BTreeSet<&str>)>::new();
+    for (path, file) in index.entries.values().flat_map(|x| x.files.iter()).chain(index.files.iter()) {
+        referenced.entry(file.hash.as_str()).or_insert_with(|| (file, BTreeSet::new())).1.insert(path);
+    }
+
+    enum Check {
+        Ok,
+        Missing,
+        Corrupt,
+    }
+    let checks = referenced
+        .par_iter()
+        .map(|(stored_hash, (file, _))| -> Result<Check> {
+            // there is no content to check against the hashes
+            if index.manifest_only {
+                return Ok(Check::Ok);
+            }
+            let blob = qop_dir.blob(stored_hash);
+            if !blob.exists() {
+                return Ok(Check::Missing);
+            }
+            let content = match read_stored(qop_dir, index.compression, stored_hash) {
+                | Ok(v) => v,
+                // content that can not be decompressed is damaged
+                | Err(e) if e.kind() != std::io::ErrorKind::PermissionDenied => {
+                    log::debug!("failed to decompress {}: {}", blob.display(), e);
+                    return Ok(Check::Corrupt);
+                },
+                | Err(e) => return Err(e).with_context(|| format!("failed to read {}", blob.display())),
+            };
+            // symlinks are hashed by their target, which is the stored content
+            let actual = if !file.symlink {
+                hash(index.hash, &content)
+            } else {
+                match std::str::from_utf8(&content) {
+                    | Ok(v) => hash_symlink(index.hash, v),
+                    | Err(_) => return Ok(Check::Corrupt),
+                }
+            };
+            Ok(if actual == *stored_hash {
+                Check::Ok
+            } else {
+                Check::Corrupt
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut report = FsckReport {
+        checked: referenced.len(),
+        ..Default::default()
+    };
+    let (mut missing, mut corrupt) = (BTreeSet::new(), BTreeSet::new());
+    for ((_, (_, paths)), check) in referenced.iter().zip(checks) {
+        match check {
+            | Check::Ok => (),
+            | Check::Missing => missing.extend(paths.iter().copied()),
+            | Check::Corrupt => corrupt.extend(paths.iter().copied()),
+        }
+    }
+    report.missing = missing.into_iter().map(|x| x.to_owned()).collect();
+    report.corrupt = corrupt.into_iter().map(|x| x.to_owned()).collect();
+    for blob in stored_blobs(qop_dir)? {
+        let name = blob.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        if !referenced.contains_key(name.as_str()) {
+            report.orphaned.push(blob);
+        }
+    }
+    Ok(report)
+}
+
+/// A problem found by `doctor` and how to resolve it.
+#[derive(Debug, Clone)]
+pub struct DoctorProblem {
+    pub problem: String,
+    pub fix: String,
+}
+
+/// Checks the qop directory for states that make other commands fail: a
+/// missing or unreadable index, a missing store and content of the latest
+/// checkpoint that is not stored. Unlike `fsck`, stored content is not hashed
+/// again.
+pub fn doctor(qop_dir: &QopDir) -> Result<Vec<DoctorProblem>> {
+    let problem = |problem: String, fix: &str| {
+        DoctorProblem {
+            problem,
+            fix: fix.to_owned(),
+        }
+    };
+    if !qop_dir.path.is_dir() {
+        return Ok(vec![problem(
+            format!("no qop store found in {}", qop_dir.path.display()),
+            "run `qop init` to create one",
+        )]);
+    }
+    let content = match std::fs::read_to_string(qop_dir.index()) {
+        | Ok(v) => v,
+        | Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(vec![problem(
+                format!("the index {} is missing", qop_dir.index().display()),
+                "run `qop init` to start over, the existing checkpoints are lost",
+            )]);
+        },
+        | Err(e) => return Err(e).with_context(|| format!("failed to read {}", qop_dir.index().display())),
+    };
+    let index = match parse_index(qop_dir, &content) {
+        | Ok(v) => v,
+        | Err(e) => {
+            let fix = match index_version(&qop_dir.index(), &content) {
+                | Ok(v) if v < INDEX_VERSION => "run `qop migrate`",
+                | Ok(v) if v > INDEX_VERSION => "use the qop version that wrote the store",
+                | _ => "repair the index, or run `qop init` to start over and lose the existing checkpoints",
+            };
+            return Ok(vec![problem(format!("{:#}", e), fix)]);
+        },
+    };
+
+    let mut problems = Vec::new();
+    if let Some(head) = index.head.as_ref().filter(|x| !index.entries.contains_key(*x)) {
+        problems.push(problem(
+            format!("the checked out checkpoint {} does not exist", head),
+            "run `qop checkout` with an existing checkpoint",
+        ));
+    }
+    if let Some(latest) = index.latest.as_ref().filter(|x| !index.entries.contains_key(*x)) {
+        problems.push(problem(
+            format!("the latest checkpoint {} does not exist", latest),
+            "run `qop checkpoint` to create a new one",
+        ));
+    }
+    if qop_dir.previous_blobs().exists() {
+        problems.push(problem(
+            format!(
+                "an interrupted `qop init` left the previous store in {}",
+                qop_dir.previous_blobs().display()
+            ),
+            "if `qop fsck` reports missing content, move it back in place of the store, otherwise delete it",
+        ));
+    }
+    if qop_dir.transaction().exists() {
+        problems.push(problem(
+            format!(
+                "an interrupted `qop apply --atomic` left the original files in {}",
+                qop_dir.transaction().display()
+            ),
+            "copy back the files the patches should not have changed, then delete it",
+        ));
+    }
+    if index.manifest_only {
+        return Ok(problems);
+    }
+    if !qop_dir.blobs().is_dir() {
+        if !index.files.is_empty() {
+            problems.push(problem(
+                format!("the store {} is missing", qop_dir.blobs().display()),
+                "run `qop init` to start over, the stored content is lost",
+            ));
+        }
+        return Ok(problems);
+    }
+    for (path, file) in &index.files {
+        if !qop_dir.blob(&file.hash).exists() {
+            problems.push(problem(
+                format!(
+                    "the content of {} in the latest checkpoint is missing in the store",
+                    path
+                ),
+                "run `qop checkpoint` if the file is unchanged to store it again, `qop fsck` checks all checkpoints",
+            ));
+        }
+    }
+    let partial = stored_blobs(qop_dir)?
+        .into_iter()
+        .filter(|x| x.file_name().is_some_and(|x| x.to_string_lossy().contains(".tmp-")))
+        .count();
+    if partial > 0 {
+        problems.push(problem(
+            format!("the store holds {} partially written files", partial),
+            "make sure that no other qop process is running, then delete the files ending in .tmp-*",
+        ));
+    }
+    Ok(problems)
+}
+
+/// Outcome of `migrate`.
+#[derive(Debug, Clone, Default)]
+pub struct MigrateReport {
+    /// Number of converted checkpoints.
+    pub checkpoints: usize,
+    /// Number of distinct stored contents.
+    pub blobs: usize,
+}
+
+/// Converts a version 1 store, which keeps a full copy of every checkpoint, to
+/// the content addressed store of the current version. The copies are removed
+/// afterwards.
+pub fn migrate(qop_dir: &QopDir) -> Result<MigrateReport> {
+    #[derive(serde::Deserialize)]
+    struct LegacyIndex {
+        #[serde(default)]
+        hash: HashAlgorithm,
+        latest: Option<String>,
+        entries: BTreeMap<String, LegacyIndexEntry>,
+        files: BTreeMap<String, String>,
+    }
+    #[derive(serde::Deserialize)]
+    struct LegacyIndexEntry {
+        instant: DateTime<Utc>,
+        #[serde(default)]
+        message: Option<String>,
+        #[serde(default)]
+        author: Option<String>,
+        #[serde(default)]
+        files: BTreeMap<String, String>,
+    }
+
+    let content = match std::fs::read_to_string(qop_dir.index()) {
+        | Ok(v) => v,
+        | Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(anyhow::anyhow!("no qop store found in {}", qop_dir.path.display()));
+        },
+        | Err(e) => return Err(e.into()),
+    };
+    if index_version(&qop_dir.index(), &content)? >= INDEX_VERSION {
+        return Err(anyhow::anyhow!("the store in {} is up to date", qop_dir.path.display()));
+    }
+    let legacy = toml::from_str::<LegacyIndex>(&content)
+        .with_context(|| format!("malformed index {}", qop_dir.index().display()))?;
+
+    // the stored copies are hashed again, so that the new index describes
+    // what is actually in the store
+    let index_compression = Index::default().compression;
+    let convert = |snapshot: &Path, files: &BTreeMap<String, String>| -> Result<BTreeMap<String, IndexFile>> {
+        files
+            .par_iter()
+            .map(|(path, _)| {
+                let (file, _) = store_file(qop_dir, legacy.hash, index_compression, &snapshot.join(path))
+                    .with_context(|| format!("failed to migrate {}", snapshot.join(path).display()))?;
+                // the stamp is the one of the copy in the old store
+                Ok((path.clone(), IndexFile { stamp: None, ..file }))
+            })
+            .collect()
+    };
+    let mut index = Index {
+        hash: legacy.hash,
+        latest: legacy.latest.clone(),
+        ..Default::default()
+    };
+    for (name, entry) in &legacy.entries {
+        log::debug!("migrate checkpoint {}", name);
+        index.entries.insert(name.clone(), IndexEntry {
+            instant: entry.instant,
+            message: entry.message.clone(),
+            author: entry.author.clone(),
+            files: convert(&qop_dir.store().join(name), &entry.files)?,
+        });
+    }
+    index.files = match &legacy.latest {
+        | Some(latest) => index.entries.get(latest).map(|x| x.files.clone()).unwrap_or_default(),
+        // indexes without checkpoints keep their files in the store root
+        | None => convert(&qop_dir.store(), &legacy.files)?,
+    };
+
+    write_index(qop_dir, &index)?;
+    std::fs::remove_dir_all(qop_dir.store())?;
+    Ok(MigrateReport {
+        checkpoints: index.entries.len(),
+        blobs: stored_blobs(qop_dir)?.len(),
+    })
+}
+
+/// Sorted paths that differ between the working copy and the checked out
+/// checkpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+    pub untracked: Vec<String>,
+}
+
+impl Status {
+    pub fn is_empty(&self) -> bool {
+        self.modified.is_empty() && self.deleted.is_empty() && self.untracked.is_empty()
+    }
+}
+
+/// Compares the working copy to the checked out checkpoint, the one `diff`
+/// compares against by default. Only hashes are compared, no hunks are
+/// computed.
+pub fn status(qop_dir: &QopDir) -> Result<Status> {
+    let index = read_index(qop_dir)?;
+    let files = resolve_checkpoint(&index, "head")?;
+
+    let mut status = Status::default();
+    for path in collect_files(qop_dir, Path::new("."), &mut Vec::new())? {
+        let path = path_key(&path)?;
+        if !files.contains_key(&path) {
+            status.untracked.push(path);
+        }
+    }
+    for (path, file) in files {
+        let wc_path = key_path(path);
+        match hash_file(index.hash, &wc_path) {
+            | Ok(v) if v == file.hash && file_mode(&wc_path)? == file.mode => (),
+            | Ok(_) => status.modified.push(path.clone()),
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => status.deleted.push(path.clone()),
+            | Err(e) => return Err(e.into()),
+        }
+    }
+    status.modified.sort();
+    status.deleted.sort();
+    status.untracked.sort();
+    Ok(status)
+}
+
+/// Sorted paths that `restore` writes from the store or removes from the
+/// working copy.
+#[derive(Debug, Clone, Default)]
+pub struct RestorePlan {
+    pub restored: Vec<String>,
+    pub removed: Vec<String>,
+    /// Files of `restored` and `removed` whose content is in no checkpoint, so
+    /// that restoring loses it.
+    pub local_changes: Vec<String>,
+}
+
+/// Resets the working copy to the state of a checkpoint. A dry run only
+/// returns what would be done. Unless `force` is set, files with local changes
+/// are not overwritten and the call fails instead.
+pub fn restore(qop_dir: &QopDir, checkpoint: &str, dry_run: bool, force: bool) -> Result<RestorePlan> {
+    let index = read_index(qop_dir)?;
+    index.require_content("restore")?;
+    let files = resolve_checkpoint(&index, checkpoint)?;
+    // the store holds the content of every checkpoint by its hash
+    let changed_locally = |hash: &str| !qop_dir.blob(hash).exists();
+
+    let mut plan = RestorePlan::default();
+    for wc_path in collect_files(qop_dir, Path::new("."), &mut Vec::new())? {
+        let path = path_key(&wc_path)?;
+        if !files.contains_key(&path) {
+            if changed_locally(&hash_file(index.hash, &wc_path)?) {
+                plan.local_changes.push(path.clone());
+            }
+            plan.removed.push(path);
+        }
+    }
+    plan.removed.sort();
+
+    for (path, file) in files {
+        let wc_path = key_path(path);
+        match hash_file(index.hash, &wc_path) {
+            | Ok(v) if v == file.hash && file_mode(&wc_path)? == file.mode => continue,
+            | Ok(v) if changed_locally(&v) => plan.local_changes.push(path.clone()),
+            | Ok(_) => (),
+            | Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            | Err(e) => return Err(e.into()),
+        }
+        plan.restored.push(path.clone());
+    }
+    plan.restored.sort();
+    plan.local_changes.sort();
+
+    if dry_run {
+        return Ok(plan);
+    }
+    if !force && !plan.local_changes.is_empty() {
+        return Err(anyhow::anyhow!(
+            "local changes to these files would be lost (use --force to restore anyway):\n{}",
+            plan.local_changes.join("\n")
+        ));
+    }
+    for path in &plan.restored {
+        log::debug!("restore {}", path);
+        restore_file(qop_dir, index.compression, &files[path], &key_path(path))?;
+    }
+    for path in &plan.removed {
+        log::debug!("remove {}", path);
+        std::fs::remove_file(key_path(path))?;
+    }
+    Ok(plan)
+}
+
+/// Restores a checkpoint and makes it the one `head` refers to.
+pub fn checkout(qop_dir: &QopDir, checkpoint: &str, force: bool) -> Result<RestorePlan> {
+    let plan = restore(qop_dir, checkpoint, false, force)?;
+    let mut index = read_index(qop_dir)?;
+    index.head = match checkpoint {
+        | "latest" => index.latest.clone(),
+        | "head" => index.head.clone(),
+        | _ => Some(checkpoint.to_owned()),
+    };
+    write_index(qop_dir, &index)?;
+    Ok(plan)
+}
+
+/// Compression of the stored content. The hashes are always computed over the
+/// uncompressed content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            | "none" => Ok(Self::None),
+            | "zstd" => Ok(Self::Zstd),
+            | _ => Err(anyhow::anyhow!("unknown compression")),
+        }
+    }
+}
+
+impl Compression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            | Self::None => "none",
+            | Self::Zstd => "zstd",
+        }
+    }
+
+    /// Streams `from` into `to`, so that large files are never held in memory
+    /// as a whole.
+    fn encode(self, mut from: impl Read, to: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            | Self::None => std::io::copy(&mut from, to).map(|_| ()),
+            | Self::Zstd => zstd::stream::copy_encode(from, to, 0),
+        }
+    }
+
+    fn decode(self, mut from: impl Read, to: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            | Self::None => std::io::copy(&mut from, to).map(|_| ()),
+            | Self::Zstd => zstd::stream::copy_decode(from, to),
+        }
+    }
+}
+
+/// Location of the store and the index.
+#[derive(Debug, Clone)]
+pub struct QopDir {
+    path: PathBuf,
+    hidden: bool,
+    max_file_size: Option<u64>,
+    qopfile_name: String,
+}
+
+impl QopDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            hidden: true,
+            max_file_size: None,
+            qopfile_name: ".qopfile".to_owned(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether hidden files of the working copy are collected, which they are
+    /// unless turned off.
+    pub fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    /// Size in bytes above which files of the working copy are skipped.
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    pub fn set_max_file_size(&mut self, max_file_size: Option<u64>) {
+        self.max_file_size = max_file_size;
+    }
+
+    /// Name of the files with the ignore rules of the working copy,
+    /// `.qopfile` unless changed.
+    pub fn qopfile_name(&self) -> &str {
+        &self.qopfile_name
+    }
+
+    pub fn set_qopfile_name(&mut self, qopfile_name: impl Into<String>) {
+        self.qopfile_name = qopfile_name.into();
+    }
+
+    /// Directory holding the stored file contents, named by their hash.
+    pub fn blobs(&self) -> PathBuf {
+        self.path.join("blobs")
+    }
+
+    /// Path of the stored content with the given hash. The contents are spread
+    /// over subdirectories by the first two characters of their hash.
+    pub fn blob(&self, hash: &str) -> PathBuf {
+        let shard = hash.get(..2).unwrap_or(hash);
+        self.blobs().join(shard).join(hash)
+    }
+
+    /// Directory a fresh store is built in by `checkpoint` before it replaces
+    /// the current one.
+    pub fn staging(&self) -> PathBuf {
+        self.path.join("staging")
+    }
+
+    /// Directory the stored contents are moved to while a fresh store replaces
+    /// them. It only remains if that was interrupted.
+    pub fn previous_blobs(&self) -> PathBuf {
+        self.path.join("blobs.old")
+    }
+
+    /// Directory holding the original files while `apply --atomic` runs. It
+    /// only remains if that was interrupted.
+    pub fn transaction(&self) -> PathBuf {
+        self.path.join("transaction")
+    }
+
+    /// Directory holding the per-checkpoint copies of version 1 stores, only
+    /// read by `migrate`.
+    pub fn store(&self) -> PathBuf {
+        self.path.join("store")
+    }
+
+    pub fn index(&self) -> PathBuf {
+        self.path.join("index.toml")
+    }
+
+    /// Directory holding the originals of files modified by `apply --backup`.
+    pub fn backups(&self) -> PathBuf {
+        self.path.join("backups")
+    }
+
+    /// Takes the lock that commands modifying the store, the index or the
+    /// working copy hold while they run. It is released when the returned file
+    /// is dropped, including when the process dies.
+    pub fn lock(&self) -> Result<std::fs::File> {
+        std::fs::create_dir_all(&self.path)?;
+        let path = self.path.join("lock");
+        let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+        match file.try_lock() {
+            | Ok(()) => Ok(file),
+            | Err(std::fs::TryLockError::WouldBlock) => {
+                Err(anyhow::anyhow!(
+                    "another qop process is running (lock held on {})",
+                    path.display()
+                ))
+            },
+            | Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Index {
+    /// Layout version of the store, see `INDEX_VERSION`.
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    /// Algorithm of all hashes in the index.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// Compression of all stored content.
+    #[serde(default)]
+    pub compression: Compression,
+    pub latest: Option<String>,
+    /// The checkpoint that was checked out or created last. `head` refers to it
+    /// and falls back to `latest` if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+    pub entries: BTreeMap<String, IndexEntry>,
+    pub files: BTreeMap<String, IndexFile>,
+    /// Only the hashes of the files are recorded, their content is not copied
+    /// into the store. Changes can be detected but not reverted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub manifest_only: bool,
+}
+
+impl Index {
+    /// Fails if the store only records hashes, since `action` needs the
+    /// stored content.
+    pub fn require_content(&self, action: &str) -> Result<()> {
+        if self.manifest_only {
+            return Err(anyhow::anyhow!(
+                "the store only records the hashes of the files, {} needs their content; `qop status` lists the \
+                 changed files",
+                action
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self {
+            version: INDEX_VERSION,
+            hash: HashAlgorithm::default(),
+            // new stores are compressed, indexes without the field were written
+            // before compression existed and deserialize to `none`
+            compression: Compression::Zstd,
+            latest: None,
+            head: None,
+            entries: BTreeMap::new(),
+            files: BTreeMap::new(),
+            manifest_only: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct IndexEntry {
+    pub instant: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Paths of the checkpoint and their stored content.
+    #[serde(default)]
+    pub files: BTreeMap<String, IndexFile>,
+}
+
+/// A file of a checkpoint. Its content is stored once under its hash, no
+/// matter how many paths and checkpoints share it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexFile {
+    pub hash: String,
+    /// Unix permission bits, not recorded for symlinks and on other platforms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// The file is a symlink, its stored content is the link target.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub symlink: bool,
+
+    /// Size and modification time of the file when it was hashed. `diff` takes
+    /// a file that still has them as unchanged without hashing it again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<FileStamp>,
+}
+
+/// Metadata of a file in the working copy that changes whenever its content
+/// is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub mtime: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct LogEntry {
+    pub name: String,
+    pub instant: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub files: usize,
+    pub latest: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hash::hash_symlink,
+        testing::{
+            in_dir,
+            TempDir,
+        },
+    };
+
+    #[test]
+    fn parallel_checkpoints_match_a_sequential_one() {
+        let dir = TempDir::new();
+        dir.write(".qopfile", "ignore = [\"./.qop\", \"./.qop-sequential\", \"*.skip\"]\n");
+        for n in 0..400 {
+            dir.write(&format!("dir{}/file{}.txt", n % 7, n), format!("content {}\n", n % 50));
+        }
+        dir.write("ignored.skip", "x");
+        let (parallel, sequential) = in_dir(dir.path(), || {
+            let parallel = QopDir::new(dir.path().join(".qop"));
+            checkpoint(&parallel, None, None, None, None, true, &()).unwrap();
+            let sequential = QopDir::new(dir.path().join(".qop-sequential"));
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+            pool.install(|| checkpoint(&sequential, None, None, None, None, true, &())).unwrap();
+            (read_index(&parallel).unwrap(), read_index(&sequential).unwrap())
+        });
+
+        assert_eq!(parallel.files.len(), 400);
+        assert!(!parallel.files.contains_key("./ignored.skip"));
+        for (path, file) in &parallel.files {
+            assert_eq!(
+                file.hash,
+                hash_file(parallel.hash, dir.path().join(path)).unwrap(),
+                "{}",
+                path
+            );
+        }
+        let hashes = |index: &Index| index.files.iter().map(|(k, v)| (k.clone(), v.hash.clone())).collect::<Vec<_>>();
+        assert_eq!(hashes(&parallel), hashes(&sequential));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_round_trip_through_checkpoint_and_restore() {
+        let dir = TempDir::new();
+        dir.write("target.txt", "content\n");
+        dir.write("real/inner.txt", "inner\n");
+        let link = |target: &str, path: &str| std::os::unix::fs::symlink(target, dir.path().join(path)).unwrap();
+        link("target.txt", "file-link");
+        link("real", "dir-link");
+        // a link to its own directory must not be followed
+        link(".", "real/loop");
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        let index = in_dir(dir.path(), || {
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            for path in ["file-link", "dir-link", "real/loop"] {
+                std::fs::remove_file(dir.path().join(path)).unwrap();
+            }
+            restore(&qop_dir, "latest", false, true).unwrap();
+            read_index(&qop_dir).unwrap()
+        });
+
+        let mut keys = index.files.keys().map(|x| x.as_str()).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, [
+            "./dir-link",
+            "./file-link",
+            "./real/inner.txt",
+            "./real/loop",
+            "./target.txt"
+        ]);
+        for (path, target) in [("file-link", "target.txt"), ("dir-link", "real"), ("real/loop", ".")] {
+            let key = format!("./{}", path);
+            assert!(index.files[&key].symlink, "{}", path);
+            assert_eq!(index.files[&key].hash, hash_symlink(index.hash, target));
+            assert_eq!(std::fs::read_link(dir.path().join(path)).unwrap(), Path::new(target));
+        }
+        assert_eq!(dir.read("file-link"), b"content\n");
+    }
+
+    #[test]
+    fn qop_dirs_inside_the_working_copy_are_not_indexed() {
+        for store in [".qop", "nested/backup"] {
+            let dir = TempDir::new();
+            dir.write("file.txt", "content\n");
+            dir.write("nested/file.txt", "nested\n");
+            let mut keys = in_dir(dir.path(), || {
+                // no ignore rule for the store, the walk has to skip it on its
+                // own, also while `init` builds the fresh store next to it
+                let qop_dir = QopDir::new(dir.path().join(store));
+                checkpoint(&qop_dir, None, Some(Index::default()), None, None, true, &()).unwrap();
+                checkpoint(&qop_dir, None, Some(Index::default()), None, None, true, &()).unwrap();
+                // named, the first one may have been taken in the same
+                // millisecond
+                checkpoint(&qop_dir, Some("second".to_owned()), None, None, None, true, &()).unwrap();
+                let index = read_index(&qop_dir).unwrap();
+                assert_eq!(index.entries.len(), 2);
+                for entry in index.entries.values() {
+                    let mut keys = entry.files.keys().collect::<Vec<_>>();
+                    keys.sort();
+                    assert_eq!(keys, ["./file.txt", "./nested/file.txt"]);
+                }
+                index.files.into_keys().collect::<Vec<_>>()
+            });
+            keys.sort();
+            assert_eq!(keys, ["./file.txt", "./nested/file.txt"], "{}", store);
+        }
+    }
+
+    #[test]
+    fn indexes_are_written_sorted_and_stable() {
+        let paths = ["b.txt", "a/z.txt", "a/b.txt", "c/d/e.txt", "a.txt"];
+        let index = |paths: &[&str]| {
+            let dir = TempDir::new();
+            for path in paths {
+                dir.write(path, path.as_bytes());
+            }
+            let mut index = in_dir(dir.path(), || {
+                let qop_dir = QopDir::new(dir.path().join(".qop"));
+                checkpoint(&qop_dir, Some("first".to_owned()), None, None, None, true, &()).unwrap();
+                read_index(&qop_dir).unwrap()
+            });
+            // only the order is compared, not when the files were written
+            for entry in index.entries.values_mut() {
+                entry.instant = DateTime::UNIX_EPOCH;
+            }
+            for file in index.files.values_mut().chain(index.entries.values_mut().flat_map(|x| x.files.values_mut())) {
+                file.stamp = None;
+            }
+            toml::to_string(&index).unwrap()
+        };
+
+        let first = index(&paths);
+        let reversed = paths.iter().rev().copied().collect::<Vec<_>>();
+        assert_eq!(first, index(&reversed));
+        let keys = first
+            .lines()
+            .filter_map(|x| x.strip_prefix("[files.\""))
+            .map(|x| x.split('"').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["./a.txt", "./a/b.txt", "./a/z.txt", "./b.txt", "./c/d/e.txt"]);
+    }
+
+    #[test]
+    fn compressed_stores_round_trip() {
+        let dir = TempDir::new();
+        let content = "a line that repeats\n".repeat(1000);
+        dir.write("file.txt", &content);
+        dir.write("empty.txt", "");
+        dir.write("binary.bin", b"\x00\xff\x00\xfe");
+        in_dir(dir.path(), || {
+            let qop_dir = QopDir::new(dir.path().join(".qop"));
+            let fresh = Index {
+                compression: Compression::Zstd,
+                ..Default::default()
+            };
+            checkpoint(&qop_dir, None, Some(fresh), None, None, true, &()).unwrap();
+            let index = read_index(&qop_dir).unwrap();
+            assert_eq!(index.compression, Compression::Zstd);
+            let stored = std::fs::read(qop_dir.blob(&index.files["./file.txt"].hash)).unwrap();
+            assert!(stored.starts_with(b"\x28\xb5\x2f\xfd"));
+            assert!(stored.len() < content.len() / 10);
+
+            dir.write("file.txt", "changed\n");
+            std::fs::remove_file(dir.path().join("empty.txt")).unwrap();
+            dir.write("binary.bin", b"\x01");
+            restore(&qop_dir, "latest", false, true).unwrap();
+        });
+        assert_eq!(dir.read("file.txt"), content.as_bytes());
+        assert_eq!(dir.read("empty.txt"), b"");
+        assert_eq!(dir.read("binary.bin"), b"\x00\xff\x00\xfe");
+    }
+
+    #[test]
+    fn normalizes_index_keys_written_on_windows() {
+        let dir = TempDir::new();
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        std::fs::create_dir_all(qop_dir.path()).unwrap();
+        let file = IndexFile {
+            hash: "hash".to_owned(),
+            mode: None,
+            symlink: false,
+            stamp: None,
+        };
+        let mut index = Index::default();
+        index.files.insert(".\\sub\\a.txt".to_owned(), file.clone());
+        index.entries.insert("first".to_owned(), IndexEntry {
+            instant: DateTime::UNIX_EPOCH,
+            message: None,
+            author: None,
+            files: [(".\\sub\\a.txt".to_owned(), file)].into(),
+        });
+        write_index(&qop_dir, &index).unwrap();
+
+        let index = read_index(&qop_dir).unwrap();
+        assert_eq!(index.files.keys().collect::<Vec<_>>(), ["./sub/a.txt"]);
+        assert_eq!(index.entries["first"].files.keys().collect::<Vec<_>>(), ["./sub/a.txt"]);
+    }
+
+    #[test]
+    fn failed_fresh_checkpoints_keep_the_old_store() {
+        let dir = TempDir::new();
+        dir.write("a.txt", "a\n");
+        dir.write("b.txt", "b\n");
+        in_dir(dir.path(), || {
+            let qop_dir = QopDir::new(dir.path().join(".qop"));
+            checkpoint(&qop_dir, Some("first".to_owned()), None, None, None, true, &()).unwrap();
+            let old = std::fs::read(qop_dir.index()).unwrap();
+            dir.write("c.txt", "c\n");
+            let intact = || {
+                assert_eq!(std::fs::read(qop_dir.index()).unwrap(), old);
+                let index = read_index(&qop_dir).unwrap();
+                for (path, file) in &index.files {
+                    let content = read_stored(&qop_dir, index.compression, &file.hash).unwrap();
+                    assert_eq!(content, dir.read(path), "{}", path);
+                }
+                assert!(!qop_dir.previous_blobs().exists());
+            };
+
+            // the content can not be copied into the staging store
+            std::fs::write(qop_dir.staging(), "").unwrap();
+            assert!(checkpoint(&qop_dir, None, Some(Index::default()), None, None, true, &()).is_err());
+            intact();
+            std::fs::remove_file(qop_dir.staging()).unwrap();
+
+            // the new index can not be written after the content was swapped,
+            // the temporary file of `write_atomic` is in the way
+            let index_name = qop_dir.index().file_name().unwrap().to_string_lossy().to_string();
+            let tmp = qop_dir.index().with_file_name(format!(".{}.qop-tmp-{}", index_name, std::process::id()));
+            std::fs::create_dir(&tmp).unwrap();
+            assert!(checkpoint(&qop_dir, None, Some(Index::default()), None, None, true, &()).is_err());
+            intact();
+            std::fs::remove_dir(&tmp).unwrap();
+
+            checkpoint(&qop_dir, None, Some(Index::default()), None, None, true, &()).unwrap();
+            let index = read_index(&qop_dir).unwrap();
+            assert!(index.files.contains_key("./c.txt"));
+            assert_eq!(index.entries.len(), 1);
+        });
+    }
+
+    #[test]
+    fn status_compares_against_the_checked_out_checkpoint() {
+        let dir = TempDir::new();
+        dir.write("file.txt", "old\n");
+        in_dir(dir.path(), || {
+            let qop_dir = QopDir::new(dir.path().join(".qop"));
+            checkpoint(&qop_dir, Some("old".to_owned()), None, None, None, true, &()).unwrap();
+            dir.write("file.txt", "new\n");
+            dir.write("added.txt", "added\n");
+            checkpoint(&qop_dir, Some("new".to_owned()), None, None, None, true, &()).unwrap();
+            assert!(status(&qop_dir).unwrap().is_empty());
+
+            checkout(&qop_dir, "old", false).unwrap();
+            assert!(status(&qop_dir).unwrap().is_empty());
+            dir.write("file.txt", "changed\n");
+            assert_eq!(status(&qop_dir).unwrap().modified, ["./file.txt"]);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_and_diff_agree_on_permission_changes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let script = dir.write("a.sh", "echo\n");
+        dir.write("b.txt", "b\n");
+        in_dir(dir.path(), || {
+            let qop_dir = QopDir::new(dir.path().join(".qop"));
+            checkpoint(&qop_dir, None, None, None, None, true, &()).unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+            // a later modification time alone is neither modified nor part of the diff
+            let day = std::time::UNIX_EPOCH + std::time::Duration::from_secs(86_400);
+            std::fs::File::open(dir.path().join("b.txt")).unwrap().set_modified(day).unwrap();
+
+            assert_eq!(status(&qop_dir).unwrap().modified, ["./a.sh"]);
+            let patch = crate::diff::compute_diff(&qop_dir, "latest", &crate::testing::diff_options()).unwrap().patch;
+            assert_eq!(patch.files.keys().collect::<Vec<_>>(), ["./a.sh"]);
+        });
+    }
+}
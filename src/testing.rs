@@ -0,0 +1,139 @@
+//! Helpers shared by the tests of the modules.
+
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
+use crate::{
+    apply::ApplyOptions,
+    diff::{
+        diff_file,
+        DiffAlgorithm,
+        DiffOptions,
+        Whitespace,
+    },
+    hash::{
+        hash,
+        HashAlgorithm,
+    },
+    patch::{
+        LineEndings,
+        Patch,
+        PATCH_VERSION,
+    },
+    walk::PathFilter,
+};
+
+/// A directory below the temporary directory of the system, removed again
+/// when it is dropped.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "qop-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.canonicalize().unwrap())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes a file below the directory, missing parents are created.
+    pub(crate) fn write(&self, path: &str, content: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    pub(crate) fn read(&self, path: &str) -> Vec<u8> {
+        std::fs::read(self.0.join(path)).unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `f` in `dir`. The working copy of the store functions is the current
+/// directory, which the tests share, so only one of them runs at a time.
+pub(crate) fn in_dir<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+    static CURRENT_DIR: Mutex<()> = Mutex::new(());
+    let _guard = CURRENT_DIR.lock().unwrap_or_else(|x| x.into_inner());
+    let previous = std::env::current_dir().unwrap();
+    std::env::set_current_dir(dir).unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    std::env::set_current_dir(previous).unwrap();
+    result.unwrap_or_else(|x| std::panic::resume_unwind(x))
+}
+
+/// The options of `qop diff` without any flags.
+pub(crate) fn diff_options() -> DiffOptions {
+    DiffOptions {
+        reverse: false,
+        line_endings: LineEndings::Preserve,
+        context: 3,
+        paths: PathFilter::new(&[]).unwrap(),
+        index: None,
+        find_renames: None,
+        strict: true,
+        algorithm: DiffAlgorithm::Myers,
+        whitespace: Whitespace::Exact,
+        include_metadata: false,
+        fast_path: true,
+    }
+}
+
+/// The options of `qop apply` without any flags, applying below `root`.
+pub(crate) fn apply_options(root: &Path) -> ApplyOptions {
+    ApplyOptions {
+        root: root.to_owned(),
+        line_endings: LineEndings::Preserve,
+        fuzz: 0,
+        force: false,
+        merge: false,
+        backup: false,
+        reject: false,
+    }
+}
+
+/// A patch that changes the file at `path` from `pre` to `post`.
+pub(crate) fn file_patch(path: &str, pre: &[u8], post: &[u8]) -> Patch {
+    let algorithm = HashAlgorithm::default();
+    let patch_file = diff_file(
+        pre,
+        hash(algorithm, pre),
+        post,
+        hash(algorithm, post),
+        diff_options().text(),
+    )
+    .unwrap();
+    Patch {
+        version: PATCH_VERSION,
+        hash: algorithm,
+        files: [(path.to_owned(), patch_file)].into(),
+        deleted: Vec::new(),
+        deleted_hashes: Default::default(),
+        added: Vec::new(),
+        renamed: Default::default(),
+    }
+}
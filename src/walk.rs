@@ -0,0 +1,551 @@
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+
+use anyhow::{
+    Context,
+    Result,
+};
+
+use crate::store::QopDir;
+
+/// Files found by `walk_files`.
+#[derive(Debug, Clone, Default)]
+pub struct Collected {
+    pub files: Vec<PathBuf>,
+    /// Files that are not ignored but skipped since they exceed the size limit.
+    pub too_large: Vec<PathBuf>,
+}
+
+/// Recursively collects all files below `path` that are not excluded by a
+/// `.qopfile`, or the file with the name configured for the qop directory, or
+/// a `.gitignore` on the way down. The qop directory itself and the
+/// `.qopfile`s are always excluded. Hidden files and directories, whose name
+/// starts with `.`, are skipped if the qop directory says so or a `.qopfile` on
+/// the way down sets `hidden = false`; the rules of a skipped `.qopfile` still
+/// apply.
+pub fn collect_files(qop_dir: &QopDir, path: &Path, ignore_stack: &mut Vec<IgnoreRules>) -> Result<Vec<PathBuf>> {
+    Ok(walk_files(qop_dir, path, ignore_stack)?.files)
+}
+
+/// Like `collect_files`, but also returns the files that were skipped since
+/// they are larger than the size limit of the qop directory or of a
+/// `.qopfile` on the way down, whichever is lower.
+pub fn walk_files(qop_dir: &QopDir, path: &Path, ignore_stack: &mut Vec<IgnoreRules>) -> Result<Collected> {
+    let mut collected = Collected::default();
+    let exclude = qop_dir.path().canonicalize().ok();
+    let settings = DirSettings {
+        qopfile_name: qop_dir.qopfile_name(),
+        skip_hidden: !qop_dir.hidden(),
+        max_file_size: qop_dir.max_file_size(),
+    };
+    collect_files_rec(path, exclude.as_deref(), ignore_stack, false, settings, &mut collected)?;
+    Ok(collected)
+}
+
+/// Settings that a directory passes on to the ones below it.
+#[derive(Debug, Clone, Copy)]
+struct DirSettings<'a> {
+    /// Name of the files with the ignore rules, `.qopfile` unless configured.
+    qopfile_name: &'a str,
+    skip_hidden: bool,
+    max_file_size: Option<u64>,
+}
+
+/// `ignored` tells whether `path` itself is ignored. Ignored directories are
+/// only visited if a negation pattern could re-include something below them.
+fn collect_files_rec(
+    path: &Path,
+    exclude: Option<&Path>,
+    ignore_stack: &mut Vec<IgnoreRules>,
+    ignored: bool,
+    settings: DirSettings<'_>,
+    collected: &mut Collected,
+) -> Result<()> {
+    let dir = std::fs::read_dir(path)?.filter_map(|entry| entry.ok()).collect::<Vec<_>>();
+
+    let qop_file_path = Path::join(path, settings.qopfile_name);
+    let qop_file = match std::fs::read_to_string(&qop_file_path) {
+        | Ok(s) => toml::from_str::<QopFile>(&s)?,
+        | Err(_) => QopFile::default(),
+    };
+    let gitignore_path = Path::join(path, ".gitignore");
+    let gitignore = if qop_file.respect_gitignore && gitignore_path.is_file() {
+        let mut builder = ignore::gitignore::GitignoreBuilder::new(path);
+        if let Some(e) = builder.add(&gitignore_path) {
+            return Err(e.into());
+        }
+        Some(builder.build()?)
+    } else {
+        None
+    };
+    let patterns = qop_file
+        .ignore
+        .iter()
+        .map(|x| expand_pattern(path, x))
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("invalid ignore entry in {}", qop_file_path.display()))?;
+    ignore_stack.push(IgnoreRules::new(path, &patterns, gitignore)?);
+    let own_max_file_size = qop_file.max_file_size.as_deref().map(parse_size).transpose()?;
+    let settings = DirSettings {
+        qopfile_name: settings.qopfile_name,
+        skip_hidden: settings.skip_hidden || !qop_file.hidden,
+        max_file_size: settings.max_file_size.into_iter().chain(own_max_file_size).min(),
+    };
+
+    for d in dir {
+        if settings.skip_hidden && d.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let is_dir = d.file_type()?.is_dir();
+        // the rules are configuration of the working copy, not part of it
+        if !is_dir && d.file_name() == settings.qopfile_name {
+            continue;
+        }
+        let d_ignored = ignore_stack.iter().rev().find_map(|x| x.matched(&d.path(), is_dir)).unwrap_or(ignored);
+
+        if is_dir {
+            if exclude.is_some() && d.path().canonicalize().ok().as_deref() == exclude {
+                continue;
+            }
+            if !d_ignored || ignore_stack.iter().any(|x| x.may_reinclude(&d.path())) {
+                collect_files_rec(&d.path(), exclude, ignore_stack, d_ignored, settings, collected)?;
+            }
+        } else if !d_ignored {
+            // a symlink is as large as the path it points to
+            if settings.max_file_size.is_some_and(|max| d.metadata().is_ok_and(|x| x.len() > max)) {
+                collected.too_large.push(d.path());
+            } else {
+                collected.files.push(d.path());
+            }
+        }
+    }
+    ignore_stack.pop();
+
+    Ok(())
+}
+
+/// Expands the environment variables of an ignore pattern of the `.qopfile` in
+/// `base`. A pattern that becomes an absolute path below `base` is made
+/// relative to it, so that paths like `$HOME/.cache` work on every machine.
+fn expand_pattern(base: &Path, pattern: &str) -> Result<String> {
+    let (negation, pattern) = match pattern.strip_prefix('!') {
+        | Some(v) => ("!", v),
+        | None => ("", pattern),
+    };
+    let expanded = expand_vars(pattern)?;
+    let expanded_path = Path::new(&expanded);
+    if expanded_path.is_absolute() {
+        for base in [std::path::absolute(base).ok(), base.canonicalize().ok()].into_iter().flatten() {
+            if let Ok(relative) = expanded_path.strip_prefix(&base) {
+                return Ok(format!("{}{}", negation, path_key(relative)?));
+            }
+        }
+    }
+    Ok(format!("{}{}", negation, expanded))
+}
+
+/// Replaces `$VAR` and `${VAR}` with the value of the environment variable and
+/// `$$` with `$`. Variables that are not set are an error. A `$` that is
+/// followed by nothing of this is kept.
+fn expand_vars(s: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, len) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced.find('}').ok_or_else(|| anyhow::anyhow!("unclosed variable in {}", s))?;
+            (&braced[..end], end + 2)
+        } else if rest.starts_with('$') {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        } else {
+            let end = rest.find(|x: char| !(x.is_ascii_alphanumeric() || x == '_')).unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+        if name.is_empty() || name.starts_with(|x: char| x.is_ascii_digit()) {
+            expanded.push('$');
+            continue;
+        }
+        let value =
+            std::env::var(name).map_err(|_| anyhow::anyhow!("environment variable {} in {} is not set", name, s))?;
+        expanded.push_str(&value);
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Parses a size in bytes with an optional binary suffix, like `512`, `100K`,
+/// `10M` or `1G`.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().find(|x| !x.1.is_ascii_digit()) {
+        | Some((i, _)) => s.split_at(i),
+        | None => (s, ""),
+    };
+    let factor: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        | "" | "B" => 1,
+        | "K" | "KB" | "KIB" => 1 << 10,
+        | "M" | "MB" | "MIB" => 1 << 20,
+        | "G" | "GB" | "GIB" => 1 << 30,
+        | _ => {
+            return Err(anyhow::anyhow!(
+                "invalid size, expected e.g. 512, 100K, 10M or 1G: {}",
+                s
+            ))
+        },
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(factor))
+        .ok_or_else(|| anyhow::anyhow!("invalid size, expected e.g. 512, 100K, 10M or 1G: {}", s))
+}
+
+struct IgnorePattern {
+    /// The pattern relative to the directory of its `.qopfile`.
+    glob: String,
+    matcher: globset::GlobMatcher,
+    /// Set for patterns prefixed with `!` that re-include matched paths.
+    negated: bool,
+}
+
+/// Ignore rules of a single directory, taken from its `.qopfile` and
+/// `.gitignore`. Rules of deeper directories take precedence over the ones of
+/// their parents.
+pub struct IgnoreRules {
+    base: PathBuf,
+    patterns: Vec<IgnorePattern>,
+    gitignore: Option<ignore::gitignore::Gitignore>,
+}
+
+impl IgnoreRules {
+    /// Compiles the glob patterns. Patterns without a `/` match at any depth
+    /// below `base`, all others are anchored at `base`.
+    pub fn new(base: &Path, patterns: &[String], gitignore: Option<ignore::gitignore::Gitignore>) -> Result<Self> {
+        let mut compiled = Vec::new();
+        for pattern in patterns {
+            let (pattern, negated) = match pattern.strip_prefix('!') {
+                | Some(v) => (v, true),
+                | None => (pattern.as_str(), false),
+            };
+            let pattern = pattern.strip_prefix("./").unwrap_or(pattern).trim_end_matches('/');
+            let pattern = if pattern.contains('/') {
+                pattern.to_owned()
+            } else {
+                format!("**/{}", pattern)
+            };
+            let glob = globset::GlobBuilder::new(&pattern).literal_separator(true).build()?;
+            compiled.push(IgnorePattern {
+                matcher: glob.compile_matcher(),
+                glob: pattern,
+                negated,
+            });
+        }
+        Ok(Self {
+            base: base.to_owned(),
+            patterns: compiled,
+            gitignore,
+        })
+    }
+
+    /// Returns `Some(true)` if the path is ignored, `Some(false)` if it is
+    /// explicitly re-included and `None` if no rule matches. `.qopfile`
+    /// patterns are evaluated in order with the last match winning and take
+    /// precedence over `.gitignore` rules.
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let rel = path.strip_prefix(&self.base).ok()?;
+        let qop_match = self.patterns.iter().rev().find(|x| x.matcher.is_match(rel)).map(|x| !x.negated);
+        if qop_match.is_some() {
+            return qop_match;
+        }
+        match self.gitignore.as_ref()?.matched(rel, is_dir) {
+            | ignore::Match::Ignore(_) => Some(true),
+            | ignore::Match::Whitelist(_) => Some(false),
+            | ignore::Match::None => None,
+        }
+    }
+
+    /// Tells whether a negation pattern could match a path below `dir`.
+    pub fn may_reinclude(&self, dir: &Path) -> bool {
+        let rel = match dir.strip_prefix(&self.base) {
+            | Ok(v) => v.to_string_lossy().replace('\\', "/"),
+            | Err(_) => return false,
+        };
+        self.patterns.iter().filter(|x| x.negated).any(|x| {
+            let literal = x.glob.split(['*', '?', '[', '{']).next().unwrap_or("");
+            literal.is_empty() || literal.starts_with(&format!("{}/", rel)) || rel.starts_with(literal)
+        })
+    }
+}
+
+/// Restricts a command to files, directories and glob patterns relative to the
+/// working directory. A filter without paths matches every path.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    prefixes: Vec<String>,
+    globs: Vec<globset::GlobMatcher>,
+}
+
+impl PathFilter {
+    pub fn new(paths: &[String]) -> Result<Self> {
+        let mut filter = Self::default();
+        for path in paths {
+            let path = normalize(path);
+            if path.contains(['*', '?', '[', '{']) {
+                let glob = globset::GlobBuilder::new(path).literal_separator(true).build()?;
+                filter.globs.push(glob.compile_matcher());
+            } else {
+                filter.prefixes.push(path.to_owned());
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Tells whether a path of the index or a patch, like `./sub/a.txt`, is
+    /// selected by the filter. Directories select everything below them.
+    pub fn matches(&self, path: &str) -> bool {
+        if self.prefixes.is_empty() && self.globs.is_empty() {
+            return true;
+        }
+        let path = normalize(path);
+        self.prefixes.iter().any(|x| x.is_empty() || path == x || path.starts_with(&format!("{}/", x)))
+            || self.globs.iter().any(|x| x.is_match(path))
+    }
+}
+
+/// Returns the key of a path relative to the working directory as used by the
+/// index and patches: `./` followed by the components separated by `/` on
+/// every platform. Absolute paths and paths that leave the directory have no
+/// key.
+pub fn path_key(path: &Path) -> Result<String> {
+    let mut key = String::from(".");
+    for component in path.components() {
+        match component {
+            | Component::CurDir => (),
+            | Component::Normal(v) => {
+                key.push('/');
+                key.push_str(&v.to_string_lossy());
+            },
+            | _ => {
+                return Err(anyhow::anyhow!(
+                    "path is absolute or leaves the directory: {}",
+                    path.display()
+                ));
+            },
+        }
+    }
+    Ok(key)
+}
+
+/// Turns a key of the index or a patch back into a path with the separators of
+/// the platform.
+pub fn key_path(key: &str) -> PathBuf {
+    key.split('/').collect()
+}
+
+/// Rewrites a key written by an older version on Windows, like `.\\sub\\a.txt`,
+/// to the separators used on every platform. Other keys are left as they are,
+/// on Unix a backslash may be part of a file name.
+pub fn normalize_key(key: String) -> String {
+    if key.starts_with(".\\") {
+        key.replace('\\', "/")
+    } else {
+        key
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct QopFile {
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Whether the `.gitignore` in the same directory is honored.
+    #[serde(default = "default_true")]
+    pub respect_gitignore: bool,
+    /// Whether hidden files and directories in the same directory and below
+    /// are collected.
+    #[serde(default = "default_true")]
+    pub hidden: bool,
+    /// Files in the same directory and below that are larger than this size,
+    /// like `10M`, are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<String>,
+}
+
+impl Default for QopFile {
+    fn default() -> Self {
+        Self {
+            ignore: Vec::new(),
+            respect_gitignore: true,
+            hidden: true,
+            max_file_size: None,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Tells whether the rules of `patterns` in `/base` ignore the file at
+    /// `path` below it.
+    fn ignores(patterns: &[&str], path: &str) -> bool {
+        let base = Path::new("/base");
+        let patterns = patterns.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let rules = IgnoreRules::new(base, &patterns, None).unwrap();
+        rules.matched(&base.join(path), false).unwrap_or(false)
+    }
+
+    /// Returns the sorted keys of the files collected below `dir`.
+    fn collect(qop_dir: &QopDir, dir: &TempDir) -> Vec<String> {
+        let mut keys = collect_files(qop_dir, dir.path(), &mut Vec::new())
+            .unwrap()
+            .iter()
+            .map(|x| path_key(x.strip_prefix(dir.path()).unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn matches_extension_globs_at_any_depth() {
+        assert!(ignores(&["*.tmp"], "a.tmp"));
+        assert!(ignores(&["*.tmp"], "sub/deep/b.tmp"));
+        assert!(!ignores(&["*.tmp"], "a.tmp.txt"));
+        assert!(!ignores(&["*.tmp"], "a.txt"));
+    }
+
+    #[test]
+    fn matches_nested_double_star_globs() {
+        assert!(ignores(&["**/target"], "target"));
+        assert!(ignores(&["**/target"], "crates/core/target"));
+        assert!(ignores(&["src/**/*.rs"], "src/a/b/main.rs"));
+        assert!(!ignores(&["src/**/*.rs"], "tests/main.rs"));
+    }
+
+    #[test]
+    fn matches_exact_paths_only_at_the_base() {
+        assert!(ignores(&["./docs/readme.md"], "docs/readme.md"));
+        assert!(ignores(&["docs/readme.md"], "docs/readme.md"));
+        assert!(!ignores(&["./docs/readme.md"], "other/docs/readme.md"));
+        assert!(!ignores(&["./docs/readme.md"], "docs/readme.md.bak"));
+    }
+
+    #[test]
+    fn applies_the_globs_of_the_qopfile() {
+        let dir = TempDir::new();
+        dir.write(".qopfile", "ignore = [\"*.tmp\", \"**/target\", \"./docs/skip.md\"]\n");
+        dir.write("a.txt", "a");
+        dir.write("a.tmp", "a");
+        dir.write("crates/core/target/out.bin", "x");
+        dir.write("crates/core/lib.rs", "x");
+        dir.write("docs/skip.md", "x");
+        dir.write("docs/keep.md", "x");
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        assert_eq!(collect(&qop_dir, &dir), [
+            "./a.txt",
+            "./crates/core/lib.rs",
+            "./docs/keep.md"
+        ]);
+    }
+
+    #[test]
+    fn later_negations_re_include_paths() {
+        assert!(ignores(&["*.log"], "keep.log"));
+        assert!(!ignores(&["*.log", "!keep.log"], "keep.log"));
+        assert!(ignores(&["*.log", "!keep.log"], "other.log"));
+        // the last matching pattern wins
+        assert!(ignores(&["!keep.log", "*.log"], "keep.log"));
+    }
+
+    #[test]
+    fn re_includes_files_below_ignored_directories() {
+        let dir = TempDir::new();
+        dir.write(".qopfile", "ignore = [\"build/\", \"!build/keep.txt\"]\n");
+        dir.write("build/keep.txt", "x");
+        dir.write("build/out.o", "x");
+        dir.write("build/deep/out.o", "x");
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        assert_eq!(collect(&qop_dir, &dir), ["./build/keep.txt"]);
+    }
+
+    #[test]
+    fn rules_of_subdirectories_override_their_parents() {
+        let dir = TempDir::new();
+        dir.write(".qopfile", "ignore = [\"*.log\"]\n");
+        dir.write("sub/.qopfile", "ignore = [\"!*.log\"]\n");
+        dir.write("a.log", "x");
+        dir.write("sub/b.log", "x");
+        dir.write("sub/deep/c.log", "x");
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        assert_eq!(collect(&qop_dir, &dir), ["./sub/b.log", "./sub/deep/c.log"]);
+    }
+
+    #[test]
+    fn keys_use_forward_slashes() {
+        let path = Path::new(".").join("sub").join("dir").join("a.txt");
+        assert_eq!(path_key(&path).unwrap(), "./sub/dir/a.txt");
+        assert_eq!(key_path("./sub/dir/a.txt"), path);
+        assert!(path_key(&Path::new("sub").join("..").join("..")).is_err());
+    }
+
+    #[test]
+    fn normalizes_keys_written_on_windows() {
+        assert_eq!(normalize_key(".\\sub\\dir\\a.txt".to_owned()), "./sub/dir/a.txt");
+        assert_eq!(normalize_key("./sub/a.txt".to_owned()), "./sub/a.txt");
+        // a backslash in the name of a file on Unix
+        assert_eq!(normalize_key("./sub/a\\b.txt".to_owned()), "./sub/a\\b.txt");
+    }
+
+    #[test]
+    fn skips_hidden_files_and_directories() {
+        let dir = TempDir::new();
+        dir.write("visible.txt", "");
+        dir.write(".hidden.txt", "");
+        dir.write(".config/file.txt", "");
+        dir.write("sub/.hidden.txt", "");
+        dir.write(".qopfile", "");
+        dir.write("sub/.qopfile", "");
+        let mut qop_dir = QopDir::new(dir.path().join(".qop"));
+        assert_eq!(collect(&qop_dir, &dir), [
+            "./.config/file.txt",
+            "./.hidden.txt",
+            "./sub/.hidden.txt",
+            "./visible.txt"
+        ]);
+        qop_dir.set_hidden(false);
+        assert_eq!(collect(&qop_dir, &dir), ["./visible.txt"]);
+    }
+
+    #[test]
+    fn qopfiles_skip_hidden_files_below_them() {
+        let dir = TempDir::new();
+        dir.write(".hidden.txt", "");
+        dir.write("sub/.qopfile", "hidden = false\n");
+        dir.write("sub/visible.txt", "");
+        dir.write("sub/.hidden.txt", "");
+        dir.write("sub/.config/file.txt", "");
+        dir.write("sub/nested/.hidden.txt", "");
+        let qop_dir = QopDir::new(dir.path().join(".qop"));
+        assert_eq!(collect(&qop_dir, &dir), ["./.hidden.txt", "./sub/visible.txt"]);
+    }
+}